@@ -0,0 +1,114 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Client access control, evaluated on every query before it is handed to the router.
+
+use crate::parser::{AclAction, AclSettings};
+use droute::utils::{IpCidr, UtilsError};
+use once_cell::sync::{Lazy, OnceCell};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::net::IpAddr;
+
+static ACL: OnceCell<Acl> = OnceCell::new();
+
+static DECISIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "dcompass_acl_decisions_total",
+        "Number of queries checked against the ACL, by decision (allow, refuse, or drop)",
+        &["decision"]
+    )
+    .unwrap()
+});
+
+/// What to do with a query
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Refuse,
+    Drop,
+}
+
+impl Decision {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Refuse => "refuse",
+            Self::Drop => "drop",
+        }
+    }
+}
+
+pub struct Acl {
+    // `None` if everyone not denied is allowed
+    allow: Option<IpCidr>,
+    deny: IpCidr,
+    action: AclAction,
+}
+
+impl Acl {
+    pub fn new(settings: AclSettings) -> Result<Self, UtilsError> {
+        let cidrs = |list: Vec<String>| -> Result<IpCidr, UtilsError> {
+            let mut cidr = IpCidr::new();
+            for c in list {
+                cidr.add_cidr(&c)?;
+            }
+            Ok(cidr)
+        };
+        Ok(Self {
+            allow: if settings.allow.is_empty() {
+                None
+            } else {
+                Some(cidrs(settings.allow)?)
+            },
+            deny: cidrs(settings.deny)?,
+            action: settings.action,
+        })
+    }
+
+    /// Denied clients are matched first, then the client has to be allowed if there is any allowed one.
+    pub fn check(&self, ip: IpAddr) -> Decision {
+        // Clients on IPv4 reach sockets bound on `[::]` with IPv4-mapped addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        let allowed =
+            !self.deny.contains(ip) && self.allow.as_ref().map(|a| a.contains(ip)).unwrap_or(true);
+        match (allowed, self.action) {
+            (true, _) => Decision::Allow,
+            (false, AclAction::Refuse) => Decision::Refuse,
+            (false, AclAction::Drop) => Decision::Drop,
+        }
+    }
+}
+
+/// Set up the ACL applied to all the listeners.
+pub fn init(settings: AclSettings) -> Result<(), UtilsError> {
+    // We only set it once at start.
+    let _ = ACL.set(Acl::new(settings)?);
+    Ok(())
+}
+
+/// Check the client against the ACL. Everyone is allowed if there is no ACL configured.
+pub fn check(ip: IpAddr) -> Decision {
+    match ACL.get() {
+        Some(acl) => {
+            let decision = acl.check(ip);
+            DECISIONS.with_label_values(&[decision.as_str()]).inc();
+            decision
+        }
+        None => Decision::Allow,
+    }
+}
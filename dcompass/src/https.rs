@@ -0,0 +1,253 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{ratelimit::QueryLimiter, tls::load_server_config, worker, SharedRouter};
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use domain::base::Message;
+use droute::{builders::RuneScript, dnstap::SocketProtocol, QueryContext, Router};
+use hyper::{
+    body::HttpBody,
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    server::conn::Http,
+    service::service_fn,
+    Body, HeaderMap, Method, Request, Response, StatusCode,
+};
+use log::*;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::Sender,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+// Same as the one used for DNS over TLS.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// DNS messages cannot be longer than this as per RFC 8484.
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// Settings that apply to every request on a DNS over HTTPS listener.
+pub struct DohSettings {
+    /// The path the endpoint is served on. e.g. `/dns-query`
+    pub path: String,
+    /// Proxies from which we trust the `X-Forwarded-For` header
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Per-client query rate limiter
+    pub limiter: QueryLimiter,
+}
+
+/// Create a TLS acceptor for DNS over HTTPS, which negotiates both HTTP/2 and HTTP/1.1.
+pub fn create_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let mut config = load_server_config(cert, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accept incoming TCP connections, and serve DNS over HTTPS (RFC 8484) on each of them after the TLS handshake completes.
+pub async fn serve_https(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    settings: Arc<DohSettings>,
+    router: SharedRouter,
+    tx: &Sender<()>,
+) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("failed to accept HTTPS connection: {}", e);
+                continue;
+            }
+        };
+
+        let router = router.clone();
+        let acceptor = acceptor.clone();
+        let settings = settings.clone();
+        let mut shutdown = tx.subscribe();
+        #[rustfmt::skip]
+        tokio::spawn(async move {
+            tokio::select! {
+                biased; res = handle_https(router, acceptor, settings, stream, src) => {
+                    match res {
+                        Ok(_) => (),
+                        Err(e) => warn!("handling HTTPS connection from {} failed: {}", src, e),
+                    }
+                }
+                _ = shutdown.recv() => {
+                    log::warn!("HTTPS connection handler shut down");
+                }
+            }
+        });
+    }
+}
+
+async fn handle_https(
+    router: SharedRouter,
+    acceptor: TlsAcceptor,
+    settings: Arc<DohSettings>,
+    stream: TcpStream,
+    src: SocketAddr,
+) -> Result<()> {
+    let stream = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("TLS handshake timed out")??;
+    let sni = stream.get_ref().1.sni_hostname().map(|s| s.to_string());
+
+    Http::new()
+        .serve_connection(
+            stream,
+            service_fn(move |req| {
+                let router = router.load_full();
+                let settings = settings.clone();
+                let sni = sni.clone();
+                async move {
+                    Ok::<_, Infallible>(match respond(&router, &settings, src, sni, req).await {
+                        Ok(resp) => resp,
+                        Err(status) => Response::builder()
+                            .status(status)
+                            .body(Body::empty())
+                            .unwrap(),
+                    })
+                }
+            }),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn respond(
+    router: &Router<RuneScript>,
+    settings: &DohSettings,
+    src: SocketAddr,
+    sni: Option<String>,
+    req: Request<Body>,
+) -> std::result::Result<Response<Body>, StatusCode> {
+    if req.uri().path() != settings.path {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let ip = client_ip(src.ip(), req.headers(), &settings.trusted_proxies);
+    if !settings.limiter.check(ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let query = match *req.method() {
+        Method::GET => req
+            .uri()
+            .query()
+            .and_then(|q| q.split('&').find_map(|p| p.strip_prefix("dns=")))
+            // Padding is omitted per RFC, but we are tolerant about it.
+            .and_then(|q| {
+                base64::decode_config(q.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()
+            })
+            .map(Bytes::from)
+            .ok_or(StatusCode::BAD_REQUEST)?,
+        Method::POST => {
+            let content_type = req.headers().get(CONTENT_TYPE).map(|v| v.as_bytes());
+            if content_type != Some(DNS_MESSAGE.as_bytes()) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            read_body(req.into_body()).await?
+        }
+        _ => return Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let query = Message::from_octets(query).map_err(|_| StatusCode::BAD_REQUEST)?;
+    // The port of a client behind a trusted proxy is unknown.
+    let port = if ip == src.ip() {
+        Some(src.port())
+    } else {
+        None
+    };
+    let resp = worker::resolve(
+        router,
+        query,
+        QueryContext::new(ip).with_sni(sni),
+        port,
+        SocketProtocol::Doh,
+    )
+    .await
+    .map_err(|e| {
+        warn!("handling query failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    // There is no way to drop a query silently over HTTP.
+    .ok_or(StatusCode::FORBIDDEN)?;
+
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, DNS_MESSAGE);
+    if let Some(ttl) = min_ttl(&resp) {
+        builder = builder.header(CACHE_CONTROL, format!("max-age={}", ttl));
+    }
+    info!("response completed. Sent back to {} successfully.", ip);
+    Ok(builder.body(Body::from(resp.into_octets())).unwrap())
+}
+
+// Read the whole body, but refuse to read anything larger than a DNS message can be.
+async fn read_body(mut body: Body) -> std::result::Result<Bytes, StatusCode> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buf.len() + chunk.len() > MAX_MESSAGE_LEN {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+// If the connection comes from a trusted proxy, the client is the rightmost address in `X-Forwarded-For` that is not one of our trusted proxies.
+fn client_ip(src: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&src) {
+        return src;
+    }
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !trusted_proxies.contains(ip))
+        .or_else(|| forwarded.first())
+        .copied()
+        .unwrap_or(src)
+}
+
+// Minimum TTL of the answers, or of the authority section records (e.g. SOA on negative responses) if there is no answer.
+fn min_ttl(msg: &Message<Bytes>) -> Option<u32> {
+    msg.answer()
+        .ok()
+        .and_then(|records| records.filter_map(|r| r.ok()).map(|r| r.ttl()).min())
+        .or_else(|| {
+            msg.authority()
+                .ok()
+                .and_then(|records| records.filter_map(|r| r.ok()).map(|r| r.ttl()).min())
+        })
+}
@@ -0,0 +1,123 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+use crate::{
+    https::{self, serve_https, DohSettings},
+    tls::{self, serve_tls},
+};
+use crate::{
+    parser::Listener,
+    ratelimit::QueryLimiter,
+    tcp::serve_tcp,
+    worker::{serve_udp, UdpConfig},
+    SharedRouter,
+};
+use anyhow::{Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::broadcast::Sender,
+};
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+use tokio_rustls::TlsAcceptor;
+
+/// A listener that is ready to be bound, with everything it needs (e.g. certificates) loaded.
+pub enum Server {
+    Udp(SocketAddr, Arc<UdpConfig>),
+    Tcp(SocketAddr, Arc<QueryLimiter>),
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+    Tls(SocketAddr, TlsAcceptor, Arc<QueryLimiter>),
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+    Https(SocketAddr, TlsAcceptor, Arc<DohSettings>),
+}
+
+impl Server {
+    /// Prepare the listener. Certificates are loaded here so that broken TLS settings are caught on validation as well.
+    pub fn prepare(listener: Listener) -> Result<Self> {
+        Ok(match listener {
+            Listener::Udp(l) => Self::Udp(
+                l.addr,
+                Arc::new(UdpConfig {
+                    max_payload_size: l.max_payload_size,
+                    limiter: l.ratelimit.into(),
+                    rrl: l.rrl.into(),
+                }),
+            ),
+            Listener::Tcp(l) => Self::Tcp(l.addr, Arc::new(l.ratelimit.into())),
+            #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+            Listener::Tls(l) => Self::Tls(
+                l.addr,
+                tls::create_acceptor(&l.cert, &l.key)?,
+                Arc::new(l.ratelimit.into()),
+            ),
+            #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+            Listener::Https(l) => Self::Https(
+                l.addr,
+                https::create_acceptor(&l.cert, &l.key)?,
+                Arc::new(DohSettings {
+                    path: l.path,
+                    trusted_proxies: l.trusted_proxies,
+                    limiter: l.ratelimit.into(),
+                }),
+            ),
+            #[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+            Listener::Tls(_) | Listener::Https(_) => anyhow::bail!(
+                "DNS over TLS and DNS over HTTPS listeners are not supported on this platform"
+            ),
+        })
+    }
+
+    /// Bind on the address and return the future serving on it. All the servers share the same router and shutdown channel.
+    pub async fn bind<'a>(
+        self,
+        router: SharedRouter,
+        tx: &'a Sender<()>,
+    ) -> Result<BoxFuture<'a, ()>> {
+        Ok(match self {
+            Self::Udp(addr, config) => {
+                let socket = Arc::new(
+                    UdpSocket::bind(addr)
+                        .await
+                        .with_context(|| format!("failed to bind to {}", addr))?,
+                );
+                serve_udp(socket, config, router, tx).boxed()
+            }
+            Self::Tcp(addr, limiter) => {
+                serve_tcp(bind_tcp(addr, "TCP").await?, limiter, router, tx).boxed()
+            }
+            #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+            Self::Tls(addr, acceptor, limiter) => {
+                serve_tls(bind_tcp(addr, "TLS").await?, acceptor, limiter, router, tx).boxed()
+            }
+            #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+            Self::Https(addr, acceptor, settings) => serve_https(
+                bind_tcp(addr, "HTTPS").await?,
+                acceptor,
+                settings,
+                router,
+                tx,
+            )
+            .boxed(),
+        })
+    }
+}
+
+async fn bind_tcp(addr: SocketAddr, proto: &str) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind {} listener to {}", proto, addr))
+}
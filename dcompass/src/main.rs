@@ -20,31 +20,43 @@
 // #[global_allocator]
 // static GLOBAL: Jemalloc = Jemalloc;
 
+mod acl;
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+mod https;
+mod listener;
+mod metrics;
 mod parser;
+mod querylog;
+mod ratelimit;
+mod tcp;
 #[cfg(test)]
 mod tests;
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+mod tls;
+mod truncate;
 mod worker;
 
-use self::{parser::Parsed, worker::worker};
+use self::{
+    listener::Server,
+    metrics::serve_metrics,
+    parser::{Parsed, Settings},
+};
 use anyhow::{Context, Result};
-use bytes::BytesMut;
+use arc_swap::ArcSwap;
 use droute::{
     builders::{RouterBuilder, RuneScript},
     errors::ScriptError,
     AsyncTryInto, Router,
 };
+use futures::future::{join_all, BoxFuture, FutureExt};
 use log::*;
 use simple_logger::SimpleLogger;
-use std::{net::SocketAddr, path::PathBuf, result::Result as StdResult, sync::Arc, time::Duration};
+use std::{path::PathBuf, result::Result as StdResult, sync::Arc, time::Duration};
 use structopt::StructOpt;
-use tokio::{
-    fs::File,
-    io::AsyncReadExt,
-    net::UdpSocket,
-    signal,
-    sync::broadcast::{self, Sender},
-    time::sleep,
-};
+use tokio::{fs::File, io::AsyncReadExt, net::TcpListener, signal, sync::broadcast, time::sleep};
+
+/// The router shared by all the listeners, which can be swapped on reload.
+pub type SharedRouter = Arc<ArcSwap<Router<RuneScript>>>;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -61,52 +73,64 @@ struct DcompassOpts {
     validate: bool,
 }
 
-async fn init(p: Parsed) -> StdResult<(Router<RuneScript>, SocketAddr, LevelFilter), ScriptError> {
+async fn init(p: Parsed) -> StdResult<(Router<RuneScript>, Settings), ScriptError> {
     Ok((
         RouterBuilder::new(p.script, p.upstreams)
             .async_try_into()
             .await?,
-        p.address,
-        p.verbosity,
+        Settings {
+            listeners: p.address.into_listeners(),
+            metrics: p.metrics,
+            query_log: p.query_log,
+            dnstap: p.dnstap,
+            acl: p.acl,
+            verbosity: p.verbosity,
+        },
     ))
 }
 
-async fn serve(socket: Arc<UdpSocket>, router: Arc<Router<RuneScript>>, tx: &Sender<()>) {
-    loop {
-        // Size recommended by DNS Flag Day 2020: "This is practical for the server operators that know their environment, and the defaults in the DNS software should reflect the minimum safe size which is 1232."
-        let mut buf = BytesMut::with_capacity(1024);
-        buf.resize(1024, 0);
-        // On windows, some applications may go away after they got their first response, resulting in a broken pipe, we should discard errors on receiving/sending messages.
-        let (len, src) = match socket.recv_from(&mut buf).await {
-            Ok(r) => r,
-            Err(e) => {
-                warn!("failed to receive query: {}", e);
-                continue;
-            }
-        };
-
-        buf.resize(len, 0);
-
-        let router = router.clone();
-        let socket = socket.clone();
-        let mut shutdown = tx.subscribe();
-        #[rustfmt::skip]
-        tokio::spawn(async move {
-            tokio::select! {
-                biased; res = worker(router, socket, buf.freeze(), src) => {
-                    match res {
-                        Ok(_) => (),
-                        Err(e) => warn!("handling query failed: {}", e),
-                    }
-                }
-                _ = shutdown.recv() => {
-                    // If a shutdown signal is received, return from the spawned task.
-                    // This will result in the task terminating.
-                    log::warn!("worker shut down");
-                }
+// Re-read the configuration and build a new router from it, which takes over the response cache of the running one.
+// Listeners and verbosity are not changed.
+#[cfg(unix)]
+async fn reload(path: &std::path::Path, router: &SharedRouter) -> Result<()> {
+    let config = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read from the file: {}", path.display()))?;
+    let p: Parsed = serde_yaml::from_str(&config)
+        .with_context(|| "Failed to parse the configuration file".to_string())?;
+    let current = router.load_full();
+    let new = RouterBuilder::new(p.script, p.upstreams)
+        .inherit_cache(&current)
+        .async_try_into()
+        .await?;
+    router.store(Arc::new(new));
+    Ok(())
+}
+
+// Reload the router every time SIGHUP is received. On failure, the running router stays active.
+#[cfg(unix)]
+async fn reload_on_hangup(path: Option<PathBuf>, router: SharedRouter) -> Result<()> {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        match &path {
+            Some(path) => match reload(path, &router).await {
+                Ok(_) => info!("configuration reloaded from {}", path.display()),
+                Err(e) => warn!(
+                    "failed to reload the configuration, keeping the running one: {:#}",
+                    e
+                ),
+            },
+            None => {
+                warn!("SIGHUP received, but there is nothing to reload from the built-in config")
             }
-        });
+        }
     }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_: Option<PathBuf>, _: SharedRouter) -> Result<()> {
+    futures::future::pending().await
 }
 
 #[tokio::main]
@@ -117,7 +141,8 @@ async fn main() -> Result<()> {
 
     // If the config path is manually specified with `-c` flag, we use it and any error should fail early.
     // If there is no specified config but there is `config.yaml` under the path where user is invoking `dcompass` (not the absolute path of the binary), then we shall try that config. If the file exists but we failed to read, this should fail. Otherwise, we shall use the default anyway.
-    let config = if let Some(config_path) = args.config {
+    // The path is kept for reloading, and it is `None` if the built-in config is used.
+    let (config, config_path) = if let Some(config_path) = args.config {
         let display_path = config_path.as_path().display();
         let mut file = File::open(config_path.clone())
             .await
@@ -127,7 +152,7 @@ async fn main() -> Result<()> {
             .await
             .with_context(|| format!("Failed to read from the file specified: {}", display_path))?;
         println!("Using the config file specified: {}", display_path);
-        config
+        (config, Some(config_path))
     } else {
         let mut config_path = std::env::current_dir()?;
         config_path.push("config.yaml");
@@ -140,12 +165,12 @@ async fn main() -> Result<()> {
                     format!("Failed to read from the file found: {}", display_path)
                 })?;
                 println!("Using the config under current path: {}", display_path);
-                config
+                (config, Some(config_path))
             }
             // No config found, using built-in.
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
                 println!("No config found or specified, using built-in config.");
-                include_str!("../../configs/default.json").to_owned()
+                (include_str!("../../configs/default.json").to_owned(), None)
             }
             // Found but unable to open. We shall exit as this is intended.
             Err(e) => {
@@ -157,12 +182,23 @@ async fn main() -> Result<()> {
     };
 
     // Create whatever we need for get dcompass up and running.
-    let (router, addr, verbosity) = init(
+    let (router, settings) = init(
         serde_yaml::from_str(&config)
             .with_context(|| "Failed to parse the configuration file".to_string())?,
     )
     .await?;
 
+    // Load certificates now so that broken TLS settings are caught on validation as well.
+    let prepared = settings
+        .listeners
+        .into_iter()
+        .map(Server::prepare)
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(acl) = settings.acl {
+        acl::init(acl).context("failed to set up the ACL")?;
+    }
+
     // If we are only required to validate the config, we shall be safe to exit now.
     if args.validate {
         println!("The configuration provided is valid.");
@@ -172,26 +208,51 @@ async fn main() -> Result<()> {
     // Start logging
     SimpleLogger::new()
         // These modules are quite chatty, we want to disable it.
-        .with_level(verbosity)
+        .with_level(settings.verbosity)
         .init()?;
 
+    // Query log is written regardless of the verbosity
+    if let Some(query_log) = settings.query_log {
+        querylog::init(query_log).await?;
+    }
+
+    if let Some(dnstap) = settings.dnstap {
+        droute::dnstap::init(
+            dnstap.output,
+            dnstap.identity,
+            Some(format!("dcompass {}", env!("CARGO_PKG_VERSION"))),
+        )
+        .await
+        .context("failed to set up dnstap output")?;
+    }
+
     info!("dcompass ready!");
 
-    let router = Arc::new(router);
-    // Bind an UDP socket
-    let socket = Arc::new(
-        UdpSocket::bind(addr)
-            .await
-            .with_context(|| format!("failed to bind to {}", addr))?,
-    );
+    // Workers load the router on every query, so that a reloaded router takes over new queries while in-flight ones finish on the old one.
+    let router: SharedRouter = Arc::new(ArcSwap::from_pointee(router));
 
     // Create a shutdown broadcast channel
     let (tx, _) = broadcast::channel::<()>(10);
 
+    let mut servers: Vec<BoxFuture<()>> = Vec::new();
+
+    // Every listener shares the same router and shutdown channel.
+    for server in prepared {
+        servers.push(server.bind(router.clone(), &tx).await?);
+    }
+
+    if let Some(addr) = settings.metrics {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to bind metrics listener to {}", addr))?;
+        servers.push(serve_metrics(listener, router.clone(), &tx).boxed());
+    }
+
     // We don't have to worry about incoming requests when shutting down, because when we initiate shutdown, the loop was already terminated
     #[rustfmt::skip]
     tokio::select! {
-        _ = serve(socket, router, &tx) => (),
+        _ = join_all(servers) => (),
+        res = reload_on_hangup(config_path, router.clone()) => res?,
         _ = signal::ctrl_c() => {
             log::warn!("Ctrl-C received, shutting down");
 	    sleep(Duration::from_millis(500)).await;
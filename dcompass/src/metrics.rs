@@ -0,0 +1,101 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::SharedRouter;
+use anyhow::Result;
+use hyper::{
+    header::CONTENT_TYPE, server::conn::Http, service::service_fn, Body, Method, Request, Response,
+    StatusCode,
+};
+use log::*;
+use std::convert::Infallible;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::Sender,
+};
+
+/// Accept incoming TCP connections, and serve the Prometheus metrics over plain HTTP on `/metrics`.
+pub async fn serve_metrics(listener: TcpListener, router: SharedRouter, tx: &Sender<()>) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+
+        let router = router.clone();
+        let mut shutdown = tx.subscribe();
+        #[rustfmt::skip]
+        tokio::spawn(async move {
+            tokio::select! {
+                biased; res = handle_metrics(router, stream) => {
+                    match res {
+                        Ok(_) => (),
+                        Err(e) => warn!("handling metrics connection from {} failed: {}", src, e),
+                    }
+                }
+                _ = shutdown.recv() => {
+                    log::warn!("metrics connection handler shut down");
+                }
+            }
+        });
+    }
+}
+
+async fn handle_metrics(router: SharedRouter, stream: TcpStream) -> Result<()> {
+    Http::new()
+        .serve_connection(
+            stream,
+            service_fn(move |req| {
+                let router = router.clone();
+                async move {
+                    Ok::<_, Infallible>(match respond(&router, req) {
+                        Ok(resp) => resp,
+                        Err(status) => Response::builder()
+                            .status(status)
+                            .body(Body::empty())
+                            .unwrap(),
+                    })
+                }
+            }),
+        )
+        .await?;
+    Ok(())
+}
+
+fn respond(
+    router: &SharedRouter,
+    req: Request<Body>,
+) -> std::result::Result<Response<Body>, StatusCode> {
+    if req.uri().path() != "/metrics" {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if req.method() != Method::GET {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    // Connection pools are sampled from the router currently in use.
+    let text = droute::metrics::encode(router.load().upstreams()).map_err(|e| {
+        warn!("failed to encode metrics: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, droute::metrics::content_type())
+        .body(Body::from(text))
+        .unwrap())
+}
@@ -13,10 +13,14 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use droute::builders::*;
+use droute::{builders::*, dnstap::Output};
 use log::LevelFilter;
 use serde::Deserialize;
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+};
 
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -37,7 +41,273 @@ pub struct Parsed {
     // We are not using UpstreamsBuilder because flatten ruins error location.
     #[serde(flatten)]
     pub upstreams: UpstreamsBuilder<UpstreamBuilder>,
-    pub address: SocketAddr,
+    pub address: Address,
+    #[serde(default)]
+    pub metrics: Option<SocketAddr>,
+    #[serde(default)]
+    pub query_log: Option<QueryLogSettings>,
+    #[serde(default)]
+    pub dnstap: Option<DnstapSettings>,
+    #[serde(default)]
+    pub acl: Option<AclSettings>,
     #[serde(with = "LevelFilterDef")]
     pub verbosity: LevelFilter,
 }
+
+/// Settings other than the router itself.
+pub struct Settings {
+    pub listeners: Vec<Listener>,
+    pub metrics: Option<SocketAddr>,
+    pub query_log: Option<QueryLogSettings>,
+    pub dnstap: Option<DnstapSettings>,
+    pub acl: Option<AclSettings>,
+    pub verbosity: LevelFilter,
+}
+
+/// Addresses to listen on
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Address {
+    /// Serve both UDP and TCP on a single address
+    Single(SocketAddr),
+    /// A list of listeners, each with its own protocol and address
+    Listeners(Vec<Listener>),
+}
+
+impl Address {
+    /// Flatten into a list of listeners
+    pub fn into_listeners(self) -> Vec<Listener> {
+        match self {
+            Self::Single(addr) => vec![
+                Listener::Udp(UdpListener {
+                    addr,
+                    max_payload_size: default_max_payload_size(),
+                    ratelimit: None,
+                    rrl: None,
+                }),
+                Listener::Tcp(PlainListener {
+                    addr,
+                    ratelimit: None,
+                }),
+            ],
+            Self::Listeners(v) => v,
+        }
+    }
+}
+
+/// A listener with the protocol it serves
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Listener {
+    /// DNS over UDP
+    Udp(UdpListener),
+    /// DNS over TCP
+    Tcp(PlainListener),
+    /// DNS over TLS
+    Tls(TlsListener),
+    /// DNS over HTTPS
+    Https(HttpsListener),
+}
+
+/// TCP listener
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PlainListener {
+    /// The address to bind on
+    pub addr: SocketAddr,
+    /// Per-client query rate limit
+    #[serde(default)]
+    pub ratelimit: Option<RateLimitSettings>,
+}
+
+// Size recommended by DNS Flag Day 2020: "This is practical for the server operators that know their environment, and the defaults in the DNS software should reflect the minimum safe size which is 1232."
+const fn default_max_payload_size() -> u16 {
+    1232
+}
+
+/// UDP listener
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UdpListener {
+    /// The address to bind on
+    pub addr: SocketAddr,
+    /// The largest response to send, regardless of the UDP payload size advertised by the client
+    #[serde(default = "default_max_payload_size")]
+    pub max_payload_size: u16,
+    /// Per-client query rate limit
+    #[serde(default)]
+    pub ratelimit: Option<RateLimitSettings>,
+    /// Response rate limit
+    #[serde(default)]
+    pub rrl: Option<RrlSettings>,
+}
+
+/// DNS over TLS listener
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsListener {
+    /// The address to bind on
+    pub addr: SocketAddr,
+    /// Path to the certificate chain in PEM format
+    pub cert: PathBuf,
+    /// Path to the private key in PEM format
+    pub key: PathBuf,
+    /// Per-client query rate limit
+    #[serde(default)]
+    pub ratelimit: Option<RateLimitSettings>,
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".to_string()
+}
+
+/// DNS over HTTPS listener
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HttpsListener {
+    /// The address to bind on
+    pub addr: SocketAddr,
+    /// Path to the certificate chain in PEM format
+    pub cert: PathBuf,
+    /// Path to the private key in PEM format
+    pub key: PathBuf,
+    /// The path to serve DNS over HTTPS on
+    #[serde(default = "default_doh_path")]
+    pub path: String,
+    /// Reverse proxies whose `X-Forwarded-For` header is trusted to carry the real client address
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Per-client query rate limit
+    #[serde(default)]
+    pub ratelimit: Option<RateLimitSettings>,
+}
+
+const fn default_ipv4_prefix() -> u8 {
+    32
+}
+
+const fn default_ipv6_prefix() -> u8 {
+    128
+}
+
+fn default_max_clients() -> NonZeroUsize {
+    NonZeroUsize::new(65536).unwrap()
+}
+
+/// Per-client query rate limit of a listener
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitSettings {
+    /// Queries allowed per second from each client
+    pub qps: NonZeroU32,
+    /// Queries allowed in a burst. Default to `qps`.
+    #[serde(default)]
+    pub burst: Option<NonZeroU32>,
+    /// Clients in the same IPv4 network of this prefix length share the same limit
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    /// Clients in the same IPv6 network of this prefix length share the same limit
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+    /// The number of clients tracked. The least recently seen ones are forgotten.
+    #[serde(default = "default_max_clients")]
+    pub max_clients: NonZeroUsize,
+}
+
+const fn default_slip() -> u32 {
+    2
+}
+
+const fn default_rrl_ipv4_prefix() -> u8 {
+    24
+}
+
+const fn default_rrl_ipv6_prefix() -> u8 {
+    56
+}
+
+/// Response rate limit of a UDP listener, which works like the one in BIND
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RrlSettings {
+    /// Identical responses allowed per second to each client network
+    pub responses_per_second: NonZeroU32,
+    /// Every `slip`th response over the limit is sent truncated instead of being dropped. 0 drops all of them.
+    #[serde(default = "default_slip")]
+    pub slip: u32,
+    /// Prefix length of the IPv4 client networks
+    #[serde(default = "default_rrl_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    /// Prefix length of the IPv6 client networks
+    #[serde(default = "default_rrl_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+    /// The number of distinct responses tracked. The least recently seen ones are forgotten.
+    #[serde(default = "default_max_clients")]
+    pub max_entries: NonZeroUsize,
+}
+
+// 10 MiB
+const fn default_query_log_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+const fn default_query_log_max_files() -> usize {
+    5
+}
+
+/// Query log written in JSON lines
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QueryLogSettings {
+    /// The file to write to. Write to stdout if not specified.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// The size in bytes at which the file gets rotated
+    #[serde(default = "default_query_log_max_size")]
+    pub max_size: u64,
+    /// The number of rotated files to keep
+    #[serde(default = "default_query_log_max_files")]
+    pub max_files: usize,
+}
+
+/// dnstap output of the queries and responses
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DnstapSettings {
+    /// Where the frames are written to
+    pub output: Output,
+    /// The identity of this server sent along with every message
+    #[serde(default)]
+    pub identity: Option<String>,
+}
+
+/// Action taken on the queries from denied clients
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    /// Answer with REFUSED
+    Refuse,
+    /// Drop the query silently
+    Drop,
+}
+
+impl Default for AclAction {
+    fn default() -> Self {
+        Self::Refuse
+    }
+}
+
+/// Client access control list
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AclSettings {
+    /// IP CIDRs of the clients allowed. Everyone is allowed if it is empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// IP CIDRs of the clients denied, which takes precedence over `allow`
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// What to do with the queries from denied clients
+    #[serde(default)]
+    pub action: AclAction,
+}
@@ -0,0 +1,232 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::parser::QueryLogSettings;
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use domain::{
+    base::Message,
+    rdata::{Aaaa, A},
+};
+use droute::{
+    builders::RuneScript, errors::ScriptError, trace::UpstreamTrace, QueryContext, Router,
+};
+use log::*;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+
+// Entries are dropped rather than slowing down queries if the sink falls this far behind.
+const BUFFER_SIZE: usize = 4096;
+
+static QUERY_LOG: OnceCell<mpsc::Sender<String>> = OnceCell::new();
+
+/// A single line in the query log.
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// Seconds since UNIX epoch
+    timestamp: f64,
+    client: Option<IpAddr>,
+    qname: String,
+    qtype: String,
+    upstreams: &'a [UpstreamTrace],
+    rcode: String,
+    answers: Vec<IpAddr>,
+    latency_ms: f64,
+}
+
+/// Start writing the query log in the background. Queries resolved afterwards are logged.
+pub async fn init(settings: QueryLogSettings) -> Result<()> {
+    let sink = Sink::open(settings).await?;
+    let (tx, rx) = mpsc::channel(BUFFER_SIZE);
+    QUERY_LOG
+        .set(tx)
+        .map_err(|_| anyhow!("query log is already initialized"))?;
+    tokio::spawn(sink.run(rx));
+    Ok(())
+}
+
+/// Resolve the query with the router given, and write an entry to the query log if it is enabled.
+pub async fn resolve(
+    router: &Router<RuneScript>,
+    msg: Message<Bytes>,
+    qctx: Option<QueryContext>,
+) -> std::result::Result<Message<Bytes>, ScriptError> {
+    let log = match QUERY_LOG.get() {
+        Some(log) => log,
+        None => return router.resolve(msg, qctx).await,
+    };
+
+    let start = Instant::now();
+    let client = qctx.as_ref().map(|c| c.ip);
+    let (qname, qtype) = msg
+        .first_question()
+        .map(|q| (q.qname().to_string(), q.qtype().to_string()))
+        .unwrap_or_default();
+
+    let (resp, trace) = router.resolve_traced(msg, qctx).await?;
+
+    let entry = Entry {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        client,
+        qname,
+        qtype,
+        upstreams: &trace.upstreams,
+        rcode: resp.header().rcode().to_string(),
+        answers: answer_ips(&resp),
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+    };
+    match serde_json::to_string(&entry) {
+        Ok(line) => {
+            if log.try_send(line).is_err() {
+                debug!("query log is falling behind, discarding entry");
+            }
+        }
+        Err(e) => warn!("failed to serialize query log entry: {}", e),
+    }
+
+    Ok(resp)
+}
+
+// Addresses in A and AAAA records of the answer section.
+fn answer_ips(msg: &Message<Bytes>) -> Vec<IpAddr> {
+    let mut ips = Vec::new();
+    if let Ok(answer) = msg.answer() {
+        ips.extend(
+            answer
+                .limit_to::<A>()
+                .filter_map(|r| r.ok())
+                .map(|r| IpAddr::V4(r.data().addr())),
+        );
+    }
+    if let Ok(answer) = msg.answer() {
+        ips.extend(
+            answer
+                .limit_to::<Aaaa>()
+                .filter_map(|r| r.ok())
+                .map(|r| IpAddr::V6(r.data().addr())),
+        );
+    }
+    ips
+}
+
+// Where the query log goes. Files are rotated once they are larger than `max_size`, keeping at most `max_files` rotated files named `<path>.1`, `<path>.2`, etc.
+pub(crate) struct Sink {
+    writer: BufWriter<Pin<Box<dyn AsyncWrite + Send>>>,
+    path: Option<PathBuf>,
+    written: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl Sink {
+    pub(crate) async fn open(settings: QueryLogSettings) -> Result<Self> {
+        let (writer, written): (Pin<Box<dyn AsyncWrite + Send>>, _) = match &settings.path {
+            Some(path) => {
+                let file = open_file(path).await?;
+                let written = file.metadata().await?.len();
+                (Box::pin(file), written)
+            }
+            None => (Box::pin(tokio::io::stdout()), 0),
+        };
+        Ok(Self {
+            writer: BufWriter::new(writer),
+            path: settings.path,
+            written,
+            max_size: settings.max_size,
+            max_files: settings.max_files,
+        })
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<String>) {
+        while let Some(line) = rx.recv().await {
+            if let Err(e) = self.write(&line).await {
+                warn!("failed to write query log: {}", e);
+            }
+            // Write out whatever is available, and flush only when we are idle.
+            while let Ok(line) = rx.try_recv() {
+                if let Err(e) = self.write(&line).await {
+                    warn!("failed to write query log: {}", e);
+                }
+            }
+            if let Err(e) = self.writer.flush().await {
+                warn!("failed to flush query log: {}", e);
+            }
+        }
+    }
+
+    pub(crate) async fn write(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.path.is_some() && self.written > 0 && self.written + len > self.max_size {
+            self.rotate().await?;
+        }
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.write_all(b"\n").await?;
+        self.written += len;
+        Ok(())
+    }
+
+    pub(crate) async fn rotate(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        self.writer.flush().await?;
+
+        let rotated = |i: usize| {
+            let mut p = path.clone().into_os_string();
+            p.push(format!(".{}", i));
+            PathBuf::from(p)
+        };
+        if self.max_files == 0 {
+            fs::remove_file(path).await?;
+        } else {
+            // Shift `<path>.i` to `<path>.i+1`, the oldest one gets overwritten.
+            for i in (1..self.max_files).rev() {
+                match fs::rename(rotated(i), rotated(i + 1)).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+            }
+            fs::rename(path, rotated(1)).await?;
+        }
+
+        self.writer = BufWriter::new(Box::pin(open_file(path).await?));
+        self.written = 0;
+        Ok(())
+    }
+}
+
+async fn open_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("failed to open the query log: {}", path.display()))
+}
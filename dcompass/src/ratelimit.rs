@@ -0,0 +1,261 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Rate limits applied at the listeners to keep dcompass from being abused, e.g. for amplification.
+
+use crate::parser::{RateLimitSettings, RrlSettings};
+use bytes::Bytes;
+use clru::CLruCache;
+use domain::base::{iana::rcode::Rcode, Message, Rtype};
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::{
+    hash::Hash,
+    net::IpAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    sync::Mutex,
+    time::Instant,
+};
+
+static LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "dcompass_rate_limited_total",
+        "Number of queries and responses over the rate limits, by action taken (drop, or slip for responses sent truncated)",
+        &["kind", "action"]
+    )
+    .unwrap()
+});
+
+// Tokens are refilled at `rate` per second, up to `burst`.
+// governor is not used here as it is not available on 32-bit platforms.
+#[derive(Clone, Copy)]
+struct Quota {
+    rate: f64,
+    burst: f64,
+}
+
+impl Quota {
+    fn new(rate: NonZeroU32, burst: NonZeroU32) -> Self {
+        Self {
+            rate: rate.get().into(),
+            burst: burst.get().into(),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    // Number of times denied in a row
+    denied: u32,
+}
+
+// Token buckets keyed by `K`. Only the most recently used ones are kept, so that the memory used is bounded regardless of the number of clients.
+struct Buckets<K: Hash + Eq> {
+    quota: Quota,
+    buckets: Mutex<CLruCache<K, Bucket>>,
+}
+
+impl<K: Hash + Eq + Clone> Buckets<K> {
+    fn new(quota: Quota, capacity: NonZeroUsize) -> Self {
+        Self {
+            quota,
+            buckets: Mutex::new(CLruCache::new(capacity)),
+        }
+    }
+
+    // Take a token from the bucket of `key`. On failure, the number of times it has been denied in a row is returned.
+    fn check(&self, key: K) -> Result<(), u32> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.get_mut(&key).is_none() {
+            buckets.put(
+                key.clone(),
+                Bucket {
+                    tokens: self.quota.burst,
+                    last: Instant::now(),
+                    denied: 0,
+                },
+            );
+        }
+        // It must exist as we have just put it in.
+        let bucket = buckets.get_mut(&key).unwrap();
+        let now = Instant::now();
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.last).as_secs_f64() * self.quota.rate)
+            .min(self.quota.burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.denied = 0;
+            Ok(())
+        } else {
+            bucket.denied = bucket.denied.saturating_add(1);
+            Err(bucket.denied)
+        }
+    }
+}
+
+// Clients are accounted by the network they are in.
+#[derive(Clone, Copy)]
+struct Prefix {
+    v4: u8,
+    v6: u8,
+}
+
+impl Prefix {
+    fn mask(&self, ip: IpAddr) -> IpAddr {
+        // Clients on IPv4 reach sockets bound on `[::]` with IPv4-mapped addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        match ip {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.v4.min(32)))
+                    .unwrap_or(0);
+                IpAddr::V4((u32::from(v4) & mask).into())
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.v6.min(128)))
+                    .unwrap_or(0);
+                IpAddr::V6((u128::from(v6) & mask).into())
+            }
+        }
+    }
+}
+
+/// Per-client query rate limiter of a listener. Everything is let through if it is not configured.
+#[derive(Default)]
+pub struct QueryLimiter(Option<(Buckets<IpAddr>, Prefix)>);
+
+impl From<Option<RateLimitSettings>> for QueryLimiter {
+    fn from(settings: Option<RateLimitSettings>) -> Self {
+        Self(settings.map(|s| {
+            (
+                Buckets::new(Quota::new(s.qps, s.burst.unwrap_or(s.qps)), s.max_clients),
+                Prefix {
+                    v4: s.ipv4_prefix,
+                    v6: s.ipv6_prefix,
+                },
+            )
+        }))
+    }
+}
+
+impl QueryLimiter {
+    /// Whether the query from `ip` is within the limit.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        match &self.0 {
+            Some((buckets, prefix)) => {
+                let ok = buckets.check(prefix.mask(ip)).is_ok();
+                if !ok {
+                    LIMITED.with_label_values(&["query", "drop"]).inc();
+                }
+                ok
+            }
+            None => true,
+        }
+    }
+}
+
+/// What to do with a response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RrlAction {
+    Send,
+    /// Send it truncated, so that legitimate clients are able to retry over TCP.
+    Slip,
+    Drop,
+}
+
+// Client network, rcode, qtype, and the name the response is about.
+type ResponseKey = (IpAddr, u8, u16, String);
+
+/// Response rate limiter of a listener, which limits identical responses sent to each client network like BIND does.
+#[derive(Default)]
+pub struct ResponseLimiter(Option<(Buckets<ResponseKey>, Prefix, u32)>);
+
+impl From<Option<RrlSettings>> for ResponseLimiter {
+    fn from(settings: Option<RrlSettings>) -> Self {
+        Self(settings.map(|s| {
+            (
+                Buckets::new(
+                    Quota::new(s.responses_per_second, s.responses_per_second),
+                    s.max_entries,
+                ),
+                Prefix {
+                    v4: s.ipv4_prefix,
+                    v6: s.ipv6_prefix,
+                },
+                s.slip,
+            )
+        }))
+    }
+}
+
+impl ResponseLimiter {
+    /// Decide what to do with the response to be sent to `ip`.
+    pub fn check(&self, ip: IpAddr, resp: &Message<Bytes>) -> RrlAction {
+        let (buckets, prefix, slip) = match &self.0 {
+            Some(rrl) => rrl,
+            None => return RrlAction::Send,
+        };
+        let (rcode, qtype, name) = identity(resp);
+        match buckets.check((prefix.mask(ip), rcode, qtype, name)) {
+            Ok(_) => RrlAction::Send,
+            // Every `slip`th response over the limit slips through truncated.
+            Err(denied) if *slip != 0 && denied % slip == 0 => {
+                LIMITED.with_label_values(&["response", "slip"]).inc();
+                RrlAction::Slip
+            }
+            Err(_) => {
+                LIMITED.with_label_values(&["response", "drop"]).inc();
+                RrlAction::Drop
+            }
+        }
+    }
+}
+
+// What the response is about. Responses with the same identity are considered identical.
+fn identity(resp: &Message<Bytes>) -> (u8, u16, String) {
+    let rcode = resp.header().rcode();
+    let question = resp.first_question();
+    // Names are compared case-insensitively.
+    let qname = question
+        .as_ref()
+        .map(|q| q.qname().to_string().to_ascii_lowercase())
+        .unwrap_or_default();
+    let qtype = question.map(|q| q.qtype().to_int()).unwrap_or(0);
+
+    if rcode == Rcode::NoError && resp.header_counts().ancount() > 0 {
+        (rcode.to_int(), qtype, qname)
+    } else if rcode == Rcode::NoError || rcode == Rcode::NXDomain {
+        // Negative responses are accounted by the zone they are from, so that random subdomains don't get around the limit.
+        let zone = resp.authority().ok().and_then(|mut section| {
+            section.find_map(|record| {
+                record
+                    .ok()
+                    .filter(|r| r.rtype() == Rtype::Soa)
+                    .map(|r| r.owner().to_string().to_ascii_lowercase())
+            })
+        });
+        let qtype = if rcode == Rcode::NXDomain { 0 } else { qtype };
+        (rcode.to_int(), qtype, zone.unwrap_or(qname))
+    } else {
+        // Errors are all accounted together.
+        (rcode.to_int(), 0, String::new())
+    }
+}
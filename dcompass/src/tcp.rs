@@ -0,0 +1,194 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{ratelimit::QueryLimiter, worker, SharedRouter};
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use domain::base::Message;
+use droute::{builders::RuneScript, dnstap::SocketProtocol, QueryContext, Router};
+use log::*;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::{broadcast::Sender, mpsc, Semaphore},
+    time::timeout,
+};
+
+// RFC 7766 recommends the idle timeout to be on the order of seconds.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Maximum number of queries in flight on a single connection. No more queries are read until some of them are answered.
+const MAX_INFLIGHT: usize = 64;
+
+/// Accept incoming TCP connections and serve DNS queries on each of them.
+pub async fn serve_tcp(
+    listener: TcpListener,
+    limiter: Arc<QueryLimiter>,
+    router: SharedRouter,
+    tx: &Sender<()>,
+) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("failed to accept TCP connection: {}", e);
+                continue;
+            }
+        };
+
+        let router = router.clone();
+        let limiter = limiter.clone();
+        let qctx = QueryContext::new(src.ip());
+        let mut shutdown = tx.subscribe();
+        #[rustfmt::skip]
+        tokio::spawn(async move {
+            tokio::select! {
+                biased; res = handle_stream(router, stream, qctx, src.port(), SocketProtocol::Tcp, limiter, IDLE_TIMEOUT) => {
+                    match res {
+                        Ok(_) => (),
+                        Err(e) => warn!("handling TCP connection from {} failed: {}", src, e),
+                    }
+                }
+                _ = shutdown.recv() => {
+                    log::warn!("TCP connection handler shut down");
+                }
+            }
+        });
+    }
+}
+
+/// Serve all the queries sent on a stream which is framed as per RFC 1035, 4.2.2.
+/// Queries are resolved concurrently and their responses are written back as soon as they are ready, which is permitted by RFC 7766.
+/// `qctx` is the query context shared by every query on this stream, `port` is the client's source port, and `protocol` is the transport the stream is carried over.
+/// Queries over the limit of `limiter` are dropped.
+pub async fn handle_stream<S>(
+    router: SharedRouter,
+    stream: S,
+    qctx: QueryContext,
+    port: u16,
+    protocol: SocketProtocol,
+    limiter: Arc<QueryLimiter>,
+    idle_timeout: Duration,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (resp_tx, mut resp_rx) = mpsc::channel::<Bytes>(MAX_INFLIGHT);
+    let src = qctx.ip;
+    let inflight = Arc::new(Semaphore::new(MAX_INFLIGHT));
+
+    let reading = async move {
+        loop {
+            // Waiting for the queries in flight doesn't count as idle.
+            let permit = inflight
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+            // The connection is considered idle if the client doesn't send us a complete query in time.
+            let buf = match timeout(idle_timeout, read_frame(&mut reader)).await {
+                Ok(Ok(Some(buf))) => buf,
+                // Client closed the connection
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    debug!("closing idle connection from {}", src);
+                    break;
+                }
+            };
+
+            if !limiter.check(src) {
+                continue;
+            }
+
+            // Every query is resolved by the router active at the time it arrives.
+            let router = router.load_full();
+            let resp_tx = resp_tx.clone();
+            let qctx = qctx.clone();
+            tokio::spawn(async move {
+                match resolve(&router, buf, qctx, port, protocol).await {
+                    // Writer may have gone away, in which case there is nobody to deliver to.
+                    Ok(Some(resp)) => {
+                        let _ = resp_tx.send(resp).await;
+                    }
+                    Ok(None) => (),
+                    Err(e) => warn!("handling query failed: {}", e),
+                }
+                drop(permit);
+            });
+        }
+        // Dropping the last sender held by us lets the writer exit once all the in-flight queries are answered.
+        Ok::<(), std::io::Error>(())
+    };
+
+    let writing = async move {
+        while let Some(resp) = resp_rx.recv().await {
+            // Prefix our payload with length per RFC.
+            let len = match u16::try_from(resp.len()) {
+                Ok(len) => len.to_be_bytes(),
+                Err(_) => {
+                    warn!("response is too long to be sent over a stream, discarding");
+                    continue;
+                }
+            };
+            writer.write_all(&len).await?;
+            writer.write_all(&resp).await?;
+            writer.flush().await?;
+            info!("response completed. Sent back to {} successfully.", src);
+        }
+        writer.shutdown().await?;
+        Ok::<(), std::io::Error>(())
+    };
+
+    tokio::try_join!(reading, writing)?;
+    Ok(())
+}
+
+// Read a length-prefixed message. `None` is returned if the stream is closed before a new message starts.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<Bytes>> {
+    let mut len = [0; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len: usize = u16::from_be_bytes(len).into();
+
+    let mut buf = BytesMut::with_capacity(len);
+    buf.resize(len, 0);
+    reader.read_exact(&mut buf).await?;
+
+    Ok(Some(buf.freeze()))
+}
+
+async fn resolve(
+    router: &Router<RuneScript>,
+    buf: Bytes,
+    qctx: QueryContext,
+    port: u16,
+    protocol: SocketProtocol,
+) -> Result<Option<Bytes>> {
+    Ok(worker::resolve(
+        router,
+        Message::from_octets(buf)?,
+        qctx,
+        Some(port),
+        protocol,
+    )
+    .await?
+    .map(Message::into_octets))
+}
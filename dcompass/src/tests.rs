@@ -13,8 +13,43 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::init;
-use droute::errors::*;
+use super::{init, listener::Server, tcp::handle_stream, SharedRouter};
+use arc_swap::ArcSwap;
+use bytes::{Bytes, BytesMut};
+use domain::base::{Dname, Message, MessageBuilder, Rtype};
+use droute::{
+    builders::{RouterBuilder, RuneScriptBuilder, UpstreamBuilder, UpstreamsBuilder},
+    dnstap::SocketProtocol,
+    errors::*,
+    AsyncTryInto, QueryContext,
+};
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+async fn create_router(script: &str) -> SharedRouter {
+    Arc::new(ArcSwap::from_pointee(
+        RouterBuilder::new(
+            RuneScriptBuilder::new(script),
+            UpstreamsBuilder::<UpstreamBuilder>::new(1).unwrap(),
+        )
+        .async_try_into()
+        .await
+        .unwrap(),
+    ))
+}
+
+async fn blackhole_router() -> SharedRouter {
+    create_router("pub async fn route(upstreams, inited, ctx, query) { blackhole(query) }").await
+}
+
+fn create_query(id: u16) -> Bytes {
+    let name = Dname::<Bytes>::from_str("example.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
+    builder.header_mut().set_id(id);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A)).unwrap();
+    builder.into_message().into_octets().freeze()
+}
 
 #[tokio::test]
 async fn check_default() {
@@ -23,6 +58,58 @@ async fn check_default() {
         .unwrap();
 }
 
+#[tokio::test]
+async fn check_success_listeners() {
+    let (_, settings) =
+        init(serde_yaml::from_str(include_str!("../../configs/success_listeners.yaml")).unwrap())
+            .await
+            .unwrap();
+    assert_eq!(settings.listeners.len(), 3);
+    for listener in settings.listeners {
+        Server::prepare(listener).unwrap();
+    }
+}
+
+#[test]
+fn acl_decisions() {
+    use super::{
+        acl::{Acl, Decision},
+        parser::{AclAction, AclSettings},
+    };
+
+    let acl = Acl::new(AclSettings {
+        allow: vec!["192.168.0.0/16".to_string(), "::1".to_string()],
+        deny: vec!["192.168.1.0/24".to_string()],
+        action: AclAction::Drop,
+    })
+    .unwrap();
+    let check = |ip: &str| acl.check(ip.parse().unwrap());
+    assert_eq!(check("192.168.0.1"), Decision::Allow);
+    assert_eq!(check("::1"), Decision::Allow);
+    // IPv4-mapped addresses are matched as IPv4 ones.
+    assert_eq!(check("::ffff:192.168.0.1"), Decision::Allow);
+    // Denied ones take precedence
+    assert_eq!(check("192.168.1.1"), Decision::Drop);
+    assert_eq!(check("8.8.8.8"), Decision::Drop);
+
+    // Everyone not denied is allowed if there is nothing in `allow`.
+    let acl = Acl::new(AclSettings {
+        allow: Vec::new(),
+        deny: vec!["10.0.0.0/8".to_string()],
+        action: AclAction::Refuse,
+    })
+    .unwrap();
+    assert_eq!(acl.check("8.8.8.8".parse().unwrap()), Decision::Allow);
+    assert_eq!(acl.check("10.0.0.1".parse().unwrap()), Decision::Refuse);
+
+    assert!(Acl::new(AclSettings {
+        allow: vec!["not a cidr".to_string()],
+        deny: Vec::new(),
+        action: AclAction::Refuse,
+    })
+    .is_err());
+}
+
 #[tokio::test]
 async fn check_success_ipcidr() {
     assert_eq!(true, true);
@@ -86,6 +173,27 @@ async fn check_success_header_yaml() {
     );
 }
 
+#[tokio::test]
+async fn check_success_zone() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_zone.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_static() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_static.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn check_success_health_check() {
+    init(serde_yaml::from_str(include_str!("../../configs/success_health_check.yaml")).unwrap())
+        .await
+        .unwrap();
+}
+
 #[tokio::test]
 async fn check_fail_recursion() {
     match init(serde_yaml::from_str(include_str!("../../configs/fail_recursion.json")).unwrap())
@@ -97,3 +205,421 @@ async fn check_fail_recursion() {
         e => panic!("Not the right error type: {}", e),
     };
 }
+
+#[cfg(unix)]
+#[tokio::test]
+async fn reload_router() {
+    use super::reload;
+
+    let router = blackhole_router().await;
+    let path = std::env::temp_dir().join("dcompass-reload-test.yaml");
+
+    // Broken config should leave the running router in place
+    std::fs::write(&path, include_str!("../../configs/fail_recursion.json")).unwrap();
+    let old = router.load_full();
+    assert!(reload(&path, &router).await.is_err());
+    assert!(Arc::ptr_eq(&old, &router.load_full()));
+
+    std::fs::write(&path, include_str!("../../configs/success_listeners.yaml")).unwrap();
+    reload(&path, &router).await.unwrap();
+    assert!(!Arc::ptr_eq(&old, &router.load_full()));
+}
+
+#[tokio::test]
+async fn tcp_pipelined_queries() {
+    let (client, server) = tokio::io::duplex(4096);
+    tokio::spawn(handle_stream(
+        blackhole_router().await,
+        server,
+        QueryContext::new("127.0.0.1".parse().unwrap()),
+        53000,
+        SocketProtocol::Tcp,
+        Default::default(),
+        Duration::from_millis(500),
+    ));
+    let (mut reader, mut writer) = tokio::io::split(client);
+
+    // Send all the queries at once without waiting for any response
+    for id in 0..3 {
+        let query = create_query(id);
+        writer
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        writer.write_all(&query).await.unwrap();
+    }
+
+    let mut ids = Vec::new();
+    for _ in 0..3 {
+        let mut len = [0; 2];
+        reader.read_exact(&mut len).await.unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len).into()];
+        reader.read_exact(&mut buf).await.unwrap();
+        ids.push(Message::from_octets(buf).unwrap().header().id());
+    }
+    // Responses may come back out of order
+    ids.sort_unstable();
+    assert_eq!(ids, vec![0, 1, 2]);
+
+    // Connection should be closed by the server once it is idle
+    let mut len = [0; 2];
+    assert_eq!(
+        reader.read_exact(&mut len).await.unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+#[tokio::test]
+async fn doh_get_and_post() {
+    use super::https::{create_acceptor, serve_https, DohSettings};
+    use tokio::{net::TcpListener, sync::broadcast};
+
+    // Answer with two records, whose smaller TTL should be used in Cache-Control
+    let router = create_router(
+        r#"pub async fn route(upstreams, inited, ctx, query) {
+          let header = query.header;
+          header.qr = true;
+          query.header = header;
+          query.push_answer(DnsRecord::new(query.first_question?.qname, Class::from_str("IN")?, 3600, A::new(IpAddr::from_str("1.1.1.1")?)?.to_rdata()))?;
+          query.push_answer(DnsRecord::new(query.first_question?.qname, Class::from_str("IN")?, 600, A::new(IpAddr::from_str("1.0.0.1")?)?.to_rdata()))?;
+          Ok(query)
+        }"#,
+    )
+    .await;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join("dcompass-doh-test-cert.pem");
+    let key_path = dir.join("dcompass-doh-test-key.pem");
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+    let acceptor = create_acceptor(&cert_path, &key_path).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (tx, _) = broadcast::channel(1);
+        serve_https(
+            listener,
+            acceptor,
+            Arc::new(DohSettings {
+                path: "/dns-query".to_string(),
+                trusted_proxies: Vec::new(),
+                limiter: Default::default(),
+            }),
+            router,
+            &tx,
+        )
+        .await
+    });
+
+    let client = reqwest::Client::builder()
+        .add_root_certificate(
+            reqwest::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap(),
+        )
+        .resolve("localhost", addr)
+        .build()
+        .unwrap();
+    let url = format!("https://localhost:{}/dns-query", addr.port());
+
+    let get = client
+        .get(format!(
+            "{}?dns={}",
+            url,
+            base64::encode_config(create_query(0), base64::URL_SAFE_NO_PAD)
+        ))
+        .send()
+        .await
+        .unwrap();
+    let post = client
+        .post(&url)
+        .header("content-type", "application/dns-message")
+        .body(create_query(1))
+        .send()
+        .await
+        .unwrap();
+
+    for (id, resp) in [(0, get), (1, post)] {
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/dns-message");
+        assert_eq!(resp.headers()["cache-control"], "max-age=600");
+        let msg = Message::from_octets(resp.bytes().await.unwrap()).unwrap();
+        assert_eq!(msg.header().id(), id);
+        assert_eq!(msg.header_counts().ancount(), 2);
+    }
+
+    // Queries on other paths are not served
+    assert_eq!(
+        client
+            .post(format!("https://localhost:{}/other", addr.port()))
+            .header("content-type", "application/dns-message")
+            .body(create_query(2))
+            .send()
+            .await
+            .unwrap()
+            .status(),
+        reqwest::StatusCode::NOT_FOUND
+    );
+}
+
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+#[tokio::test]
+async fn dot_handshake_and_sni() {
+    use super::tls::{create_acceptor, serve_tls};
+    use tokio::{net::TcpListener, sync::broadcast};
+    use tokio_rustls::{
+        rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+        TlsConnector,
+    };
+
+    // Answer only if the SNI reaches the script
+    let router = create_router(
+        r#"pub async fn route(upstreams, inited, ctx, query) {
+          if let Some(sni) = ctx.sni {
+            if sni == "localhost" {
+              let header = query.header;
+              header.qr = true;
+              query.header = header;
+              query.push_answer(DnsRecord::new(query.first_question?.qname, Class::from_str("IN")?, 600, A::new(IpAddr::from_str("1.1.1.1")?)?.to_rdata()))?;
+              return Ok(query);
+            }
+          }
+          blackhole(query)
+        }"#,
+    )
+    .await;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join("dcompass-dot-test-cert.pem");
+    let key_path = dir.join("dcompass-dot-test-key.pem");
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+    let acceptor = create_acceptor(&cert_path, &key_path).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (tx, _) = broadcast::channel(1);
+        serve_tls(listener, acceptor, Default::default(), router, &tx).await
+    });
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&Certificate(cert.serialize_der().unwrap()))
+        .unwrap();
+    let connector = TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ));
+    let mut stream = connector
+        .connect(
+            ServerName::try_from("localhost").unwrap(),
+            tokio::net::TcpStream::connect(addr).await.unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let query = create_query(7);
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await
+        .unwrap();
+    stream.write_all(&query).await.unwrap();
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await.unwrap();
+    let mut buf = vec![0; u16::from_be_bytes(len).into()];
+    stream.read_exact(&mut buf).await.unwrap();
+
+    let msg = Message::from_octets(buf).unwrap();
+    assert_eq!(msg.header().id(), 7);
+    assert_eq!(msg.header_counts().ancount(), 1);
+}
+
+#[tokio::test]
+async fn metrics_endpoint() {
+    use super::metrics::serve_metrics;
+    use tokio::{net::TcpListener, sync::broadcast};
+
+    let router = blackhole_router().await;
+    router
+        .load()
+        .resolve(Message::from_octets(create_query(0)).unwrap(), None)
+        .await
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (tx, _) = broadcast::channel(1);
+        serve_metrics(listener, router, &tx).await
+    });
+
+    let resp = reqwest::get(format!("http://{}/metrics", addr))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert!(resp
+        .text()
+        .await
+        .unwrap()
+        .contains(r#"dcompass_queries_total{qtype="A",rcode="NOERROR"}"#));
+
+    assert_eq!(
+        reqwest::get(format!("http://{}/other", addr))
+            .await
+            .unwrap()
+            .status(),
+        reqwest::StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn query_log_rotation() {
+    use super::{parser::QueryLogSettings, querylog::Sink};
+
+    let dir = std::env::temp_dir().join("dcompass-query-log-test");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("query.log");
+
+    // Each line takes 10 bytes with the newline, so every file holds two lines.
+    let mut sink = Sink::open(QueryLogSettings {
+        path: Some(path.clone()),
+        max_size: 20,
+        max_files: 2,
+    })
+    .await
+    .unwrap();
+    for i in 0..7 {
+        sink.write(&format!("line {:04}", i)).await.unwrap();
+    }
+    sink.rotate().await.unwrap();
+
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!(read("query.log"), "");
+    assert_eq!(read("query.log.1"), "line 0006\n");
+    assert_eq!(read("query.log.2"), "line 0004\nline 0005\n");
+    // Older ones are discarded
+    assert!(!dir.join("query.log.3").exists());
+}
+
+#[test]
+fn udp_truncation() {
+    use super::truncate::{payload_size, slip, truncate};
+    use domain::rdata::A;
+
+    // 100 answers of 16 bytes each, along with an OPT record advertising 4096 bytes, make up a 1640-byte message.
+    let name = Dname::<Bytes>::from_str("example.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+    builder.header_mut().set_qr(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A)).unwrap();
+    let mut builder = builder.answer();
+    for i in 0..100 {
+        builder
+            .push((&name, 3600, A::from_octets(10, 0, 0, i)))
+            .unwrap();
+    }
+    let mut builder = builder.additional();
+    builder
+        .opt(|opt| {
+            opt.set_udp_payload_size(4096);
+            Ok(())
+        })
+        .unwrap();
+    let resp = builder.into_message();
+    assert_eq!(resp.as_slice().len(), 1640);
+
+    // Payload size advertised is capped, and clients without EDNS get 512 bytes.
+    assert_eq!(payload_size(&resp, 1232), 1232);
+    assert_eq!(payload_size(&resp, 65535), 4096);
+    assert_eq!(
+        payload_size(&Message::from_octets(create_query(0)).unwrap(), 1232),
+        512
+    );
+
+    assert_eq!(truncate(&resp, 4096).unwrap().as_slice(), resp.as_slice());
+
+    let truncated = truncate(&resp, 512).unwrap();
+    assert!(truncated.as_slice().len() <= 512);
+    assert!(truncated.header().tc());
+    assert_eq!(truncated.header_counts().ancount(), 29);
+    assert_eq!(truncated.header_counts().arcount(), 1);
+    for record in truncated.answer().unwrap() {
+        record.unwrap();
+    }
+    assert_eq!(
+        truncated
+            .additional()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .rtype(),
+        Rtype::Opt
+    );
+
+    // Slipped responses contain nothing but the question.
+    let slipped = slip(&resp).unwrap();
+    assert!(slipped.header().tc());
+    assert_eq!(slipped.header_counts().qdcount(), 1);
+    assert_eq!(slipped.header_counts().ancount(), 0);
+    assert_eq!(slipped.header_counts().arcount(), 0);
+    assert_eq!(slipped.as_slice().len(), 29);
+
+    // Malformed responses are cut down to the header
+    let malformed = Message::from_octets(resp.as_octets().slice(..600)).unwrap();
+    let malformed = truncate(&malformed, 512).unwrap();
+    assert_eq!(malformed.as_slice().len(), 12);
+    assert!(malformed.header().tc());
+    assert_eq!(malformed.header_counts().ancount(), 0);
+}
+
+#[test]
+fn rate_limits() {
+    use super::{
+        parser::{RateLimitSettings, RrlSettings},
+        ratelimit::{QueryLimiter, ResponseLimiter, RrlAction},
+    };
+    use std::num::{NonZeroU32, NonZeroUsize};
+
+    let one = NonZeroU32::new(1).unwrap();
+    let limiter: QueryLimiter = Some(RateLimitSettings {
+        qps: one,
+        burst: None,
+        ipv4_prefix: 24,
+        ipv6_prefix: 128,
+        max_clients: NonZeroUsize::new(1).unwrap(),
+    })
+    .into();
+    let check = |ip: &str| limiter.check(ip.parse().unwrap());
+    assert!(check("10.0.0.1"));
+    // Clients in the same network share the limit
+    assert!(!check("10.0.0.2"));
+    assert!(check("10.0.1.1"));
+    // Only one client is tracked, so the first one is forgotten.
+    assert!(check("10.0.0.1"));
+
+    // Everything is let through without limits
+    assert!((0..10).all(|_| QueryLimiter::default().check("10.0.0.1".parse().unwrap())));
+
+    let rrl: ResponseLimiter = Some(RrlSettings {
+        responses_per_second: one,
+        slip: 2,
+        ipv4_prefix: 24,
+        ipv6_prefix: 56,
+        max_entries: NonZeroUsize::new(16).unwrap(),
+    })
+    .into();
+    let resp = Message::from_octets(create_query(0)).unwrap();
+    let check = |ip: &str| rrl.check(ip.parse().unwrap(), &resp);
+    assert_eq!(check("10.0.0.1"), RrlAction::Send);
+    assert_eq!(check("10.0.0.2"), RrlAction::Drop);
+    assert_eq!(check("10.0.0.3"), RrlAction::Slip);
+    assert_eq!(check("10.0.0.4"), RrlAction::Drop);
+    assert_eq!(check("10.0.1.1"), RrlAction::Send);
+}
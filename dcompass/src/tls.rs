@@ -0,0 +1,135 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+    ratelimit::QueryLimiter,
+    tcp::{handle_stream, IDLE_TIMEOUT},
+    SharedRouter,
+};
+use anyhow::{anyhow, Context, Result};
+use droute::{dnstap::SocketProtocol, QueryContext};
+use log::*;
+use rustls_pemfile::Item;
+use std::{fs::File, io::BufReader, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::Sender,
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+// Clients that cannot finish the handshake in time are unlikely to be legit.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Create the server side TLS configuration from the certificate chain and the private key, both in PEM format.
+pub fn load_server_config(cert: &Path, key: &Path) -> Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert)
+            .with_context(|| format!("failed to open the certificate: {}", cert.display()))?,
+    ))
+    .with_context(|| format!("failed to parse the certificate: {}", cert.display()))?
+    .into_iter()
+    .map(Certificate)
+    .collect();
+
+    let private_key = rustls_pemfile::read_all(&mut BufReader::new(
+        File::open(key)
+            .with_context(|| format!("failed to open the private key: {}", key.display()))?,
+    ))
+    .with_context(|| format!("failed to parse the private key: {}", key.display()))?
+    .into_iter()
+    .find_map(|item| match item {
+        Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+        _ => None,
+    })
+    .ok_or_else(|| anyhow!("no private key found in {}", key.display()))?;
+
+    Ok(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, private_key)?)
+}
+
+/// Create a TLS acceptor for DNS over TLS.
+pub fn create_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(load_server_config(cert, key)?)))
+}
+
+/// Accept incoming TCP connections, and serve DNS queries on each of them after the TLS handshake completes.
+pub async fn serve_tls(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    limiter: Arc<QueryLimiter>,
+    router: SharedRouter,
+    tx: &Sender<()>,
+) {
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("failed to accept TLS connection: {}", e);
+                continue;
+            }
+        };
+
+        let router = router.clone();
+        let acceptor = acceptor.clone();
+        let limiter = limiter.clone();
+        let mut shutdown = tx.subscribe();
+        #[rustfmt::skip]
+        tokio::spawn(async move {
+            tokio::select! {
+                biased; res = handle_tls(router, acceptor, limiter, stream, src) => {
+                    match res {
+                        Ok(_) => (),
+                        Err(e) => warn!("handling TLS connection from {} failed: {}", src, e),
+                    }
+                }
+                _ = shutdown.recv() => {
+                    log::warn!("TLS connection handler shut down");
+                }
+            }
+        });
+    }
+}
+
+async fn handle_tls(
+    router: SharedRouter,
+    acceptor: TlsAcceptor,
+    limiter: Arc<QueryLimiter>,
+    stream: TcpStream,
+    src: SocketAddr,
+) -> Result<()> {
+    let stream = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("TLS handshake timed out")??;
+
+    let sni = stream.get_ref().1.sni_hostname().map(|s| s.to_string());
+    debug!("TLS connection from {} established with SNI {:?}", src, sni);
+
+    handle_stream(
+        router,
+        stream,
+        QueryContext::new(src.ip()).with_sni(sni),
+        src.port(),
+        SocketProtocol::Dot,
+        limiter,
+        IDLE_TIMEOUT,
+    )
+    .await
+}
@@ -0,0 +1,59 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Fit responses into the UDP payload size the client is able to receive.
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use domain::base::{Message, MessageBuilder};
+use droute::utils;
+
+// Every client must be able to receive this much as per RFC 1035 and RFC 6891.
+const MIN_PAYLOAD_SIZE: u16 = 512;
+
+/// The largest response the client can receive, which is the UDP payload size advertised in its EDNS OPT record, capped at `max`.
+/// Queries without EDNS are limited to 512 bytes.
+pub fn payload_size(query: &Message<Bytes>, max: u16) -> usize {
+    let advertised = query
+        .opt()
+        .map(|opt| opt.udp_payload_size())
+        .unwrap_or(MIN_PAYLOAD_SIZE);
+    advertised.min(max).max(MIN_PAYLOAD_SIZE).into()
+}
+
+/// Truncate the response to at most `limit` bytes at a record boundary, see `droute::utils::truncate`.
+/// Malformed responses that are too long are cut down to the header, with TC set so that the client retries over TCP.
+pub fn truncate(resp: &Message<Bytes>, limit: usize) -> Result<Message<Bytes>> {
+    utils::truncate(resp, limit).or_else(|_| stub(resp, false))
+}
+
+/// Strip the response down to the header and questions with TC set, which is sent in place of responses over the rate limit.
+pub fn slip(resp: &Message<Bytes>) -> Result<Message<Bytes>> {
+    stub(resp, true).or_else(|_| stub(resp, false))
+}
+
+// Keep only the header with TC set, followed by the questions if `questions` is set.
+fn stub(resp: &Message<Bytes>, questions: bool) -> Result<Message<Bytes>> {
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(resp.as_slice().len()))?;
+    *builder.header_mut() = resp.header();
+    builder.header_mut().set_tc(true);
+    let mut builder = builder.question();
+    if questions {
+        for item in resp.question() {
+            builder.push(item?)?;
+        }
+    }
+    Ok(builder.into_message())
+}
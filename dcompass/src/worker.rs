@@ -13,13 +13,82 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use crate::{
+    acl::{self, Decision},
+    querylog,
+    ratelimit::{QueryLimiter, ResponseLimiter, RrlAction},
+    truncate, SharedRouter,
+};
 use anyhow::Result;
-use bytes::Bytes;
-use domain::base::Message;
-use droute::{builders::RuneScript, QueryContext, Router};
+use bytes::{Bytes, BytesMut};
+use domain::base::{iana::rcode::Rcode, Message, MessageBuilder};
+use droute::{
+    builders::RuneScript,
+    dnstap::{self, Event, MessageType, SocketProtocol},
+    errors::ScriptError,
+    QueryContext, Router,
+};
 use log::*;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, sync::broadcast::Sender};
+
+// Largest UDP payload possible
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Settings of a UDP listener shared by all of its workers
+pub struct UdpConfig {
+    /// The largest response to send
+    pub max_payload_size: u16,
+    pub limiter: QueryLimiter,
+    pub rrl: ResponseLimiter,
+}
+
+/// Receive queries on the UDP socket and handle each of them in a worker.
+pub async fn serve_udp(
+    socket: Arc<UdpSocket>,
+    config: Arc<UdpConfig>,
+    router: SharedRouter,
+    tx: &Sender<()>,
+) {
+    // Queries are received into this buffer and then copied out, so that any query that fits in a UDP datagram is read in full.
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        // On windows, some applications may go away after they got their first response, resulting in a broken pipe, we should discard errors on receiving/sending messages.
+        let (len, src) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("failed to receive query: {}", e);
+                continue;
+            }
+        };
+        // Queries over the limit are dropped without being looked at.
+        if !config.limiter.check(src.ip()) {
+            continue;
+        }
+        let query = Bytes::copy_from_slice(&buf[..len]);
+
+        let router = router.load_full();
+        let socket = socket.clone();
+        let config = config.clone();
+        let mut shutdown = tx.subscribe();
+        #[rustfmt::skip]
+        tokio::spawn(async move {
+            tokio::select! {
+                biased; res = worker(router, socket, query, src, config) => {
+                    match res {
+                        Ok(_) => (),
+                        Err(e) => warn!("handling query failed: {}", e),
+                    }
+                }
+                _ = shutdown.recv() => {
+                    // If a shutdown signal is received, return from the spawned task.
+                    // This will result in the task terminating.
+                    log::warn!("worker shut down");
+                }
+            }
+        });
+    }
+}
 
 /// Handle a single incoming packet
 pub async fn worker(
@@ -27,18 +96,41 @@ pub async fn worker(
     socket: Arc<UdpSocket>,
     buf: Bytes,
     src: SocketAddr,
+    config: Arc<UdpConfig>,
 ) -> Result<()> {
+    let query = Message::from_octets(buf)?;
+    let limit = truncate::payload_size(&query, config.max_payload_size);
+    let resp = match resolve(
+        &router,
+        query,
+        QueryContext::new(src.ip()),
+        Some(src.port()),
+        SocketProtocol::Udp,
+    )
+    .await?
+    {
+        Some(resp) => resp,
+        None => return Ok(()),
+    };
+    let resp = match config.rrl.check(src.ip(), &resp) {
+        RrlAction::Send => resp,
+        RrlAction::Slip => truncate::slip(&resp)?,
+        RrlAction::Drop => return Ok(()),
+    };
+    let resp = if resp.as_slice().len() > limit {
+        debug!(
+            "response of {} bytes is truncated to fit in {} bytes for {}",
+            resp.as_slice().len(),
+            limit,
+            src
+        );
+        truncate::truncate(&resp, limit)?
+    } else {
+        resp
+    };
+
     socket
-        .send_to(
-            router
-                .resolve(
-                    Message::from_octets(buf)?,
-                    Some(QueryContext { ip: src.ip() }),
-                )
-                .await?
-                .as_slice(),
-            src,
-        )
+        .send_to(resp.as_slice(), src)
         .await
         .unwrap_or_else(|e| {
             warn!("failed to send back response: {}", e);
@@ -49,3 +141,47 @@ pub async fn worker(
 
     Ok(())
 }
+
+/// Resolve a query received from a client over `protocol`, which is where every listener hands its queries to the router.
+/// `port` is the client's source port, if it is known. `None` is returned if the query should be dropped without any response.
+pub async fn resolve(
+    router: &Router<RuneScript>,
+    msg: Message<Bytes>,
+    qctx: QueryContext,
+    port: Option<u16>,
+    protocol: SocketProtocol,
+) -> std::result::Result<Option<Message<Bytes>>, ScriptError> {
+    let ip = qctx.ip;
+    match acl::check(ip) {
+        Decision::Allow => (),
+        Decision::Refuse => {
+            debug!("refusing query from {} denied by the ACL", ip);
+            return Ok(Some(
+                MessageBuilder::from_target(BytesMut::with_capacity(msg.as_slice().len()))?
+                    .start_answer(&msg, Rcode::Refused)?
+                    .into_message(),
+            ));
+        }
+        Decision::Drop => {
+            debug!("dropping query from {} denied by the ACL", ip);
+            return Ok(None);
+        }
+    }
+
+    let tap = |kind, message: &[u8]| {
+        dnstap::emit(Event {
+            kind,
+            protocol,
+            query_address: Some(ip),
+            query_port: port,
+            response_address: None,
+            response_port: None,
+            message,
+        })
+    };
+
+    tap(MessageType::ClientQuery, msg.as_slice());
+    let resp = querylog::resolve(router, msg, Some(qctx)).await?;
+    tap(MessageType::ClientResponse, resp.as_slice());
+    Ok(Some(resp))
+}
@@ -59,6 +59,8 @@ async fn create_router<T: ScriptBackend>(script_builder: impl ScriptBuilder<T>)
                 max_pool_size: 256,
                 timeout: 1,
                 ratelimit: None,
+                edns_size: 1232,
+                buffer_size: 4096,
             }),
         ),
     )
@@ -14,7 +14,7 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use self::RecordStatus::*;
-use crate::{Label, MAX_TTL};
+use crate::{metrics::CACHE_LOOKUPS, Label, MAX_TTL};
 use bytes::Bytes;
 use clru::CLruCache;
 use domain::base::{name::ToDname, Message};
@@ -119,6 +119,15 @@ impl RespCache {
         }
     }
 
+    // Change the capacity, dropping the least recently used records if it shrinks.
+    pub fn resize(&self, size: NonZeroUsize) {
+        self.cache.lock().unwrap().resize(size)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cache.lock().unwrap().capacity()
+    }
+
     pub fn put(&self, tag: Label, query: &Message<Bytes>, msg: Message<Bytes>) {
         if msg.no_error() {
             // We are assured that it should parse and exist
@@ -159,13 +168,18 @@ impl RespCache {
                 // Get record only once.
                 if r.validate() {
                     info!("cache hit for {}", qname);
+                    CACHE_LOOKUPS.with_label_values(&["hit"]).inc();
                     Some(Alive(r.get()))
                 } else {
                     info!("TTL passed for {}, returning expired record.", qname);
+                    CACHE_LOOKUPS.with_label_values(&["expired"]).inc();
                     Some(Expired(r.get()))
                 }
             }
-            Option::None => Option::None,
+            Option::None => {
+                CACHE_LOOKUPS.with_label_values(&["miss"]).inc();
+                Option::None
+            }
         }
     }
 }
@@ -0,0 +1,147 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Frame Streams protocol, see https://farsightsec.github.io/fstrm/.
+
+use std::io::{Error, ErrorKind, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Content type of the dnstap payloads
+pub const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// A control frame starts with the escape sequence, which would be a data frame of length zero.
+const ESCAPE: u32 = 0;
+
+// Control frame types
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_READY: u32 = 0x04;
+
+// Control field types
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+// Control frames are tiny, anything larger is garbage.
+const MAX_CONTROL_LEN: u32 = 512;
+
+/// Write a data frame
+pub async fn write_data<W: AsyncWrite + Unpin>(w: &mut W, data: &[u8]) -> Result<()> {
+    let len = u32::try_from(data.len()).map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+    w.write_all(&len.to_be_bytes()).await?;
+    w.write_all(data).await
+}
+
+// Write a control frame carrying our content type.
+async fn write_control<W: AsyncWrite + Unpin>(w: &mut W, ty: u32) -> Result<()> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&ty.to_be_bytes());
+    frame.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+    frame.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+    frame.extend_from_slice(CONTENT_TYPE);
+    w.write_all(&ESCAPE.to_be_bytes()).await?;
+    w.write_all(&(frame.len() as u32).to_be_bytes()).await?;
+    w.write_all(&frame).await?;
+    w.flush().await
+}
+
+/// Read a control frame, and return its type along with the content types it carries.
+pub async fn read_control<R: AsyncRead + Unpin>(r: &mut R) -> Result<(u32, Vec<Vec<u8>>)> {
+    if r.read_u32().await? != ESCAPE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "expected a control frame",
+        ));
+    }
+    let len = r.read_u32().await?;
+    if !(4..=MAX_CONTROL_LEN).contains(&len) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "invalid control frame length",
+        ));
+    }
+    let mut frame = vec![0; len as usize];
+    r.read_exact(&mut frame).await?;
+
+    let ty = u32::from_be_bytes(frame[..4].try_into().unwrap());
+    let mut content_types = Vec::new();
+    let mut rest = &frame[4..];
+    while rest.len() >= 8 {
+        let field = u32::from_be_bytes(rest[..4].try_into().unwrap());
+        let len = u32::from_be_bytes(rest[4..8].try_into().unwrap()) as usize;
+        rest = &rest[8..];
+        if rest.len() < len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "truncated control field",
+            ));
+        }
+        if field == FIELD_CONTENT_TYPE {
+            content_types.push(rest[..len].to_vec());
+        }
+        rest = &rest[len..];
+    }
+    Ok((ty, content_types))
+}
+
+/// Start a unidirectional stream, e.g. to a file.
+pub async fn start<W: AsyncWrite + Unpin>(w: &mut W) -> Result<()> {
+    write_control(w, CONTROL_START).await
+}
+
+/// Start a bidirectional stream, e.g. to a Unix socket, where the reader has to accept our content type first.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(s: &mut S) -> Result<()> {
+    write_control(s, CONTROL_READY).await?;
+    match read_control(s).await? {
+        (CONTROL_ACCEPT, types) if types.is_empty() || types.iter().any(|t| t == CONTENT_TYPE) => {
+            start(s).await
+        }
+        (CONTROL_ACCEPT, _) => Err(Error::new(
+            ErrorKind::InvalidData,
+            "reader doesn't accept dnstap content type",
+        )),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "expected an ACCEPT frame",
+        )),
+    }
+}
+
+/// Accept a bidirectional stream as a reader. Used by tests.
+#[doc(hidden)]
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(s: &mut S) -> Result<()> {
+    match read_control(s).await? {
+        (CONTROL_READY, _) => write_control(s, CONTROL_ACCEPT).await?,
+        _ => return Err(Error::new(ErrorKind::InvalidData, "expected a READY frame")),
+    }
+    match read_control(s).await? {
+        (CONTROL_START, _) => Ok(()),
+        _ => Err(Error::new(ErrorKind::InvalidData, "expected a START frame")),
+    }
+}
+
+/// Read a data frame as a reader. `None` is returned when the writer stops. Used by tests.
+#[doc(hidden)]
+pub async fn read_data<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let len = r.read_u32().await?;
+    if len == ESCAPE {
+        // Control frame, which can only be STOP here
+        let len = r.read_u32().await?;
+        let mut frame = vec![0; len as usize];
+        r.read_exact(&mut frame).await?;
+        return Ok(None);
+    }
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
@@ -0,0 +1,303 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Output DNS messages seen in the [dnstap](https://dnstap.info) format over Frame Streams.
+
+pub mod fstrm;
+
+use log::*;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::IpAddr,
+    path::PathBuf,
+    pin::Pin,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    fs::File,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+
+// Frames are dropped rather than slowing down queries if the output falls this far behind.
+const BUFFER_SIZE: usize = 4096;
+
+// Minimum interval between reconnection attempts to the socket.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+static TAP: OnceCell<Tap> = OnceCell::new();
+
+struct Tap {
+    tx: mpsc::Sender<Vec<u8>>,
+    identity: Vec<u8>,
+    version: Vec<u8>,
+}
+
+/// Where the dnstap frames are written to
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// Write to a file, which is truncated on start.
+    File(PathBuf),
+    /// Connect to a Unix socket on which a dnstap reader (e.g. `fstrm_capture`) listens. Connection is re-established if it is lost.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Type of the dnstap message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    /// Query received from a client
+    ClientQuery = 5,
+    /// Response sent back to a client
+    ClientResponse = 6,
+    /// Query forwarded to an upstream
+    ForwarderQuery = 7,
+    /// Response received from an upstream
+    ForwarderResponse = 8,
+}
+
+impl MessageType {
+    fn is_query(&self) -> bool {
+        matches!(self, Self::ClientQuery | Self::ForwarderQuery)
+    }
+}
+
+/// Transport protocol the DNS message is sent over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketProtocol {
+    /// DNS over UDP
+    Udp = 1,
+    /// DNS over TCP
+    Tcp = 2,
+    /// DNS over TLS
+    Dot = 3,
+    /// DNS over HTTPS
+    Doh = 4,
+    /// DNSCrypt over UDP
+    DnsCryptUdp = 5,
+    /// DNSCrypt over TCP
+    DnsCryptTcp = 6,
+    /// DNS over QUIC
+    Doq = 7,
+}
+
+/// A DNS message to be logged
+pub struct Event<'a> {
+    /// Type of the message
+    pub kind: MessageType,
+    /// Transport protocol
+    pub protocol: SocketProtocol,
+    /// Address of the side sending the query, i.e. the client
+    pub query_address: Option<IpAddr>,
+    /// Port of the side sending the query
+    pub query_port: Option<u16>,
+    /// Address of the side answering the query, e.g. the upstream server
+    pub response_address: Option<IpAddr>,
+    /// Port of the side answering the query
+    pub response_port: Option<u16>,
+    /// The DNS message in wire format
+    pub message: &'a [u8],
+}
+
+impl Event<'_> {
+    // Encode as a `Dnstap` protobuf message, see https://github.com/dnstap/dnstap.pb/blob/master/dnstap.proto
+    fn encode(&self, identity: &[u8], version: &[u8]) -> Vec<u8> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut msg = Vec::new();
+        put_varint_field(&mut msg, 1, self.kind as u64);
+        if let Some(addr) = self.query_address.or(self.response_address) {
+            // INET = 1, INET6 = 2
+            put_varint_field(&mut msg, 2, if addr.is_ipv4() { 1 } else { 2 });
+        }
+        put_varint_field(&mut msg, 3, self.protocol as u64);
+        if let Some(addr) = self.query_address {
+            put_bytes_field(&mut msg, 4, &ip_octets(addr));
+        }
+        if let Some(addr) = self.response_address {
+            put_bytes_field(&mut msg, 5, &ip_octets(addr));
+        }
+        if let Some(port) = self.query_port {
+            put_varint_field(&mut msg, 6, port.into());
+        }
+        if let Some(port) = self.response_port {
+            put_varint_field(&mut msg, 7, port.into());
+        }
+        // Time and message fields are numbered differently for queries and responses.
+        let (sec, nsec, message) = if self.kind.is_query() {
+            (8, 9, 10)
+        } else {
+            (12, 13, 14)
+        };
+        put_varint_field(&mut msg, sec, now.as_secs());
+        put_fixed32_field(&mut msg, nsec, now.subsec_nanos());
+        put_bytes_field(&mut msg, message, self.message);
+
+        let mut frame = Vec::new();
+        if !identity.is_empty() {
+            put_bytes_field(&mut frame, 1, identity);
+        }
+        if !version.is_empty() {
+            put_bytes_field(&mut frame, 2, version);
+        }
+        put_bytes_field(&mut frame, 14, &msg);
+        // Type MESSAGE
+        put_varint_field(&mut frame, 15, 1);
+        frame
+    }
+}
+
+fn ip_octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+// Wire types: 0 for varint, 2 for length-delimited, and 5 for 32-bit.
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, v: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, v);
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, v: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, v.len() as u64);
+    buf.extend_from_slice(v);
+}
+
+fn put_fixed32_field(buf: &mut Vec<u8>, field: u64, v: u32) {
+    put_varint(buf, field << 3 | 5);
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+/// Start writing dnstap frames to the output in the background. `identity` and `version` are put into every frame if given.
+/// A file output that cannot be opened fails immediately, while a socket that cannot be connected is retried later.
+pub async fn init(output: Output, identity: Option<String>, version: Option<String>) -> Result<()> {
+    if TAP.get().is_some() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "dnstap output is already initialized",
+        ));
+    }
+
+    let writer = match open(&output).await {
+        Ok(w) => Some(w),
+        Err(e) => match &output {
+            Output::File(_) => return Err(e),
+            #[cfg(unix)]
+            Output::Unix(_) => {
+                warn!(
+                    "failed to connect to the dnstap socket, retrying later: {}",
+                    e
+                );
+                None
+            }
+        },
+    };
+
+    let (tx, rx) = mpsc::channel(BUFFER_SIZE);
+    TAP.set(Tap {
+        tx,
+        identity: identity.map(String::into_bytes).unwrap_or_default(),
+        version: version.map(String::into_bytes).unwrap_or_default(),
+    })
+    .map_err(|_| {
+        Error::new(
+            ErrorKind::AlreadyExists,
+            "dnstap output is already initialized",
+        )
+    })?;
+    tokio::spawn(run(output, writer, rx));
+    Ok(())
+}
+
+/// Emit a message if the dnstap output is enabled.
+pub fn emit(event: Event<'_>) {
+    if let Some(tap) = TAP.get() {
+        if tap
+            .tx
+            .try_send(event.encode(&tap.identity, &tap.version))
+            .is_err()
+        {
+            debug!("dnstap output is falling behind, discarding frame");
+        }
+    }
+}
+
+type Writer = BufWriter<Pin<Box<dyn AsyncWrite + Send>>>;
+
+async fn open(output: &Output) -> Result<Writer> {
+    Ok(match output {
+        Output::File(path) => {
+            let mut file = File::create(path).await?;
+            fstrm::start(&mut file).await?;
+            BufWriter::new(Box::pin(file))
+        }
+        #[cfg(unix)]
+        Output::Unix(path) => {
+            let mut stream = UnixStream::connect(path).await?;
+            fstrm::handshake(&mut stream).await?;
+            BufWriter::new(Box::pin(stream))
+        }
+    })
+}
+
+async fn run(output: Output, mut writer: Option<Writer>, mut rx: mpsc::Receiver<Vec<u8>>) {
+    let mut last_attempt = Instant::now();
+    while let Some(frame) = rx.recv().await {
+        if writer.is_none() && last_attempt.elapsed() >= RECONNECT_INTERVAL {
+            last_attempt = Instant::now();
+            match open(&output).await {
+                Ok(w) => writer = Some(w),
+                Err(e) => warn!("failed to reopen the dnstap output: {}", e),
+            }
+        }
+
+        // Frames are dropped while the output is unavailable.
+        if let Some(w) = &mut writer {
+            let mut res = fstrm::write_data(w, &frame).await;
+            // Write out whatever is available, and flush only when we are idle.
+            while res.is_ok() {
+                match rx.try_recv() {
+                    Ok(frame) => res = fstrm::write_data(w, &frame).await,
+                    Err(_) => break,
+                }
+            }
+            if res.is_ok() {
+                res = w.flush().await;
+            }
+            if let Err(e) = res {
+                warn!("failed to write dnstap frames: {}", e);
+                writer = None;
+            }
+        }
+    }
+}
@@ -26,9 +26,12 @@
 // Documentation
 //! This is the core library for dcompass. It implements configuration parsing scheme, DNS query routing rules, and upstream managements.
 pub(crate) mod cache;
+pub mod dnstap;
+pub mod metrics;
 #[doc(hidden)]
 pub mod mock;
 mod router;
+pub mod trace;
 
 #[cfg(all(feature = "doh-native-tls", feature = "doh-rustls"))]
 compile_error!("You should only choose one TLS backend for DNS over HTTPS implementation");
@@ -56,7 +59,7 @@ pub mod errors {
 // All the major components
 pub use self::router::{
     script::{native::NativeScript, utils, QueryContext, ScriptBackend, ScriptBuilder},
-    upstreams::{CacheMode, Upstream, Upstreams},
+    upstreams::{CacheMode, HealthState, Upstream, Upstreams},
     Router,
 };
 
@@ -0,0 +1,142 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Prometheus metrics collected by droute, registered in the default registry.
+
+use crate::{Label, Upstreams};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+pub(crate) static QUERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "dcompass_queries_total",
+        "Number of queries answered, by response code and query type",
+        &["rcode", "qtype"]
+    )
+    .unwrap()
+});
+
+// Buckets from 1ms to about 8s, which covers everything below the maximum timeout we usually see.
+pub(crate) static UPSTREAM_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "dcompass_upstream_latency_seconds",
+        "Time taken to resolve a query by each upstream, including the responses served from the cache",
+        &["upstream"],
+        exponential_buckets(0.001, 2.0, 14).unwrap()
+    )
+    .unwrap()
+});
+
+pub(crate) static UPSTREAM_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "dcompass_upstream_errors_total",
+        "Number of errors encountered by each upstream, by error kind",
+        &["upstream", "kind"]
+    )
+    .unwrap()
+});
+
+pub(crate) static CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "dcompass_cache_lookups_total",
+        "Number of response cache lookups, by result (hit, expired, or miss)",
+        &["result"]
+    )
+    .unwrap()
+});
+
+static POOL_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "dcompass_upstream_pool_connections",
+        "Number of connections in the pool of each upstream, by state (size or available)",
+        &["upstream", "state"]
+    )
+    .unwrap()
+});
+
+// Connection pool sizes are sampled on scrape, rather than on every query.
+fn update_pool_connections(upstreams: &Upstreams) {
+    for (tag, status) in upstreams.pool_status() {
+        POOL_CONNECTIONS
+            .with_label_values(&[tag.as_str(), "size"])
+            .set(status.size as i64);
+        POOL_CONNECTIONS
+            .with_label_values(&[tag.as_str(), "available"])
+            .set(status.available as i64);
+    }
+}
+
+/// Encode all the metrics in the Prometheus text exposition format. Connection pool sizes are sampled from `upstreams` given, if any.
+pub fn encode(upstreams: Option<&Upstreams>) -> prometheus::Result<String> {
+    // Tags removed on reload should not linger around.
+    POOL_CONNECTIONS.reset();
+    if let Some(upstreams) = upstreams {
+        update_pool_connections(upstreams);
+    }
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    // Text format is always valid UTF-8
+    Ok(String::from_utf8(buf).unwrap())
+}
+
+/// The content type of the encoded metrics.
+pub fn content_type() -> String {
+    TextEncoder::new().format_type().to_string()
+}
+
+pub(crate) fn record_upstream_error(tag: &Label, kind: &str) {
+    UPSTREAM_ERRORS
+        .with_label_values(&[tag.as_str(), kind])
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode;
+    use crate::{
+        builders::{UdpBuilder, UpstreamBuilder, UpstreamsBuilder},
+        AsyncTryInto, Upstreams,
+    };
+
+    #[tokio::test]
+    async fn encode_pool_connections() {
+        let upstreams: Upstreams = UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream(
+                "udp",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    addr: "127.0.0.1:53533".parse().unwrap(),
+                    max_pool_size: 32,
+                    timeout: 1,
+                    ratelimit: None,
+                    edns_size: 1232,
+                    buffer_size: 4096,
+                }),
+            )
+            .async_try_into()
+            .await
+            .unwrap();
+
+        let text = encode(Some(&upstreams)).unwrap();
+        assert!(
+            text.contains(r#"dcompass_upstream_pool_connections{state="size",upstream="udp"} 0"#)
+        );
+        assert!(text
+            .contains(r#"dcompass_upstream_pool_connections{state="available",upstream="udp"} 0"#));
+    }
+}
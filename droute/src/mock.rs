@@ -14,9 +14,16 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 //! This module is NOT intended to be used by regular users. It is used for mocking purpose only.
-use bytes::BytesMut;
-use domain::base::Message;
-use std::net::SocketAddr;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{iana::Rcode, rdata::UnknownRecordData, Dname, Message, MessageBuilder, Rtype},
+    rdata::Soa,
+};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 use tokio::net::UdpSocket;
 
 /// Mock echo server
@@ -63,3 +70,168 @@ impl Server {
         }
     }
 }
+
+/// Mock authoritative server of a zone. NS records below the apex are delegations, which are answered with referrals along with the glue.
+pub struct Authority {
+    apex: Dname<Bytes>,
+    records: Vec<(Dname<Bytes>, Rtype, Bytes)>,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Authority {
+    /// Create a server for the zone
+    pub fn new(apex: &str) -> Self {
+        Self {
+            apex: Dname::from_str(apex).unwrap(),
+            records: Vec::new(),
+            log: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Add a record of type A, AAAA, NS, or CNAME
+    pub fn add(mut self, name: &str, rtype: Rtype, data: &str) -> Self {
+        let data = match rtype {
+            Rtype::A => Bytes::copy_from_slice(&data.parse::<Ipv4Addr>().unwrap().octets()),
+            Rtype::Aaaa => Bytes::copy_from_slice(&data.parse::<Ipv6Addr>().unwrap().octets()),
+            Rtype::Ns | Rtype::Cname => {
+                Bytes::copy_from_slice(Dname::<Bytes>::from_str(data).unwrap().as_slice())
+            }
+            _ => panic!("unsupported record type {}", rtype),
+        };
+        self.records
+            .push((Dname::from_str(name).unwrap(), rtype, data));
+        self
+    }
+
+    /// Names queried, in the order they are received
+    pub fn log(&self) -> Arc<Mutex<Vec<String>>> {
+        self.log.clone()
+    }
+
+    fn records(
+        &self,
+        name: &Dname<Bytes>,
+        rtype: Rtype,
+    ) -> impl Iterator<Item = (&Dname<Bytes>, Rtype, &Bytes)> {
+        let name = name.clone();
+        self.records
+            .iter()
+            .filter(move |(owner, t, _)| *owner == name && (*t == rtype || rtype == Rtype::Any))
+            .map(|(owner, t, data)| (owner, *t, data))
+    }
+
+    fn answer(&self, query: &Message<Bytes>) -> Message<Bytes> {
+        let builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        let question = query.first_question().unwrap();
+        let qname: Dname<Bytes> = Dname::from_str(&question.qname().to_string()).unwrap();
+        let qtype = question.qtype();
+        self.log.lock().unwrap().push(qname.to_string());
+
+        if !qname.ends_with(&self.apex) {
+            return builder
+                .start_answer(query, Rcode::Refused)
+                .unwrap()
+                .into_message();
+        }
+
+        // Refer to the servers of the child zone enclosing the name, if there is any.
+        let cut = self
+            .records
+            .iter()
+            .filter(|(owner, t, _)| {
+                *t == Rtype::Ns && *owner != self.apex && qname.ends_with(owner)
+            })
+            .map(|(owner, _, _)| owner)
+            .min_by_key(|owner| owner.label_count());
+        if let Some(cut) = cut {
+            let mut builder = builder
+                .start_answer(query, Rcode::NoError)
+                .unwrap()
+                .authority();
+            let mut servers = Vec::new();
+            for (owner, rtype, data) in self.records(cut, Rtype::Ns) {
+                builder
+                    .push((
+                        owner,
+                        3600,
+                        UnknownRecordData::from_octets(rtype, data.clone()),
+                    ))
+                    .unwrap();
+                servers.push(Dname::<Bytes>::from_octets(data.clone()).unwrap());
+            }
+            let mut builder = builder.additional();
+            for server in &servers {
+                for rtype in [Rtype::A, Rtype::Aaaa] {
+                    for (owner, rtype, data) in self.records(server, rtype) {
+                        builder
+                            .push((
+                                owner,
+                                3600,
+                                UnknownRecordData::from_octets(rtype, data.clone()),
+                            ))
+                            .unwrap();
+                    }
+                }
+            }
+            return builder.into_message();
+        }
+
+        let mut answers: Vec<_> = self.records(&qname, qtype).collect();
+        if answers.is_empty() && qtype != Rtype::Cname {
+            answers = self.records(&qname, Rtype::Cname).collect();
+        }
+        let exists = self
+            .records
+            .iter()
+            .any(|(owner, _, _)| owner.ends_with(&qname));
+        let rcode = if exists {
+            Rcode::NoError
+        } else {
+            Rcode::NXDomain
+        };
+
+        let mut builder = builder.start_answer(query, rcode).unwrap();
+        builder.header_mut().set_aa(true);
+        for (owner, rtype, data) in &answers {
+            builder
+                .push((
+                    *owner,
+                    3600,
+                    UnknownRecordData::from_octets(*rtype, (*data).clone()),
+                ))
+                .unwrap();
+        }
+        let mut builder = builder.authority();
+        if answers.is_empty() {
+            builder
+                .push((
+                    &self.apex,
+                    60,
+                    Soa::new(
+                        Dname::<Bytes>::from_str("ns.invalid").unwrap(),
+                        Dname::<Bytes>::from_str("hostmaster.invalid").unwrap(),
+                        1.into(),
+                        3600,
+                        600,
+                        86400,
+                        60,
+                    ),
+                ))
+                .unwrap();
+        }
+        builder.into_message()
+    }
+
+    /// Serve the zone on the socket
+    pub async fn run(self, socket: UdpSocket) -> Result<(), std::io::Error> {
+        let mut buf = vec![0; 4096];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await?;
+            let query = match Message::from_octets(Bytes::copy_from_slice(&buf[..len])) {
+                Ok(query) if query.first_question().is_some() => query,
+                _ => continue,
+            };
+            socket.send_to(self.answer(&query).as_slice(), peer).await?;
+        }
+    }
+}
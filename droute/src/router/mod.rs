@@ -25,7 +25,10 @@ use self::{
     upstreams::{error::UpstreamError, Upstreams},
 };
 use crate::{
-    errors::ScriptError, AsyncTryInto, Label, ScriptBackend, ScriptBuilder, Validatable, MAX_LEN,
+    errors::ScriptError,
+    metrics::QUERIES,
+    trace::{traced, QueryTrace},
+    AsyncTryInto, Label, ScriptBackend, ScriptBuilder, Validatable, MAX_LEN,
 };
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
@@ -35,6 +38,8 @@ use log::warn;
 /// Router implementation.
 pub struct Router<T: ScriptBackend> {
     script: T,
+    // Upstreams handed to the script, kept for carrying the cache over on reload. Absent if the router is created from raw.
+    upstreams: Option<Upstreams>,
 }
 
 impl<T: ScriptBackend> Validatable for Router<T> {
@@ -48,16 +53,50 @@ impl<T: ScriptBackend> Validatable for Router<T> {
 impl<T: ScriptBackend> Router<T> {
     /// Create a new `Router` from raw
     pub fn new(script: T) -> Result<Self, ScriptError> {
-        let router = Self { script };
+        let router = Self {
+            script,
+            upstreams: None,
+        };
         router.validate(None)?;
         Ok(router)
     }
 
+    /// The upstreams used by the router, if it is built by `RouterBuilder`.
+    pub fn upstreams(&self) -> Option<&Upstreams> {
+        self.upstreams.as_ref()
+    }
+
     /// Resolve the DNS query with routing rules defined.
     pub async fn resolve(
         &self,
         msg: Message<Bytes>,
         qctx: Option<QueryContext>,
+    ) -> Result<Message<Bytes>, ScriptError> {
+        Ok(self.resolve_traced(msg, qctx).await?.0)
+    }
+
+    /// Resolve the DNS query with routing rules defined, and return the trace of the resolution along with the response.
+    pub async fn resolve_traced(
+        &self,
+        msg: Message<Bytes>,
+        qctx: Option<QueryContext>,
+    ) -> Result<(Message<Bytes>, QueryTrace), ScriptError> {
+        let qtype = msg
+            .first_question()
+            .map(|q| q.qtype().to_string())
+            .unwrap_or_default();
+        let (resp, trace) = traced(self.resolve_inner(msg, qctx)).await;
+        let resp = resp?;
+        QUERIES
+            .with_label_values(&[&resp.header().rcode().to_string(), &qtype])
+            .inc();
+        Ok((resp, trace))
+    }
+
+    async fn resolve_inner(
+        &self,
+        msg: Message<Bytes>,
+        qctx: Option<QueryContext>,
     ) -> Result<Message<Bytes>, ScriptError> {
         // We have to ensure the number of queries is larger than 0 as it is a gurantee for actions/matchers.
         // Not using `query_count()` because it is manually set, and may not be correct.
@@ -94,6 +133,7 @@ where
 {
     script: S,
     upstreams: U,
+    inherit: Option<Upstreams>,
     _phantom: PhantomData<T>,
 }
 
@@ -108,9 +148,16 @@ where
         Self {
             script,
             upstreams,
+            inherit: None,
             _phantom: PhantomData::default(),
         }
     }
+
+    /// Carry the response cache over from a running router, e.g. when reloading the configuration.
+    pub fn inherit_cache(mut self, router: &Router<T>) -> Self {
+        self.inherit = router.upstreams().cloned();
+        self
+    }
 }
 
 #[async_trait(?Send)]
@@ -124,7 +171,13 @@ where
 
     /// Build a new `Router` from configuration and check the validity. `data` is the content of the configuration file.
     async fn async_try_into(self) -> Result<Router<T>, ScriptError> {
-        let upstreams = self.upstreams.async_try_into().await?;
-        Router::new(self.script.build(upstreams).await?)
+        let mut upstreams = self.upstreams.async_try_into().await?;
+        if let Some(old) = &self.inherit {
+            upstreams.inherit_cache(old);
+        }
+        // Upstreams are cheap to clone, with the cache shared.
+        let mut router = Router::new(self.script.build(upstreams.clone()).await?)?;
+        router.upstreams = Some(upstreams);
+        Ok(router)
     }
 }
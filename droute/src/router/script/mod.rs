@@ -149,10 +149,26 @@ pub enum ScriptError {
 
 /// Query Context
 #[derive(Clone)]
+#[non_exhaustive]
 #[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
 pub struct QueryContext {
     /// Query sender's IP address
     pub ip: IpAddr,
+    /// The server name indicated by the client, if the query came over a TLS connection.
+    pub sni: Option<String>,
+}
+
+impl QueryContext {
+    /// Create the context of a query sent from `ip`.
+    pub fn new(ip: IpAddr) -> Self {
+        Self { ip, sni: None }
+    }
+
+    /// Set the server name indicated by the client.
+    pub fn with_sni(mut self, sni: Option<String>) -> Self {
+        self.sni = sni;
+        self
+    }
 }
 
 /// A script backend routes every message with query context and the query itself.
@@ -14,7 +14,10 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use super::types::*;
-use crate::{errors::ScriptError, CacheMode, QueryContext, Upstreams};
+use crate::{
+    errors::{ScriptError, UpstreamError},
+    CacheMode, HealthState, QueryContext, Upstreams,
+};
 use once_cell::sync::Lazy;
 use rune::{runtime::Protocol, Module};
 
@@ -45,12 +48,27 @@ pub static BASIS_MODULE: Lazy<Module> = Lazy::new(|| {
             .into())
     }
 
+    fn health(upstreams: &Upstreams, tag: &str) -> Result<HealthState, ScriptError> {
+        upstreams
+            .health(&tag.into())
+            .ok_or_else(|| UpstreamError::MissingTag(tag.into()).into())
+    }
+
     m.ty::<Upstreams>().unwrap();
     m.async_inst_fn("send", send).unwrap();
     m.async_inst_fn("send_default", send_default).unwrap();
+    m.inst_fn("health", health).unwrap();
 
     m.ty::<CacheMode>().unwrap();
 
+    m.ty::<HealthState>().unwrap();
+    m.inst_fn("to_str", |this: &HealthState| this.to_string())
+        .unwrap();
+    m.inst_fn(Protocol::EQ, |this: &HealthState, other: &str| {
+        this.to_string() == other
+    })
+    .unwrap();
+
     m.ty::<QueryContext>().unwrap();
     m.field_fn(Protocol::GET, "ip", |qctx: &QueryContext| -> IpAddr {
         qctx.ip.into()
@@ -62,6 +80,12 @@ pub static BASIS_MODULE: Lazy<Module> = Lazy::new(|| {
         |qctx: &mut QueryContext, ip: IpAddr| qctx.ip = ip.into(),
     )
     .unwrap();
+    m.field_fn(
+        Protocol::GET,
+        "sni",
+        |qctx: &QueryContext| -> Option<String> { qctx.sni.clone() },
+    )
+    .unwrap();
 
     m
 });
@@ -35,6 +35,12 @@ impl IpCidr {
         Ok(())
     }
 
+    /// Add a single IP CIDR (e.g. `192.168.0.0/16`), or a single IP address.
+    pub fn add_cidr(&mut self, cidr: &str) -> Result<()> {
+        self.matcher.push(Cidr::from_str(cidr)?);
+        Ok(())
+    }
+
     /// Check if IP CIDR set contains the given IP address.
     pub fn contains(&self, ip: IpAddr) -> bool {
         self.matcher.contains(ip)
@@ -19,11 +19,13 @@ mod blackhole;
 mod domain;
 mod geoip;
 mod ipcidr;
+mod truncate;
 
 pub use self::domain::Domain;
 pub use blackhole::blackhole;
 pub use geoip::GeoIp;
 pub use ipcidr::IpCidr;
+pub use truncate::{set_payload_size, truncate};
 
 use ::domain::base::{name::FromStrError, octets::ParseError};
 use maxminddb::MaxMindDBError;
@@ -0,0 +1,157 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Result;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{iana::Class, Message, MessageBuilder, ParsedDname, Record, Rtype, StaticCompressor},
+    rdata::AllRecordData,
+};
+
+type AnyRecord<'a> = Record<ParsedDname<&'a Bytes>, AllRecordData<Bytes, ParsedDname<&'a Bytes>>>;
+
+// Records of a message, which is rebuilt from them with some of them left out.
+struct Sections<'a> {
+    msg: &'a Message<Bytes>,
+    // Records in the answer and authority sections, and how many of them are answers
+    records: Vec<AnyRecord<'a>>,
+    answers: usize,
+    // Records in the additional section other than the OPT record
+    additional: Vec<AnyRecord<'a>>,
+    opt: Option<AnyRecord<'a>>,
+}
+
+impl<'a> Sections<'a> {
+    fn new(msg: &'a Message<Bytes>) -> Result<Self> {
+        let mut records = Vec::new();
+        for item in msg.answer()? {
+            if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+                records.push(record);
+            }
+        }
+        let answers = records.len();
+        for item in msg.authority()? {
+            if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+                records.push(record);
+            }
+        }
+
+        // Only the first OPT record is kept, as there can only be one as per RFC 6891.
+        let mut additional = Vec::new();
+        let mut opt = None;
+        for item in msg.additional()? {
+            if let Some(record) = item?.into_record::<AllRecordData<_, _>>()? {
+                if record.rtype() != Rtype::Opt {
+                    additional.push(record);
+                } else if opt.is_none() {
+                    opt = Some(record);
+                }
+            }
+        }
+
+        Ok(Self {
+            msg,
+            records,
+            answers,
+            additional,
+            opt,
+        })
+    }
+
+    // Build the message with only the first `kept` records of the answer and authority sections, or all the records if it is `None`.
+    // TC is set if any answer or authority record is left out. The OPT record is kept only if `opt` is set.
+    fn build(&self, kept: Option<usize>, opt: bool) -> Result<Message<Bytes>> {
+        let all = kept.is_none();
+        let kept = kept.unwrap_or(self.records.len());
+        let mut builder = MessageBuilder::from_target(StaticCompressor::new(
+            BytesMut::with_capacity(self.msg.as_slice().len()),
+        ))?;
+        *builder.header_mut() = self.msg.header();
+        if kept < self.records.len() {
+            builder.header_mut().set_tc(true);
+        }
+
+        let mut builder = builder.question();
+        for item in self.msg.question() {
+            builder.push(item?)?;
+        }
+
+        let mut builder = builder.answer();
+        for record in self.records.iter().take(kept.min(self.answers)) {
+            builder.push(record.clone())?;
+        }
+        let mut builder = builder.authority();
+        for record in self.records.iter().take(kept).skip(self.answers) {
+            builder.push(record.clone())?;
+        }
+
+        let mut builder = builder.additional();
+        if all {
+            for record in &self.additional {
+                builder.push(record.clone())?;
+            }
+        }
+        if let Some(record) = self.opt.as_ref().filter(|_| opt) {
+            builder.push(record.clone())?;
+        }
+
+        Ok(Message::from_octets(
+            builder.finish().into_target().freeze(),
+        )?)
+    }
+}
+
+/// Rewrite the UDP payload size advertised in the EDNS OPT record of the message. Messages without EDNS are left as is.
+pub fn set_payload_size(msg: &Message<Bytes>, size: u16) -> Result<Message<Bytes>> {
+    if msg.opt().is_none() {
+        return Ok(msg.clone());
+    }
+
+    let mut sections = Sections::new(msg)?;
+    // The payload size is carried in the CLASS field of the OPT record.
+    sections.opt = sections.opt.map(|opt| {
+        Record::new(
+            opt.owner().clone(),
+            Class::from_int(size),
+            opt.ttl(),
+            opt.data().clone(),
+        )
+    });
+    sections.build(None, true)
+}
+
+/// Truncate the message to at most `limit` bytes at a record boundary.
+/// The OPT record is kept if there is room, and TC is set if any record in the answer or authority section is left out, as per RFC 2181, 9.
+/// Questions are always kept, so the message may still be over the limit if they alone don't fit.
+pub fn truncate(msg: &Message<Bytes>, limit: usize) -> Result<Message<Bytes>> {
+    if msg.as_slice().len() <= limit {
+        return Ok(msg.clone());
+    }
+
+    let sections = Sections::new(msg)?;
+    let opt = sections.build(Some(0), true)?.as_slice().len() <= limit;
+
+    // More records never make the message shorter, so we look for the most records to keep by bisection.
+    let (mut low, mut high) = (0, sections.records.len());
+    while low < high {
+        let mid = (low + high + 1) / 2;
+        if sections.build(Some(mid), opt)?.as_slice().len() <= limit {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    sections.build(Some(low), opt)
+}
@@ -35,6 +35,8 @@ pub struct UpstreamsBuilder<U: AsyncTryInto<Upstream, Error = QHandleError>> {
     upstreams: HashMap<Label, U>,
     #[serde(default = "default_cache_size")]
     cache_size: NonZeroUsize,
+    #[serde(default)]
+    health_check: Option<HealthCheck>,
 }
 
 impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
@@ -43,6 +45,7 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         Self {
             upstreams: upstreams.into_iter().map(|(k, v)| (k.into(), v)).collect(),
             cache_size,
+            health_check: None,
         }
     }
 
@@ -51,6 +54,7 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         std::num::NonZeroUsize::new(cache_size).map(|c| Self {
             upstreams: HashMap::new(),
             cache_size: c,
+            health_check: None,
         })
     }
 
@@ -59,6 +63,12 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> UpstreamsBuilder<U> {
         self.upstreams.insert(tag.into(), upstream);
         self
     }
+
+    /// Enable the health checks and the circuit breakers for the upstreams
+    pub fn health_check(mut self, settings: HealthCheck) -> Self {
+        self.health_check = Some(settings);
+        self
+    }
 }
 
 #[async_trait(?Send)]
@@ -73,6 +83,10 @@ impl<U: AsyncTryInto<Upstream, Error = QHandleError>> AsyncTryInto<Upstreams>
         for (tag, u) in self.upstreams {
             v.insert(tag, u.async_try_into().await?);
         }
-        Upstreams::new(v, self.cache_size)
+        let mut upstreams = Upstreams::new(v, self.cache_size)?;
+        if let Some(settings) = &self.health_check {
+            upstreams.health_check(settings);
+        }
+        Ok(upstreams)
     }
 }
@@ -26,7 +26,7 @@ use self::error::{Result, UpstreamError};
 use crate::{cache::RespCache, Label, Validatable, ValidateCell};
 use bytes::{Bytes, BytesMut};
 use domain::base::Message;
-use futures::future::{select_ok, BoxFuture, FutureExt};
+use futures::future::{BoxFuture, FutureExt};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, num::NonZeroUsize, str::FromStr};
 pub use upstream::*;
@@ -103,6 +103,42 @@ impl Upstreams {
         Ok(u)
     }
 
+    /// Take over the response cache of `old`, so that the records cached are kept across reloads. The capacity of our own cache is retained.
+    pub fn inherit_cache(&mut self, old: &Upstreams) {
+        // Capacity is always non-zero as it was created from a NonZeroUsize.
+        let size = NonZeroUsize::new(self.cache.capacity()).unwrap();
+        self.cache = old.cache.clone();
+        self.cache.resize(size);
+    }
+
+    /// Put every upstream other than hybrid ones behind a circuit breaker, which opens after consecutive failures and is probed periodically.
+    pub fn health_check(&mut self, settings: &builder::HealthCheck) {
+        for (tag, u) in self.upstreams.iter_mut() {
+            u.guard(tag, settings);
+        }
+    }
+
+    /// Return the health state of the upstream with the tag given. Upstreams without health checks are always up, and a hybrid upstream is as healthy as its healthiest member.
+    pub fn health(&self, tag: &Label) -> Option<HealthState> {
+        let u = self.upstreams.get(tag)?;
+        Some(match u.try_hybrid() {
+            Some(v) => v
+                .tags()
+                .iter()
+                .filter_map(|t| self.health(t))
+                .min()
+                .unwrap_or(HealthState::Up),
+            None => u.health().unwrap_or(HealthState::Up),
+        })
+    }
+
+    // Status of the connection pools, by the tags of the upstreams owning them.
+    pub(crate) fn pool_status(&self) -> impl Iterator<Item = (&Label, deadpool::Status)> {
+        self.upstreams
+            .iter()
+            .filter_map(|(tag, u)| u.pool_status().map(|s| (tag, s)))
+    }
+
     /// Return the tags of all the upstreams.
     pub fn tags(&self) -> Vec<Label> {
         self.upstreams.keys().cloned().collect()
@@ -127,12 +163,12 @@ impl Upstreams {
             bucket.get_mut(tag).unwrap().0.add(1);
             // Check if it is empty.
             if let Some(v) = u {
-                if v.is_empty() {
+                if v.tags().is_empty() {
                     return Err(UpstreamError::EmptyHybrid(tag.clone()));
                 }
 
                 // Check if it is recursively defined.
-                for t in v {
+                for t in v.tags() {
                     Self::traverse(bucket, t)?
                 }
             }
@@ -159,9 +195,11 @@ impl Upstreams {
                 .ok_or_else(|| UpstreamError::MissingTag(tag.clone()))?;
             let resp = if let Some(v) = u.try_hybrid() {
                 // Hybrid will never call `u.send_internal()`
-                let v = v.iter().map(|t| self.send(t, cache_mode, msg));
-                let (r, _) = select_ok(v).await?;
-                r
+                v.send(
+                    |t| self.health(t) != Some(HealthState::Down),
+                    |t| self.send(t, cache_mode, msg),
+                )
+                .await?
             } else {
                 u.resolve(tag, &self.cache, cache_mode, msg).await?
             };
@@ -178,12 +216,60 @@ impl Upstreams {
 
 #[cfg(test)]
 mod tests {
-    use crate::AsyncTryInto;
+    use crate::{cache::RecordStatus, AsyncTryInto, Label};
 
     use super::{
         builder::{HybridBuilder, UdpBuilder, UpstreamBuilder, UpstreamsBuilder},
-        UpstreamError,
+        upstream::QHandleError,
+        UpstreamError, Upstreams,
     };
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, Message, MessageBuilder, Rtype};
+    use std::str::FromStr;
+
+    async fn create_upstreams(cache_size: usize) -> Upstreams {
+        UpstreamsBuilder::new(cache_size)
+            .unwrap()
+            .add_upstream(
+                "udp",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    addr: "127.0.0.1:53533".parse().unwrap(),
+                    max_pool_size: 32,
+                    timeout: 1,
+                    ratelimit: None,
+                    edns_size: 1232,
+                    buffer_size: 4096,
+                }),
+            )
+            .async_try_into()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn inherit_cache() {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        let msg = Message::from_octets(builder.into_message().into_octets().freeze()).unwrap();
+        let tag = Label::from("udp");
+
+        let old = create_upstreams(1).await;
+        old.cache.put(tag.clone(), &msg, msg.clone());
+
+        let mut new = create_upstreams(2).await;
+        assert!(new.cache.get(&tag, &msg).is_none());
+        new.inherit_cache(&old);
+        assert!(matches!(
+            new.cache.get(&tag, &msg),
+            Some(RecordStatus::Alive(_))
+        ));
+        // The capacity configured for the new one is kept.
+        assert_eq!(new.cache.capacity(), 2);
+    }
 
     #[tokio::test]
     async fn should_not_fail_recursion() {
@@ -198,6 +284,8 @@ mod tests {
                     max_pool_size: 32,
                     timeout: 1,
                     ratelimit: None,
+                    edns_size: 1232,
+                    buffer_size: 4096,
                 }),
             )
             .add_upstream(
@@ -225,6 +313,8 @@ mod tests {
                     max_pool_size: 256,
                     timeout: 1,
                     ratelimit: None,
+                    edns_size: 1232,
+                    buffer_size: 4096,
                 }),
             )
             .add_upstream(
@@ -244,4 +334,29 @@ mod tests {
             e => panic!("Not the right error type: {}", e),
         }
     }
+
+    #[tokio::test]
+    async fn fail_small_payload_size() {
+        match UpstreamsBuilder::new(1)
+            .unwrap()
+            .add_upstream(
+                "udp",
+                UpstreamBuilder::Udp(UdpBuilder {
+                    addr: "127.0.0.1:53533".parse().unwrap(),
+                    max_pool_size: 256,
+                    timeout: 1,
+                    ratelimit: None,
+                    edns_size: 1232,
+                    buffer_size: 511,
+                }),
+            )
+            .async_try_into()
+            .await
+            .err()
+            .unwrap()
+        {
+            UpstreamError::QHandleError(QHandleError::InvalidPayloadSize(511)) => (),
+            e => panic!("Not the right error type: {}", e),
+        }
+    }
 }
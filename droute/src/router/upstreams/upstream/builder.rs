@@ -13,20 +13,48 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+pub use super::health::HealthCheck;
+pub use super::hybrid::HybridStrategy;
+#[cfg(feature = "dnscrypt")]
+use super::qhandle::dnscrypt::DnsCrypt;
+pub use super::qhandle::hosts::{StaticRecord, StaticType};
+#[cfg(feature = "doh3-rustls")]
+pub use super::qhandle::http3::Http3Mode;
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
-use super::qhandle::https::Https;
+pub use super::qhandle::https::DohMethod;
+#[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+use super::qhandle::https::{Https, HttpsSettings};
+#[cfg(feature = "doq-rustls")]
+use super::qhandle::quic::Quic;
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
 use super::qhandle::tls::Tls;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-rustls",
+    feature = "dot-native-tls"
+))]
+pub use super::qhandle::tls_settings::TlsSettings;
 use super::{
-    qhandle::{udp::Udp, ConnPool, Result},
-    QHandleError, Upstream,
+    qhandle::{
+        hosts::{Hosts, Table},
+        recursive::Recursive,
+        tcp::Tcp,
+        udp::Udp,
+        zone::{Zone, Zones},
+        ConnPool, Result,
+    },
+    Hybrid, QHandleError, Upstream,
 };
 use crate::{AsyncTryInto, Label};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 use std::net::IpAddr;
-use std::{net::SocketAddr, num::NonZeroU32, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap, net::SocketAddr, num::NonZeroU32, path::PathBuf, sync::Arc,
+    time::Duration,
+};
 
 // Default value for timeout
 const fn default_timeout() -> u64 {
@@ -47,8 +75,8 @@ const fn default_udp_max_pool_size() -> usize {
     43
 }
 
-// We do cache TLS connections. However, they expire quite soon.
-// Therefore, pool size is not of problems.
+// Queries are pipelined over a single TLS connection, which is replaced once it expires.
+// Therefore, the pool size only limits the number of queries in flight.
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
 const fn default_tls_max_pool_size() -> usize {
     256
@@ -64,6 +92,31 @@ const fn default_tls_reuse_timeout() -> u64 {
     60000
 }
 
+// Plain TCP connections are shared the same way as TLS ones.
+const fn default_tcp_max_pool_size() -> usize {
+    256
+}
+
+const fn default_tcp_max_reuse() -> usize {
+    200
+}
+
+const fn default_tcp_reuse_timeout() -> u64 {
+    60000
+}
+
+// Queries share a single QUIC connection, so the pool size only limits the number of concurrent streams.
+#[cfg(feature = "doq-rustls")]
+const fn default_quic_max_pool_size() -> usize {
+    256
+}
+
+// Each DNSCrypt handle owns a UDP socket, just like UDP ones.
+#[cfg(feature = "dnscrypt")]
+const fn default_dnscrypt_max_pool_size() -> usize {
+    43
+}
+
 // We don't cache HTTPS connections. That means we wouldn't need any recovery! Indeed, we store clients.
 // On average, HTTPS query roundtrip time is 750ms. That means a bigger connection pool is almost always better.
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
@@ -71,9 +124,58 @@ const fn default_https_max_pool_size() -> usize {
     1024
 }
 
+// Default value for the delay in milliseconds before firing the next member of a hedged hybrid upstream
+const fn default_hedge_delay() -> u64 {
+    100
+}
+
+// Either a bare list of tags, or the tags with the strategy and its options.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HybridRepr {
+    Tags(Vec<Label>),
+    Full {
+        tags: Vec<Label>,
+        #[serde(default)]
+        strategy: HybridStrategy,
+        #[serde(default)]
+        weights: HashMap<Label, u32>,
+        #[serde(default = "default_hedge_delay")]
+        delay: u64,
+    },
+}
+
+impl From<HybridRepr> for HybridBuilder {
+    fn from(repr: HybridRepr) -> Self {
+        match repr {
+            HybridRepr::Tags(tags) => Self {
+                tags,
+                ..Self::new()
+            },
+            HybridRepr::Full {
+                tags,
+                strategy,
+                weights,
+                delay,
+            } => Self {
+                tags,
+                strategy,
+                weights,
+                delay,
+            },
+        }
+    }
+}
+
 /// A builder for hybrid upstream
 #[derive(Serialize, Deserialize, Clone)]
-pub struct HybridBuilder(Vec<Label>);
+#[serde(from = "HybridRepr")]
+pub struct HybridBuilder {
+    tags: Vec<Label>,
+    strategy: HybridStrategy,
+    weights: HashMap<Label, u32>,
+    delay: u64,
+}
 
 impl Default for HybridBuilder {
     fn default() -> Self {
@@ -82,14 +184,37 @@ impl Default for HybridBuilder {
 }
 
 impl HybridBuilder {
-    /// Create an empty hybrid builder
+    /// Create an empty hybrid builder, which races the upstreams
     pub fn new() -> Self {
-        Self(Vec::new())
+        Self {
+            tags: Vec::new(),
+            strategy: HybridStrategy::default(),
+            weights: HashMap::new(),
+            delay: default_hedge_delay(),
+        }
     }
 
     /// Add another upstream to the hybrid upstream about to build
     pub fn add_tag(mut self, tag: impl Into<Label>) -> Self {
-        self.0.push(tag.into());
+        self.tags.push(tag.into());
+        self
+    }
+
+    /// Set the strategy to query the upstreams
+    pub fn strategy(mut self, strategy: HybridStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Set the weight of an upstream for the `Weighted` strategy. Upstreams not set have the weight of 1.
+    pub fn weight(mut self, tag: impl Into<Label>, weight: u32) -> Self {
+        self.weights.insert(tag.into(), weight);
+        self
+    }
+
+    /// Set the delay in milliseconds before firing the next upstream for the `Hedged` strategy
+    pub fn delay(mut self, delay: u64) -> Self {
+        self.delay = delay;
         self
     }
 }
@@ -99,7 +224,12 @@ impl AsyncTryInto<Upstream> for HybridBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
-        Ok(Upstream::Hybrid(self.0))
+        Ok(Upstream::Hybrid(Hybrid::new(
+            self.tags,
+            self.strategy,
+            &self.weights,
+            Duration::from_millis(self.delay),
+        )))
     }
 }
 
@@ -126,6 +256,19 @@ pub struct HttpsBuilder {
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// Trust anchors, client certificate, and SPKI pins
+    #[serde(flatten)]
+    pub tls: TlsSettings,
+    /// HTTP method to send queries with
+    #[serde(default)]
+    pub method: DohMethod,
+    /// Extra HTTP headers sent with every query, e.g. `Authorization`. Their values are redacted in logs.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Whether to send queries over HTTP/3
+    #[cfg(feature = "doh3-rustls")]
+    #[serde(default)]
+    pub http3: Http3Mode,
 }
 
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
@@ -135,7 +278,20 @@ impl AsyncTryInto<Upstream> for HttpsBuilder {
 
     async fn async_try_into(self) -> Result<Upstream> {
         Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Https::new(self.uri, self.addr, self.proxy, self.sni).await?,
+            Https::new(
+                self.uri,
+                self.addr,
+                self.proxy,
+                HttpsSettings {
+                    sni: self.sni,
+                    tls: self.tls,
+                    method: self.method,
+                    headers: self.headers,
+                    #[cfg(feature = "doh3-rustls")]
+                    http3: self.http3,
+                },
+            )
+            .await?,
             self.max_pool_size,
             Duration::from_secs(self.timeout),
             self.ratelimit.into(),
@@ -155,7 +311,7 @@ pub struct TlsBuilder {
     /// Timeout length
     #[serde(default = "default_timeout")]
     pub timeout: u64,
-    /// Max connection pool size
+    /// Max number of queries in flight over the connection
     #[serde(default = "default_tls_max_pool_size")]
     pub max_pool_size: usize,
     /// The time in millisecond to keep the underlying persistent TCP connection open for reuse
@@ -170,6 +326,9 @@ pub struct TlsBuilder {
     /// SNI
     #[serde(default)]
     pub sni: bool,
+    /// Trust anchors, client certificate, and SPKI pins
+    #[serde(flatten)]
+    pub tls: TlsSettings,
 }
 
 #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
@@ -183,6 +342,7 @@ impl AsyncTryInto<Upstream> for TlsBuilder {
                 self.domain,
                 self.addr,
                 self.sni,
+                &self.tls,
                 self.reuse_timeout,
                 self.max_reuse,
             )?,
@@ -193,6 +353,121 @@ impl AsyncTryInto<Upstream> for TlsBuilder {
     }
 }
 
+/// A builder for DNS over QUIC upstream
+#[cfg(feature = "doq-rustls")]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct QuicBuilder {
+    /// The domain of the DoQ server. e.g. `dns.adguard-dns.com`
+    pub domain: String,
+    /// The address of the server. e.g. `94.140.14.14:853` for AdGuard DNS.
+    pub addr: SocketAddr,
+    /// Timeout length
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Max number of queries in flight over the connection
+    #[serde(default = "default_quic_max_pool_size")]
+    pub max_pool_size: usize,
+    /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
+    #[serde(default)]
+    pub ratelimit: Option<NonZeroU32>,
+}
+
+#[cfg(feature = "doq-rustls")]
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for QuicBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Others(Arc::new(ConnPool::new(
+            Quic::new(self.domain, self.addr)?,
+            self.max_pool_size,
+            Duration::from_secs(self.timeout),
+            self.ratelimit.into(),
+        )?)))
+    }
+}
+
+/// A builder for DNSCrypt upstream
+#[cfg(feature = "dnscrypt")]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct DnsCryptBuilder {
+    /// The DNS stamp of the server. e.g. `sdns://AQcAAAAAAAAAB...`
+    pub stamp: String,
+    /// Timeout length
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Max connection pool size
+    #[serde(default = "default_dnscrypt_max_pool_size")]
+    pub max_pool_size: usize,
+    /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
+    #[serde(default)]
+    pub ratelimit: Option<NonZeroU32>,
+}
+
+#[cfg(feature = "dnscrypt")]
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for DnsCryptBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Others(Arc::new(ConnPool::new(
+            DnsCrypt::new(&self.stamp)?,
+            self.max_pool_size,
+            Duration::from_secs(self.timeout),
+            self.ratelimit.into(),
+        )?)))
+    }
+}
+
+/// A builder for plain DNS over TCP upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct TcpBuilder {
+    /// The address of the server. e.g. `1.1.1.1:53`
+    pub addr: SocketAddr,
+    /// Timeout length
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Max number of queries in flight over the connection
+    #[serde(default = "default_tcp_max_pool_size")]
+    pub max_pool_size: usize,
+    /// The time in millisecond to keep the underlying persistent TCP connection open for reuse
+    #[serde(default = "default_tcp_reuse_timeout")]
+    pub reuse_timeout: u64,
+    /// The maximum number of queries allowed to send over a single underlying TCP connection
+    #[serde(default = "default_tcp_max_reuse")]
+    pub max_reuse: usize,
+    /// Maximum number of query per second and the query burst size allowed to upstream using Leaky Bucket algorithm
+    #[serde(default)]
+    pub ratelimit: Option<NonZeroU32>,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for TcpBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Others(Arc::new(ConnPool::new(
+            Tcp::new(self.addr, self.reuse_timeout, self.max_reuse),
+            self.max_pool_size,
+            Duration::from_secs(self.timeout),
+            self.ratelimit.into(),
+        )?)))
+    }
+}
+
+// Size recommended by DNS Flag Day 2020
+const fn default_udp_edns_size() -> u16 {
+    1232
+}
+
+// Enough for most of the responses, while the larger ones are retried over TCP.
+const fn default_udp_buffer_size() -> usize {
+    4096
+}
+
 /// A builder for UDP upstream
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -208,6 +483,12 @@ pub struct UdpBuilder {
     /// Timeout length
     #[serde(default = "default_timeout")]
     pub timeout: u64,
+    /// The UDP payload size advertised to the server in queries with EDNS
+    #[serde(default = "default_udp_edns_size")]
+    pub edns_size: u16,
+    /// Size of the buffer to receive responses. Responses that fill it up are retried over TCP.
+    #[serde(default = "default_udp_buffer_size")]
+    pub buffer_size: usize,
 }
 
 #[async_trait(?Send)]
@@ -215,8 +496,14 @@ impl AsyncTryInto<Upstream> for UdpBuilder {
     type Error = QHandleError;
 
     async fn async_try_into(self) -> Result<Upstream> {
+        // Every DNS implementation must be able to handle this much as per RFC 1035.
+        for size in [self.edns_size.into(), self.buffer_size] {
+            if size < 512 {
+                return Err(QHandleError::InvalidPayloadSize(size));
+            }
+        }
         Ok(Upstream::Others(Arc::new(ConnPool::new(
-            Udp::new(self.addr).await?,
+            Udp::new(self.addr, self.edns_size, self.buffer_size).await?,
             self.max_pool_size,
             Duration::from_secs(self.timeout),
             self.ratelimit.into(),
@@ -224,20 +511,192 @@ impl AsyncTryInto<Upstream> for UdpBuilder {
     }
 }
 
+/// A master file loaded by the zone upstream
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct ZoneFile {
+    /// The origin of the zone, which relative names in the file are relative to. e.g. `lan`
+    pub origin: String,
+    /// Path to the master file
+    pub path: PathBuf,
+}
+
+/// A builder for local zone upstream, which answers authoritatively from master files
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct ZoneBuilder {
+    /// The master files to load, one for each zone
+    pub files: Vec<ZoneFile>,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for ZoneBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        if self.files.is_empty() {
+            return Err(QHandleError::InvalidZone("no zone file given".to_string()));
+        }
+        let mut zones = Vec::with_capacity(self.files.len());
+        for file in self.files {
+            zones.push(Zone::load(&file.origin, &file.path).await?);
+        }
+        Ok(Upstream::Others(Arc::new(Zones::new(zones))))
+    }
+}
+
+// Default TTL of the records in hosts files and static records
+const fn default_local_ttl() -> u32 {
+    60
+}
+
+/// A builder for hosts upstream, which answers from hosts files
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct HostsBuilder {
+    /// Paths to the hosts files, e.g. `/etc/hosts`
+    pub files: Vec<PathBuf>,
+    /// TTL of the records
+    #[serde(default = "default_local_ttl")]
+    pub ttl: u32,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for HostsBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Others(Hosts::load(self.files, self.ttl).await?))
+    }
+}
+
+/// A builder for static upstream, which answers from the records given
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct StaticBuilder {
+    /// The records to answer with
+    pub records: Vec<StaticRecord>,
+    /// TTL of the records which don't specify one
+    #[serde(default = "default_local_ttl")]
+    pub ttl: u32,
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for StaticBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        Ok(Upstream::Others(Arc::new(Table::from_static(
+            &self.records,
+            self.ttl,
+        )?)))
+    }
+}
+
+// Addresses of the root servers (https://www.iana.org/domains/root/servers)
+fn default_root_hints() -> Vec<SocketAddr> {
+    [
+        "198.41.0.4",
+        "170.247.170.2",
+        "192.33.4.12",
+        "199.7.91.13",
+        "192.203.230.10",
+        "192.5.5.241",
+        "192.112.36.4",
+        "198.97.190.53",
+        "192.36.148.17",
+        "192.58.128.30",
+        "193.0.14.129",
+        "199.7.83.42",
+        "202.12.27.33",
+    ]
+    .iter()
+    .map(|ip| SocketAddr::new(ip.parse().unwrap(), 53))
+    .collect()
+}
+
+const fn default_recursive_port() -> u16 {
+    53
+}
+
+// Walking down from the root takes a few round trips on a cold cache.
+const fn default_recursive_timeout() -> u64 {
+    10
+}
+
+/// A builder for recursive upstream, which resolves iteratively from the root servers
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct RecursiveBuilder {
+    /// Addresses of the root servers to start from
+    #[serde(default = "default_root_hints")]
+    pub roots: Vec<SocketAddr>,
+    /// Port of the name servers found by referrals
+    #[serde(default = "default_recursive_port")]
+    pub port: u16,
+    /// Timeout length
+    #[serde(default = "default_recursive_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for RecursiveBuilder {
+    fn default() -> Self {
+        Self {
+            roots: default_root_hints(),
+            port: default_recursive_port(),
+            timeout: default_recursive_timeout(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncTryInto<Upstream> for RecursiveBuilder {
+    type Error = QHandleError;
+
+    async fn async_try_into(self) -> Result<Upstream> {
+        if self.roots.is_empty() {
+            return Err(QHandleError::Unresolvable(
+                "no root server given".to_string(),
+            ));
+        }
+        Ok(Upstream::Others(Arc::new(Recursive::new(
+            self.roots,
+            self.port,
+            Duration::from_secs(self.timeout),
+        ))))
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 /// The builder for `Upstream`
 pub enum UpstreamBuilder {
-    /// Race various different upstreams concurrently. You can use it recursively, meaning Hybrid over (Hybrid over (DoH + UDP) + UDP) is legal.
+    /// Query various different upstreams with a strategy, racing them by default. You can use it recursively, meaning Hybrid over (Hybrid over (DoH + UDP) + UDP) is legal.
     Hybrid(HybridBuilder),
     /// UDP connection.
     Udp(UdpBuilder),
+    /// Plain TCP connection.
+    Tcp(TcpBuilder),
     #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
     /// HTTPS connection.
     Https(HttpsBuilder),
     #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
     /// HTTPS connection.
     Tls(TlsBuilder),
+    #[cfg(feature = "doq-rustls")]
+    /// QUIC connection.
+    Quic(QuicBuilder),
+    #[cfg(feature = "dnscrypt")]
+    /// DNSCrypt connection.
+    DnsCrypt(DnsCryptBuilder),
+    /// Local zone files.
+    Zone(ZoneBuilder),
+    /// Local hosts files.
+    Hosts(HostsBuilder),
+    /// Local static records.
+    Static(StaticBuilder),
+    /// Iterative resolution from the root servers.
+    Recursive(RecursiveBuilder),
 }
 
 #[async_trait(?Send)]
@@ -250,11 +709,32 @@ impl AsyncTryInto<Upstream> for UpstreamBuilder {
             // UDP Upstream
             Self::Udp(u) => u.async_try_into().await?,
 
+            // TCP Upstream
+            Self::Tcp(t) => t.async_try_into().await?,
+
             #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
             Self::Https(h) => h.async_try_into().await?,
 
             #[cfg(any(feature = "dot-native-tls", feature = "dot-rustls"))]
             Self::Tls(t) => t.async_try_into().await?,
+
+            #[cfg(feature = "doq-rustls")]
+            Self::Quic(q) => q.async_try_into().await?,
+
+            #[cfg(feature = "dnscrypt")]
+            Self::DnsCrypt(d) => d.async_try_into().await?,
+
+            // Zone Upstream
+            Self::Zone(z) => z.async_try_into().await?,
+
+            // Hosts Upstream
+            Self::Hosts(h) => h.async_try_into().await?,
+
+            // Static Upstream
+            Self::Static(s) => s.async_try_into().await?,
+
+            // Recursive Upstream
+            Self::Recursive(r) => r.async_try_into().await?,
         })
     }
 
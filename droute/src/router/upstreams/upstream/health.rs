@@ -0,0 +1,331 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::qhandle::{QHandle, QHandleError, Result, DUMMY_QUERY};
+use crate::Label;
+use async_trait::async_trait;
+use bytes::Bytes;
+use deadpool::{managed, Status};
+use domain::base::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+// Default value for the interval in seconds between the probes
+const fn default_probe_interval() -> u64 {
+    30
+}
+
+// Default value for the number of consecutive failures to mark an upstream down
+const fn default_failure_threshold() -> u32 {
+    3
+}
+
+// Default value for the time in seconds before letting queries through to an upstream that is down
+const fn default_cooldown() -> u64 {
+    30
+}
+
+/// Settings of the health checks and the circuit breakers of the upstreams.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct HealthCheck {
+    /// Interval in seconds between the probes sent to each upstream
+    #[serde(default = "default_probe_interval")]
+    pub interval: u64,
+
+    /// Number of consecutive failures, either of queries or probes, to mark an upstream down
+    #[serde(default = "default_failure_threshold")]
+    pub threshold: u32,
+
+    /// Time in seconds before an upstream that is down is half-opened
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            interval: default_probe_interval(),
+            threshold: default_failure_threshold(),
+            cooldown: default_cooldown(),
+        }
+    }
+}
+
+/// Health state of an upstream. They are ordered from the healthiest to the least.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[cfg_attr(feature = "rune-scripting", derive(rune::Any))]
+pub enum HealthState {
+    /// Queries are sent as usual
+    Up,
+    /// The upstream was down, and a single trial query is let through to see if it has recovered
+    HalfOpen,
+    /// Queries fail immediately without being sent
+    Down,
+}
+
+impl fmt::Display for HealthState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Up => "up",
+            Self::HalfOpen => "half_open",
+            Self::Down => "down",
+        })
+    }
+}
+
+// Consecutive failures, when the circuit was opened if it is not closed, and whether the trial query is in flight when half-opened
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    opened: Option<Instant>,
+    trial: bool,
+}
+
+// The trial query let through when half-opened. Another trial is allowed once it is dropped, even if it was cancelled without any result.
+struct Trial<'a>(&'a Guarded);
+
+impl Drop for Trial<'_> {
+    fn drop(&mut self) {
+        self.0.breaker.lock().unwrap().trial = false;
+    }
+}
+
+// A circuit breaker in front of the upstream, which is probed periodically.
+pub struct Guarded {
+    tag: Label,
+    inner: Arc<dyn QHandle>,
+    threshold: u32,
+    cooldown: Duration,
+    breaker: Mutex<Breaker>,
+}
+
+impl Guarded {
+    // Guard the upstream, and start probing it in the background until it is dropped.
+    pub fn start(tag: Label, inner: Arc<dyn QHandle>, settings: &HealthCheck) -> Arc<Self> {
+        let guarded = Arc::new(Self {
+            tag,
+            inner,
+            threshold: settings.threshold,
+            cooldown: Duration::from_secs(settings.cooldown),
+            breaker: Mutex::new(Breaker::default()),
+        });
+        tokio::spawn(Self::probe(
+            Arc::downgrade(&guarded),
+            Duration::from_secs(settings.interval),
+        ));
+        guarded
+    }
+
+    async fn probe(guarded: Weak<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            match guarded.upgrade() {
+                // Probes go straight to the upstream regardless of the state.
+                Some(guarded) => guarded.record(guarded.inner.query(&DUMMY_QUERY).await.is_ok()),
+                None => break,
+            }
+        }
+    }
+
+    fn state(&self) -> HealthState {
+        match self.breaker.lock().unwrap().opened {
+            None => HealthState::Up,
+            Some(opened) if opened.elapsed() < self.cooldown => HealthState::Down,
+            Some(_) => HealthState::HalfOpen,
+        }
+    }
+
+    // Whether the query should be sent, and if it is the trial one.
+    fn admit(&self) -> Result<bool> {
+        let mut breaker = self.breaker.lock().unwrap();
+        match breaker.opened {
+            None => Ok(false),
+            Some(opened) if opened.elapsed() < self.cooldown => Err(QHandleError::Down),
+            // Other queries are held off until the trial has a result, so that a dead upstream doesn't get a burst of them.
+            Some(_) if breaker.trial => Err(QHandleError::Down),
+            Some(_) => {
+                breaker.trial = true;
+                Ok(true)
+            }
+        }
+    }
+
+    fn record(&self, success: bool) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if success {
+            if breaker.opened.is_some() {
+                log::info!("upstream `{}` is up again", self.tag);
+            }
+            *breaker = Breaker::default();
+        } else {
+            breaker.failures += 1;
+            if breaker.opened.is_none() && breaker.failures >= self.threshold {
+                log::warn!(
+                    "upstream `{}` is down after {} consecutive failures",
+                    self.tag,
+                    breaker.failures
+                );
+            }
+            // A failure when half-opened opens the circuit again.
+            if breaker.opened.is_some() || breaker.failures >= self.threshold {
+                breaker.opened = Some(Instant::now());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for Guarded {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        // The trial is ended once the query is completed or cancelled.
+        let _trial = if self.admit()? {
+            Some(Trial(self))
+        } else {
+            None
+        };
+        let resp = self.inner.query(msg).await;
+        self.record(resp.is_ok());
+        resp
+    }
+
+    async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
+        self.inner.reusable().await
+    }
+
+    fn status(&self) -> Option<Status> {
+        self.inner.status()
+    }
+
+    fn cacheable(&self) -> bool {
+        self.inner.cacheable()
+    }
+
+    fn health(&self) -> Option<HealthState> {
+        Some(self.state())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breaker, Guarded, HealthCheck, HealthState, QHandle, QHandleError, DUMMY_QUERY};
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use domain::base::Message;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    // An upstream that can be turned up or down, counting the queries it received. Every query takes a while.
+    #[derive(Default)]
+    struct Flaky {
+        up: AtomicBool,
+        queries: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl QHandle for Flaky {
+        async fn query(&self, msg: &Message<Bytes>) -> super::Result<Message<Bytes>> {
+            self.queries.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if self.up.load(Ordering::Relaxed) {
+                Ok(msg.clone())
+            } else {
+                Err(QHandleError::Throttled)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker() {
+        let flaky = Arc::new(Flaky::default());
+        let guarded = Guarded {
+            tag: "flaky".into(),
+            inner: flaky.clone(),
+            threshold: 2,
+            cooldown: Duration::from_millis(100),
+            breaker: Mutex::new(Breaker::default()),
+        };
+
+        assert!(guarded.query(&DUMMY_QUERY).await.is_err());
+        assert_eq!(guarded.health(), Some(HealthState::Up));
+        assert!(guarded.query(&DUMMY_QUERY).await.is_err());
+        assert_eq!(guarded.health(), Some(HealthState::Down));
+
+        // Queries are not sent while it is down.
+        assert!(matches!(
+            guarded.query(&DUMMY_QUERY).await,
+            Err(QHandleError::Down)
+        ));
+        assert_eq!(flaky.queries.load(Ordering::Relaxed), 2);
+
+        // Only a single trial is let through when half-opened, and its failure opens the circuit again.
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(guarded.health(), Some(HealthState::HalfOpen));
+        let (trial, other) = tokio::join!(guarded.query(&DUMMY_QUERY), guarded.query(&DUMMY_QUERY));
+        assert!(matches!(trial, Err(QHandleError::Throttled)));
+        assert!(matches!(other, Err(QHandleError::Down)));
+        assert_eq!(guarded.health(), Some(HealthState::Down));
+        assert_eq!(flaky.queries.load(Ordering::Relaxed), 3);
+
+        // Another trial is let through if the last one is cancelled.
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(1), guarded.query(&DUMMY_QUERY))
+                .await
+                .is_err()
+        );
+        flaky.up.store(true, Ordering::Relaxed);
+        assert!(guarded.query(&DUMMY_QUERY).await.is_ok());
+        assert_eq!(guarded.health(), Some(HealthState::Up));
+        assert_eq!(flaky.queries.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn probe() {
+        let flaky = Arc::new(Flaky::default());
+        let guarded = Guarded::start(
+            "flaky".into(),
+            flaky.clone(),
+            &HealthCheck {
+                interval: 1,
+                threshold: 1,
+                cooldown: 60,
+            },
+        );
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(guarded.health(), Some(HealthState::Down));
+
+        // Probes are sent regardless of the state, so the upstream recovers before the cooldown ends.
+        flaky.up.store(true, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(guarded.health(), Some(HealthState::Up));
+
+        // Probing stops once the upstream is dropped.
+        drop(guarded);
+        let queries = flaky.queries.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(flaky.queries.load(Ordering::Relaxed), queries);
+    }
+}
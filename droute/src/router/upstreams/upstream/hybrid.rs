@@ -0,0 +1,379 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::super::error::Result;
+use crate::Label;
+use bytes::Bytes;
+use domain::base::Message;
+use futures::{
+    future::{select_ok, BoxFuture},
+    stream::{FuturesUnordered, StreamExt},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Weight of the latest sample in the moving average of latencies
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+// Failed queries are counted as at least this slow, so that failing members are tried last.
+const FAILURE_LATENCY: Duration = Duration::from_secs(5);
+
+/// Strategy to pick the members of a hybrid upstream to query
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HybridStrategy {
+    /// Query all the members at once, and take the first successful response
+    Race,
+    /// Query the members one by one in order, until one of them succeeds
+    Sequential,
+    /// Like `Sequential`, but start from the next member on every query
+    RoundRobin,
+    /// Like `RoundRobin`, but start from each member in proportion to its weight
+    Weighted,
+    /// Like `Sequential`, but in the order of the moving averages of their latencies
+    Fastest,
+    /// Query the members in order, firing the next one if there is no response after a delay, and take the first successful response
+    Hedged,
+}
+
+impl Default for HybridStrategy {
+    fn default() -> Self {
+        Self::Race
+    }
+}
+
+enum Strategy {
+    Race,
+    Sequential,
+    RoundRobin(AtomicUsize),
+    // Smooth weighted round-robin, as in nginx
+    Weighted {
+        weights: Vec<i64>,
+        current: Mutex<Vec<i64>>,
+    },
+    // Moving averages of the latencies in seconds, or None if the member has never been queried
+    Fastest(Mutex<Vec<Option<f64>>>),
+    Hedged(Duration),
+}
+
+/// The members of a hybrid upstream, and the strategy to query them.
+#[derive(Clone)]
+pub struct Hybrid {
+    tags: Vec<Label>,
+    // Shared by the clones, so that the states like latencies are kept
+    strategy: Arc<Strategy>,
+}
+
+impl Hybrid {
+    pub(super) fn new(
+        tags: Vec<Label>,
+        strategy: HybridStrategy,
+        weights: &HashMap<Label, u32>,
+        delay: Duration,
+    ) -> Self {
+        let strategy = match strategy {
+            HybridStrategy::Race => Strategy::Race,
+            HybridStrategy::Sequential => Strategy::Sequential,
+            HybridStrategy::RoundRobin => Strategy::RoundRobin(AtomicUsize::new(0)),
+            HybridStrategy::Weighted => Strategy::Weighted {
+                // Members not given a weight have the weight of 1.
+                weights: tags
+                    .iter()
+                    .map(|t| weights.get(t).copied().unwrap_or(1).into())
+                    .collect(),
+                current: Mutex::new(vec![0; tags.len()]),
+            },
+            HybridStrategy::Fastest => Strategy::Fastest(Mutex::new(vec![None; tags.len()])),
+            HybridStrategy::Hedged => Strategy::Hedged(delay),
+        };
+        Self {
+            tags,
+            strategy: Arc::new(strategy),
+        }
+    }
+
+    pub(crate) fn tags(&self) -> &[Label] {
+        &self.tags
+    }
+
+    // Indices of the members, in the order to try them
+    fn order(&self) -> Vec<usize> {
+        let len = self.tags.len();
+        let rotate = |start: usize| (start..len).chain(0..start).collect();
+        match &*self.strategy {
+            Strategy::RoundRobin(next) => rotate(next.fetch_add(1, Ordering::Relaxed) % len),
+            Strategy::Weighted { weights, current } => {
+                let mut current = current.lock().unwrap();
+                let total: i64 = weights.iter().sum();
+                for (c, w) in current.iter_mut().zip(weights) {
+                    *c += w;
+                }
+                // The first one is picked on ties.
+                let picked = (0..len).rev().max_by_key(|&i| current[i]).unwrap();
+                current[picked] -= total;
+                rotate(picked)
+            }
+            Strategy::Fastest(latencies) => {
+                let latencies = latencies.lock().unwrap();
+                let mut order: Vec<usize> = (0..len).collect();
+                // Members never queried are tried first, so that we learn their latencies.
+                order.sort_by(|&a, &b| {
+                    latencies[a]
+                        .unwrap_or(0.0)
+                        .total_cmp(&latencies[b].unwrap_or(0.0))
+                });
+                order
+            }
+            _ => (0..len).collect(),
+        }
+    }
+
+    fn record(&self, index: usize, latency: Duration, success: bool) {
+        if let Strategy::Fastest(latencies) = &*self.strategy {
+            let sample = if success {
+                latency
+            } else {
+                latency.max(FAILURE_LATENCY)
+            }
+            .as_secs_f64();
+            let average = &mut latencies.lock().unwrap()[index];
+            *average = Some(match *average {
+                Some(average) => average + LATENCY_SMOOTHING * (sample - average),
+                None => sample,
+            });
+        }
+    }
+
+    /// Send the query with `send` to the members picked by the strategy, and return the first successful response.
+    /// Members that are not `up` are skipped, unless none of them is.
+    pub(crate) async fn send<'a, U, F>(&'a self, up: U, send: F) -> Result<Message<Bytes>>
+    where
+        U: Fn(&Label) -> bool,
+        F: Fn(&'a Label) -> BoxFuture<'a, Result<Message<Bytes>>>,
+    {
+        let order = self.order();
+        let members: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|&i| up(&self.tags[i]))
+            .collect();
+        let members = if members.is_empty() { order } else { members };
+        match &*self.strategy {
+            Strategy::Race => Ok(select_ok(members.into_iter().map(|i| send(&self.tags[i])))
+                .await?
+                .0),
+            Strategy::Hedged(delay) => self.hedged(*delay, members, send).await,
+            _ => {
+                let mut error = None;
+                for i in members {
+                    let start = Instant::now();
+                    let resp = send(&self.tags[i]).await;
+                    self.record(i, start.elapsed(), resp.is_ok());
+                    match resp {
+                        Ok(resp) => return Ok(resp),
+                        Err(e) => error = Some(e),
+                    }
+                }
+                // Hybrid upstreams are validated to be non-empty.
+                Err(error.unwrap())
+            }
+        }
+    }
+
+    async fn hedged<'a, F>(
+        &'a self,
+        delay: Duration,
+        members: Vec<usize>,
+        send: F,
+    ) -> Result<Message<Bytes>>
+    where
+        F: Fn(&'a Label) -> BoxFuture<'a, Result<Message<Bytes>>>,
+    {
+        let mut tags = members.into_iter().map(|i| &self.tags[i]);
+        let mut pending = FuturesUnordered::new();
+        let mut error = None;
+        if let Some(tag) = tags.next() {
+            pending.push(send(tag));
+        }
+        loop {
+            let more = tags.len() > 0;
+            tokio::select! {
+                resp = pending.next() => match resp {
+                    Some(Ok(resp)) => return Ok(resp),
+                    Some(Err(e)) => error = Some(e),
+                    None => (),
+                },
+                // The next member is fired once the delay elapses, or the previous one fails.
+                _ = tokio::time::sleep(delay), if more => (),
+            }
+            match tags.next() {
+                Some(tag) => pending.push(send(tag)),
+                None if pending.is_empty() => return Err(error.unwrap()),
+                None => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hybrid, HybridStrategy};
+    use crate::{errors::UpstreamError, Label};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Message, MessageBuilder};
+    use futures::future::{BoxFuture, FutureExt};
+    use std::{collections::HashMap, time::Duration};
+
+    fn hybrid(strategy: HybridStrategy, weights: &[(&str, u32)]) -> Hybrid {
+        Hybrid::new(
+            vec!["a".into(), "b".into(), "c".into()],
+            strategy,
+            &weights.iter().map(|&(t, w)| (t.into(), w)).collect(),
+            Duration::from_millis(50),
+        )
+    }
+
+    // Tag the response with the index of the member, in the ID of the message.
+    fn response(tag: &Label) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new()).unwrap();
+        builder.header_mut().set_id(match tag.as_str() {
+            "a" => 0,
+            "b" => 1,
+            _ => 2,
+        });
+        Message::from_octets(builder.into_message().into_octets().freeze()).unwrap()
+    }
+
+    #[test]
+    fn round_robin() {
+        let h = hybrid(HybridStrategy::RoundRobin, &[]);
+        assert_eq!(h.order(), vec![0, 1, 2]);
+        assert_eq!(h.order(), vec![1, 2, 0]);
+        assert_eq!(h.order(), vec![2, 0, 1]);
+        assert_eq!(h.order(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn weighted() {
+        let h = hybrid(HybridStrategy::Weighted, &[("a", 4), ("c", 0)]);
+        let mut picked = HashMap::new();
+        for _ in 0..10 {
+            *picked.entry(h.order()[0]).or_insert(0) += 1;
+        }
+        assert_eq!(picked.get(&0), Some(&8));
+        assert_eq!(picked.get(&1), Some(&2));
+        assert_eq!(picked.get(&2), None);
+    }
+
+    #[test]
+    fn fastest() {
+        let h = hybrid(HybridStrategy::Fastest, &[]);
+        h.record(0, Duration::from_millis(30), true);
+        h.record(2, Duration::from_millis(10), true);
+        // `b` has never been queried.
+        assert_eq!(h.order(), vec![1, 2, 0]);
+        h.record(1, Duration::from_millis(1), false);
+        assert_eq!(h.order(), vec![2, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn sequential_failover() {
+        let h = hybrid(HybridStrategy::Sequential, &[]);
+        let resp = h
+            .send(
+                |_| true,
+                |t| {
+                    async move {
+                        match t.as_str() {
+                            "c" => Ok(response(t)),
+                            _ => Err(UpstreamError::MissingTag(t.clone())),
+                        }
+                    }
+                    .boxed()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.header().id(), 2);
+
+        // The last error is returned if all of them fail.
+        match h
+            .send(
+                |_| true,
+                |t| async move { Err(UpstreamError::MissingTag(t.clone())) }.boxed(),
+            )
+            .await
+        {
+            Err(UpstreamError::MissingTag(t)) => assert_eq!(t, "c".into()),
+            _ => panic!("Not the right error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn skip_down() {
+        fn send(t: &Label) -> BoxFuture<Result<Message<Bytes>, UpstreamError>> {
+            let resp = response(t);
+            async move {
+                // `a` would win the race if it were up.
+                if resp.header().id() != 0 {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                Ok(resp)
+            }
+            .boxed()
+        }
+
+        let h = hybrid(HybridStrategy::Race, &[]);
+        let resp = h.send(|t| t.as_str() != "a", send).await.unwrap();
+        assert_ne!(resp.header().id(), 0);
+
+        // All of them are tried if none of them is up.
+        let resp = h.send(|_| false, send).await.unwrap();
+        assert_eq!(resp.header().id(), 0);
+    }
+
+    #[tokio::test]
+    async fn hedged() {
+        let h = hybrid(HybridStrategy::Hedged, &[]);
+        // `a` is too slow, so `b` is fired after the delay, while `c` is fired right after `b` fails.
+        let resp = h
+            .send(
+                |_| true,
+                |t| {
+                    async move {
+                        match t.as_str() {
+                            "a" => {
+                                tokio::time::sleep(Duration::from_secs(10)).await;
+                                Ok(response(t))
+                            }
+                            "b" => Err(UpstreamError::MissingTag(t.clone())),
+                            _ => Ok(response(t)),
+                        }
+                    }
+                    .boxed()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.header().id(), 2);
+    }
+}
@@ -14,38 +14,68 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod builder;
+mod health;
+mod hybrid;
 mod qhandle;
 
 use std::sync::Arc;
 
 use bytes::Bytes;
+pub use health::HealthState;
+pub use hybrid::Hybrid;
 pub use qhandle::{QHandle, QHandleError};
 
+use self::health::{Guarded, HealthCheck};
 use super::{error::Result, CacheMode};
 use crate::{
     cache::{RecordStatus::*, RespCache},
+    metrics::{record_upstream_error, UPSTREAM_LATENCY},
+    trace::{self, CacheStatus},
     Label,
 };
+use deadpool::Status;
 use domain::base::Message;
 
 /// A single upstream. Opposite to the `Upstreams`.
 #[derive(Clone)]
 pub enum Upstream {
     /// Hybrid upstream type
-    // We don't use HashSet because we don't need to look up
-    Hybrid(Vec<Label>),
+    Hybrid(Hybrid),
     /// Other upstream types, like Zone or ClientPool.
     Others(Arc<dyn QHandle>),
 }
 
 impl Upstream {
-    pub(super) fn try_hybrid(&self) -> Option<Vec<&Label>> {
+    pub(super) fn try_hybrid(&self) -> Option<&Hybrid> {
         match &self {
-            Self::Hybrid(v) => Some(v.iter().collect()),
+            Self::Hybrid(v) => Some(v),
             _ => None,
         }
     }
 
+    // Put the upstream behind a circuit breaker, which is probed periodically. Hybrid upstreams are left as is.
+    pub(super) fn guard(&mut self, tag: &Label, settings: &HealthCheck) {
+        if let Self::Others(inner) = self {
+            *inner = Guarded::start(tag.clone(), inner.clone(), settings);
+        }
+    }
+
+    // Health state of the upstream if it is guarded.
+    pub(super) fn health(&self) -> Option<HealthState> {
+        match &self {
+            Self::Others(inner) => inner.health(),
+            Self::Hybrid(_) => None,
+        }
+    }
+
+    // Status of the connection pool behind, if there is any.
+    pub(crate) fn pool_status(&self) -> Option<Status> {
+        match &self {
+            Self::Others(inner) => inner.status(),
+            Self::Hybrid(_) => None,
+        }
+    }
+
     /// Resolve the query into a response.
     pub async fn resolve(
         &self,
@@ -56,19 +86,53 @@ impl Upstream {
     ) -> Result<Message<Bytes>> {
         if let Self::Others(inner) = &self {
             log::info!("querying with upstream: {}", tag);
+            let _timer = UPSTREAM_LATENCY
+                .with_label_values(&[tag.as_str()])
+                .start_timer();
+            // Count the errors by their kinds before they get wrapped.
+            let query = || async move {
+                inner.query(msg).await.map_err(|e| {
+                    record_upstream_error(tag, e.kind());
+                    e
+                })
+            };
+            let record = |cache| trace::record_upstream(tag, cache);
+            let cache_mode = if inner.cacheable() {
+                cache_mode
+            } else {
+                &CacheMode::Disabled
+            };
             // Manage cache with caching policies
             let r = match cache_mode {
-                CacheMode::Disabled => inner.query(msg).await?,
+                CacheMode::Disabled => {
+                    record(CacheStatus::Disabled);
+                    query().await?
+                }
                 CacheMode::Standard => match cache.get(tag, msg) {
                     // Cache available within TTL constraints
-                    Some(Alive(r)) => r,
-                    // No cache or cache expired
-                    Some(Expired(_)) | None => inner.query(msg).await?,
+                    Some(Alive(r)) => {
+                        record(CacheStatus::Hit);
+                        r
+                    }
+                    // Cache expired
+                    Some(Expired(_)) => {
+                        record(CacheStatus::Expired);
+                        query().await?
+                    }
+                    // No cache
+                    None => {
+                        record(CacheStatus::Miss);
+                        query().await?
+                    }
                 },
                 CacheMode::Persistent => match cache.get(tag, msg) {
                     // Cache available within TTL constraints
-                    Some(Alive(r)) => r,
+                    Some(Alive(r)) => {
+                        record(CacheStatus::Hit);
+                        r
+                    }
                     Some(Expired(r)) => {
+                        record(CacheStatus::Expired);
                         // Cache records exists, but TTL exceeded.
                         // We try to update the cache and return back the outdated value.
                         let inner = inner.clone();
@@ -85,7 +149,10 @@ impl Upstream {
                         });
                         r
                     }
-                    None => inner.query(msg).await?,
+                    None => {
+                        record(CacheStatus::Miss);
+                        query().await?
+                    }
                 },
             };
             if cache_mode != &CacheMode::Disabled {
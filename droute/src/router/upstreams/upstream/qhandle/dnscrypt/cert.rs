@@ -0,0 +1,146 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    super::{udp::Udp, ConnInitiator, QHandle},
+    stamp::Stamp,
+};
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{Dname, Message, MessageBuilder, Rtype},
+    rdata::Txt,
+};
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::debug;
+use std::{
+    io::{Error, ErrorKind},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::timeout;
+
+// Magic number at the beginning of every certificate
+const CERT_MAGIC: &[u8] = b"DNSC";
+
+// Size of the certificate without extensions
+const CERT_LEN: usize = 124;
+
+// Time allowed to fetch the certificates
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Encryption system of a certificate
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EsVersion {
+    /// X25519-XSalsa20Poly1305
+    XSalsa20Poly1305 = 1,
+    /// X25519-XChaCha20Poly1305
+    XChaCha20Poly1305 = 2,
+}
+
+/// A resolver certificate signed by the provider
+#[derive(Clone, Debug)]
+pub struct Cert {
+    pub es_version: EsVersion,
+    pub resolver_pk: [u8; 32],
+    pub client_magic: [u8; 8],
+    pub serial: u32,
+    pub ts_start: u32,
+    pub ts_end: u32,
+}
+
+// Seconds since UNIX epoch, as used in certificates
+pub(super) fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs().try_into().unwrap_or(u32::MAX))
+        .unwrap_or(0)
+}
+
+fn u32_at(data: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes(data[i..i + 4].try_into().unwrap())
+}
+
+impl Cert {
+    // Parse the certificate, and verify its signature with the provider public key.
+    pub fn parse(data: &[u8], provider_pk: &VerifyingKey) -> Option<Self> {
+        if data.len() < CERT_LEN || &data[..4] != CERT_MAGIC || data[6..8] != [0, 0] {
+            return None;
+        }
+        let es_version = match data[4..6] {
+            [0, 1] => EsVersion::XSalsa20Poly1305,
+            [0, 2] => EsVersion::XChaCha20Poly1305,
+            _ => return None,
+        };
+
+        // The signature covers everything that follows it, including extensions.
+        let signature = Signature::from_bytes(data[8..72].try_into().unwrap());
+        provider_pk.verify_strict(&data[72..], &signature).ok()?;
+
+        Some(Self {
+            es_version,
+            resolver_pk: data[72..104].try_into().unwrap(),
+            client_magic: data[104..112].try_into().unwrap(),
+            serial: u32_at(data, 112),
+            ts_start: u32_at(data, 116),
+            ts_end: u32_at(data, 120),
+        })
+    }
+
+    pub fn is_valid_at(&self, now: u32) -> bool {
+        (self.ts_start..self.ts_end).contains(&now)
+    }
+}
+
+// Fetch the certificates of the provider from the resolver over plain DNS, and pick the one to use.
+pub(super) async fn fetch(stamp: &Stamp) -> std::io::Result<Cert> {
+    let invalid = |e| Error::new(ErrorKind::InvalidData, e);
+    let provider_pk = VerifyingKey::from_bytes(&stamp.provider_pk)
+        .map_err(|_| invalid("invalid provider public key"))?;
+
+    let name = Dname::<Bytes>::from_str(&stamp.provider_name)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid provider name"))?;
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(512)).unwrap();
+    builder.header_mut().set_rd(true);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::Txt)).unwrap();
+    let query = builder.into_message();
+
+    // Certificates are served as TXT records, which are retried over TCP if they don't fit in UDP.
+    let conn = Udp::new(stamp.addr, 1232, 4096)
+        .await
+        .map_err(|e| Error::new(ErrorKind::Other, e))?
+        .create()
+        .await?;
+    let resp = timeout(FETCH_TIMEOUT, conn.query(&query))
+        .await?
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    let now = now();
+    resp.answer()
+        .map_err(|_| invalid("malformed certificate response"))?
+        .limit_to::<Txt<_>>()
+        .filter_map(|record| {
+            let data: Vec<u8> = record.ok()?.data().iter().flatten().copied().collect();
+            let cert = Cert::parse(&data, &provider_pk);
+            if cert.is_none() {
+                debug!("ignoring invalid certificate from {}", stamp.addr);
+            }
+            cert
+        })
+        .filter(|cert| cert.is_valid_at(now))
+        // Prefer the latest certificate, and XChaCha20 on ties.
+        .max_by_key(|cert| (cert.serial, cert.es_version))
+        .ok_or_else(|| invalid("no valid certificate found"))
+}
@@ -0,0 +1,530 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+mod cert;
+mod stamp;
+
+use self::cert::{Cert, EsVersion};
+pub use self::stamp::Stamp;
+use super::{tcp, ConnInitiator, QHandle, Result};
+use crate::dnstap::SocketProtocol;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use crypto_box::{
+    aead::{rand_core::RngCore, AeadInPlace, OsRng},
+    ChaChaBox, Nonce, PublicKey, SalsaBox, SecretKey, Tag,
+};
+use deadpool::managed::{self, RecycleError};
+use domain::base::Message;
+use log::{debug, warn};
+use std::{
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    sync::Mutex,
+};
+
+// Magic number at the beginning of every response
+const RESOLVER_MAGIC: &[u8] = &[0x72, 0x36, 0x66, 0x6e, 0x76, 0x57, 0x6a, 0x38];
+
+// Queries over UDP are padded to at least this size, so that responses are not much larger than the queries.
+const MIN_QUERY_LEN: usize = 256;
+
+// Padded queries are a multiple of this size.
+const PADDING_BLOCK: usize = 64;
+
+// Responses over UDP are received into a buffer of this size.
+const MAX_RESPONSE_LEN: usize = 4096;
+
+// Certificates are checked for renewal at least this often, in seconds.
+const CERT_REFRESH: u32 = 3600;
+
+// Time to wait before trying again if the renewal failed, in seconds.
+const CERT_RETRY: u32 = 60;
+
+enum Cipher {
+    Salsa(SalsaBox),
+    ChaCha(ChaChaBox),
+}
+
+impl Cipher {
+    fn new(es_version: EsVersion, pk: &PublicKey, sk: &SecretKey) -> Self {
+        match es_version {
+            EsVersion::XSalsa20Poly1305 => Self::Salsa(SalsaBox::new(pk, sk)),
+            EsVersion::XChaCha20Poly1305 => Self::ChaCha(ChaChaBox::new(pk, sk)),
+        }
+    }
+
+    fn seal(&self, nonce: &[u8; 24], buf: &mut [u8]) -> Tag {
+        let nonce = Nonce::from_slice(nonce);
+        match self {
+            Self::Salsa(c) => c.encrypt_in_place_detached(nonce, b"", buf),
+            Self::ChaCha(c) => c.encrypt_in_place_detached(nonce, b"", buf),
+        }
+        // This only fails with associated data, which we don't use.
+        .unwrap()
+    }
+
+    fn open(&self, nonce: &[u8], buf: &mut [u8], tag: &[u8]) -> Option<()> {
+        let (nonce, tag) = (Nonce::from_slice(nonce), Tag::from_slice(tag));
+        match self {
+            Self::Salsa(c) => c.decrypt_in_place_detached(nonce, b"", buf, tag),
+            Self::ChaCha(c) => c.decrypt_in_place_detached(nonce, b"", buf, tag),
+        }
+        .ok()
+    }
+}
+
+// Pad with 0x80 followed by zeros (ISO/IEC 7816-4) to a multiple of the block size, and at least `min_len`.
+fn pad(buf: &mut Vec<u8>, start: usize, min_len: usize) {
+    let len = buf.len() - start;
+    let padded_len = ((len + PADDING_BLOCK) / PADDING_BLOCK * PADDING_BLOCK).max(min_len);
+    buf.push(0x80);
+    buf.resize(start + padded_len, 0);
+}
+
+fn unpad(buf: &[u8]) -> Option<&[u8]> {
+    let end = buf.iter().rposition(|&b| b != 0)?;
+    (buf[end] == 0x80).then_some(&buf[..end])
+}
+
+// The certificate in use with the shared key derived from it.
+struct Session {
+    cipher: Cipher,
+    client_pk: PublicKey,
+    client_magic: [u8; 8],
+    // Time after which we look for a new certificate
+    refresh_at: AtomicU32,
+    // Time after which the certificate is no longer accepted by the resolver
+    expire_at: u32,
+}
+
+impl Session {
+    fn new(cert: Cert) -> Self {
+        // A new key pair for every certificate, so that queries cannot be linked across rotations.
+        let client_sk = SecretKey::generate(&mut OsRng);
+        let resolver_pk = PublicKey::from_bytes(cert.resolver_pk);
+        let session = Self {
+            cipher: Cipher::new(cert.es_version, &resolver_pk, &client_sk),
+            client_pk: client_sk.public_key(),
+            client_magic: cert.client_magic,
+            refresh_at: AtomicU32::new(0),
+            expire_at: cert.ts_end,
+        };
+        session.postpone_refresh(CERT_REFRESH);
+        session
+    }
+
+    // Schedule the next renewal ahead of expiry, halving the interval as the certificate gets close to it.
+    fn postpone_refresh(&self, interval: u32) {
+        let now = cert::now();
+        self.refresh_at.store(
+            now + interval.min(self.expire_at.saturating_sub(now) / 2),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn due_for_refresh(&self) -> bool {
+        cert::now() >= self.refresh_at.load(Ordering::Relaxed)
+    }
+
+    // Encrypt the padded query. Returns the packet and the client half of the nonce.
+    fn encrypt(&self, msg: &[u8], min_len: usize) -> (Vec<u8>, [u8; 12]) {
+        // The resolver fills the other half of the nonce in its response.
+        let mut nonce = [0; 24];
+        OsRng.fill_bytes(&mut nonce[..12]);
+
+        // client-magic, client-pk, client-nonce, and then the box (tag followed by the ciphertext)
+        let mut packet = Vec::with_capacity(8 + 32 + 12 + 16 + msg.len() + min_len);
+        packet.extend_from_slice(&self.client_magic);
+        packet.extend_from_slice(self.client_pk.as_bytes());
+        packet.extend_from_slice(&nonce[..12]);
+        packet.extend_from_slice(&[0; 16]);
+        let start = packet.len();
+        packet.extend_from_slice(msg);
+        pad(&mut packet, start, min_len);
+
+        let tag = self.cipher.seal(&nonce, &mut packet[start..]);
+        packet[start - 16..start].copy_from_slice(&tag);
+
+        (packet, nonce[..12].try_into().unwrap())
+    }
+
+    // Decrypt the response to the query sent with the client nonce given. None is returned on garbage.
+    fn decrypt(&self, packet: &[u8], client_nonce: &[u8; 12]) -> Option<Message<Bytes>> {
+        // resolver-magic, nonce, and then the box
+        if packet.len() < 8 + 24 + 16
+            || &packet[..8] != RESOLVER_MAGIC
+            || &packet[8..20] != client_nonce
+        {
+            return None;
+        }
+        let mut buf = packet[48..].to_vec();
+        self.cipher
+            .open(&packet[8..32], &mut buf, &packet[32..48])?;
+        Message::from_octets(Bytes::copy_from_slice(unpad(&buf)?)).ok()
+    }
+}
+
+/// Client instance for DNSCrypt connections
+pub struct DnsCrypt {
+    stamp: Stamp,
+    session: Mutex<Option<Arc<Session>>>,
+}
+
+impl DnsCrypt {
+    /// Create a new DNSCrypt client creator instance with the given server stamp (`sdns://...`).
+    pub fn new(stamp: &str) -> Result<Self> {
+        Ok(Self {
+            stamp: Stamp::from_str(stamp)?,
+            session: Mutex::new(None),
+        })
+    }
+
+    // Get the current session, fetching a new certificate if it is due for renewal.
+    async fn session(&self) -> std::io::Result<Arc<Session>> {
+        let mut current = self.session.lock().await;
+        let now = cert::now();
+        match current.as_ref() {
+            Some(session) if !session.due_for_refresh() => return Ok(session.clone()),
+            _ => (),
+        }
+
+        match cert::fetch(&self.stamp).await {
+            Ok(cert) => {
+                debug!(
+                    "using certificate {} of {} valid until {}",
+                    cert.serial, self.stamp.provider_name, cert.ts_end
+                );
+                let session = Arc::new(Session::new(cert));
+                *current = Some(session.clone());
+                Ok(session)
+            }
+            // Keep using the current certificate until it expires if we cannot renew it for now.
+            Err(e) => match current.as_ref() {
+                Some(session) if now < session.expire_at => {
+                    warn!(
+                        "failed to renew the certificate of {}: {}",
+                        self.stamp.provider_name, e
+                    );
+                    session.postpone_refresh(CERT_RETRY);
+                    Ok(session.clone())
+                }
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+/// A connected UDP socket sending queries encrypted with the current certificate, which falls back to TCP on truncated responses.
+pub struct DnsCryptConn {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    session: Arc<Session>,
+}
+
+#[async_trait]
+impl ConnInitiator for DnsCrypt {
+    type Connection = DnsCryptConn;
+
+    async fn create(&self) -> std::io::Result<Self::Connection> {
+        let session = self.session().await?;
+        let socket = UdpSocket::bind(if self.stamp.addr.is_ipv4() {
+            SocketAddr::from(([0u8; 4], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        })
+        .await?;
+        socket.connect(self.stamp.addr).await?;
+        Ok(DnsCryptConn {
+            socket,
+            addr: self.stamp.addr,
+            session,
+        })
+    }
+
+    fn conn_type(&self) -> &'static str {
+        "DNSCrypt"
+    }
+
+    fn remote(&self) -> SocketAddr {
+        self.stamp.addr
+    }
+
+    fn protocol(&self) -> SocketProtocol {
+        SocketProtocol::DnsCryptUdp
+    }
+}
+
+impl DnsCryptConn {
+    // Retry the query over a one-off TCP connection to the same server.
+    async fn query_tcp(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let mut stream = tcp::connect(self.addr).await?;
+        let (packet, nonce) = self.session.encrypt(msg.as_slice(), 0);
+
+        // Prefix our payload with length per RFC.
+        let mut buf = Vec::with_capacity(packet.len() + 2);
+        buf.extend_from_slice(
+            &u16::try_from(packet.len())
+                .expect("request too long")
+                .to_be_bytes(),
+        );
+        buf.extend_from_slice(&packet);
+        stream.write_all(&buf).await?;
+
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await?;
+        let mut buf = vec![0; u16::from_be_bytes(len).into()];
+        stream.read_exact(&mut buf).await?;
+
+        match self.session.decrypt(&buf, &nonce) {
+            Some(answer) if answer.is_answer(msg) => Ok(answer),
+            _ => Err(Error::new(ErrorKind::InvalidData, "invalid DNSCrypt response").into()),
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for DnsCryptConn {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        // Randomnize the message
+        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+        msg.header_mut().set_random_id();
+        let msg = Message::from_octets(msg.into_octets().freeze())?;
+
+        let (packet, nonce) = self.session.encrypt(msg.as_slice(), MIN_QUERY_LEN);
+        self.socket.send(&packet).await?;
+
+        loop {
+            let mut buf = [0; MAX_RESPONSE_LEN];
+            let len = self.socket.recv(&mut buf).await?;
+
+            // We ignore garbage since there is a timer on this whole thing.
+            let answer = match self.session.decrypt(&buf[..len], &nonce) {
+                Some(answer) if answer.is_answer(&msg) => answer,
+                _ => continue,
+            };
+            if answer.header().tc() {
+                debug!(
+                    "response from {} is truncated, retrying over TCP",
+                    self.addr
+                );
+                return self.query_tcp(&msg).await;
+            }
+            return Ok(answer);
+        }
+    }
+
+    async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
+        // Let the pool create a new handle, which picks up the renewed certificate.
+        if self.session.due_for_refresh() {
+            Err(RecycleError::StaticMessage(
+                "DNSCrypt certificate due for renewal",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cert::{self, EsVersion},
+        pad,
+        stamp::tests::encode,
+        unpad, Cipher, DnsCrypt, RESOLVER_MAGIC,
+    };
+    use crate::router::upstreams::upstream::qhandle::{ConnInitiator, QHandle};
+    use bytes::{Bytes, BytesMut};
+    use crypto_box::{PublicKey, SecretKey};
+    use domain::base::{Dname, Message, MessageBuilder, Rtype};
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::{net::SocketAddr, str::FromStr};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+    };
+
+    const PROVIDER_NAME: &str = "2.dnscrypt-cert.example.com";
+    const CLIENT_MAGIC: [u8; 8] = *b"dcompass";
+
+    fn provider_key() -> SigningKey {
+        SigningKey::from_bytes(&[1; 32])
+    }
+
+    fn resolver_key() -> SecretKey {
+        SecretKey::from_bytes([2; 32])
+    }
+
+    fn certificate(es_version: EsVersion, signer: &SigningKey) -> Vec<u8> {
+        let now = cert::now();
+        let mut signed = resolver_key().public_key().as_bytes().to_vec();
+        signed.extend_from_slice(&CLIENT_MAGIC);
+        // Serial, and validity period
+        signed.extend_from_slice(&1u32.to_be_bytes());
+        signed.extend_from_slice(&(now - 60).to_be_bytes());
+        signed.extend_from_slice(&(now + 86400).to_be_bytes());
+
+        let mut cert = b"DNSC".to_vec();
+        cert.extend_from_slice(&[0, es_version as u8, 0, 0]);
+        cert.extend_from_slice(&signer.sign(&signed).to_bytes());
+        cert.extend_from_slice(&signed);
+        cert
+    }
+
+    // Turn the query into a response with a single record, or a truncated one without any.
+    fn respond(query: &[u8], rtype: u16, rdata: &[u8], truncated: bool) -> Vec<u8> {
+        let mut resp = query.to_vec();
+        // QR
+        resp[2] |= 0x80;
+        if truncated {
+            resp[2] |= 0x02;
+            return resp;
+        }
+        resp[6..8].copy_from_slice(&1u16.to_be_bytes());
+        // Pointer to the name in question, class IN, and TTL
+        resp.extend_from_slice(&[0xc0, 0x0c]);
+        resp.extend_from_slice(&rtype.to_be_bytes());
+        resp.extend_from_slice(&[0, 1, 0, 0, 1, 0]);
+        resp.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        resp.extend_from_slice(rdata);
+        resp
+    }
+
+    // A DNSCrypt resolver serving the certificate over plain DNS, and answering encrypted queries with `127.0.0.1`.
+    fn handle(packet: &[u8], cert: &[u8], es_version: EsVersion, truncated: bool) -> Vec<u8> {
+        if packet[..8] != CLIENT_MAGIC {
+            let mut txt = vec![cert.len() as u8];
+            txt.extend_from_slice(cert);
+            return respond(packet, 16, &txt, false);
+        }
+
+        let cipher = Cipher::new(
+            es_version,
+            &PublicKey::from_slice(&packet[8..40]).unwrap(),
+            &resolver_key(),
+        );
+        let mut nonce = [0; 24];
+        nonce[..12].copy_from_slice(&packet[40..52]);
+        let mut query = packet[68..].to_vec();
+        cipher.open(&nonce, &mut query, &packet[52..68]).unwrap();
+        let query = unpad(&query).unwrap();
+
+        nonce[12..].copy_from_slice(&[9; 12]);
+        let mut resp = RESOLVER_MAGIC.to_vec();
+        resp.extend_from_slice(&nonce);
+        resp.extend_from_slice(&[0; 16]);
+        resp.extend_from_slice(&respond(query, 1, &[127, 0, 0, 1], truncated));
+        pad(&mut resp, 48, 0);
+        let tag = cipher.seal(&nonce, &mut resp[48..]);
+        resp[32..48].copy_from_slice(&tag);
+        resp
+    }
+
+    async fn spawn_resolver(cert: Vec<u8>, es_version: EsVersion, truncated: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).await.unwrap();
+
+        let udp_cert = cert.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            loop {
+                let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+                let resp = handle(&buf[..len], &udp_cert, es_version, truncated);
+                socket.send_to(&resp, src).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                let mut buf = vec![0; u16::from_be_bytes(len).into()];
+                stream.read_exact(&mut buf).await.unwrap();
+                let resp = handle(&buf, &cert, es_version, false);
+                stream
+                    .write_all(&(resp.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&resp).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn client(addr: SocketAddr) -> DnsCrypt {
+        DnsCrypt::new(&encode(
+            &addr.to_string(),
+            provider_key().verifying_key().as_bytes(),
+            PROVIDER_NAME,
+        ))
+        .unwrap()
+    }
+
+    fn query() -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        builder.into_message()
+    }
+
+    #[tokio::test]
+    async fn query_over_udp() {
+        for es_version in [EsVersion::XSalsa20Poly1305, EsVersion::XChaCha20Poly1305] {
+            let cert = certificate(es_version, &provider_key());
+            let addr = spawn_resolver(cert, es_version, false).await;
+
+            let conn = client(addr).create().await.unwrap();
+            let query = query();
+            let resp = conn.query(&query).await.unwrap();
+            assert!(resp.is_answer(&query));
+            assert_eq!(resp.header_counts().ancount(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback_to_tcp() {
+        let es_version = EsVersion::XChaCha20Poly1305;
+        let cert = certificate(es_version, &provider_key());
+        let addr = spawn_resolver(cert, es_version, true).await;
+
+        let conn = client(addr).create().await.unwrap();
+        let resp = conn.query(&query()).await.unwrap();
+        assert!(!resp.header().tc());
+        assert_eq!(resp.header_counts().ancount(), 1);
+    }
+
+    #[tokio::test]
+    async fn reject_forged_certificate() {
+        let es_version = EsVersion::XSalsa20Poly1305;
+        let cert = certificate(es_version, &SigningKey::from_bytes(&[3; 32]));
+        let addr = spawn_resolver(cert, es_version, false).await;
+
+        assert!(client(addr).create().await.is_err());
+    }
+}
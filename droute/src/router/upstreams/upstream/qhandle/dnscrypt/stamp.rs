@@ -0,0 +1,159 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::super::{QHandleError, Result};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+// Protocol identifier of DNSCrypt stamps
+const PROTOCOL_DNSCRYPT: u8 = 0x01;
+
+// Port used if the stamp doesn't specify one
+const DEFAULT_PORT: u16 = 443;
+
+/// A DNSCrypt server stamp (`sdns://...`), as specified in <https://dnscrypt.info/stamps-specifications>
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stamp {
+    /// Address of the resolver
+    pub addr: SocketAddr,
+    /// Ed25519 public key of the provider, which signs the certificates
+    pub provider_pk: [u8; 32],
+    /// Provider name, e.g. `2.dnscrypt-cert.example.com`
+    pub provider_name: String,
+}
+
+// Take a length-prefixed field
+fn take_lp<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let (&len, rest) = data.split_first()?;
+    let len = usize::from(len);
+    if rest.len() < len {
+        return None;
+    }
+    let (field, rest) = rest.split_at(len);
+    *data = rest;
+    Some(field)
+}
+
+// IPv6 addresses are enclosed in brackets, and the port is optional.
+fn parse_addr(addr: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = SocketAddr::from_str(addr) {
+        return Some(addr);
+    }
+    let ip = addr
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(addr);
+    Some(SocketAddr::new(IpAddr::from_str(ip).ok()?, DEFAULT_PORT))
+}
+
+impl FromStr for Stamp {
+    type Err = QHandleError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| QHandleError::InvalidStamp(format!("{}: {}", reason, s));
+
+        let data = s
+            .strip_prefix("sdns://")
+            .ok_or_else(|| invalid("missing the `sdns://` scheme"))?;
+        let data = base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| invalid("malformed base64"))?;
+
+        let (&protocol, data) = data.split_first().ok_or_else(|| invalid("empty stamp"))?;
+        if protocol != PROTOCOL_DNSCRYPT {
+            return Err(invalid("not a DNSCrypt stamp"));
+        }
+        // Properties (DNSSEC, no logs, no filter) are informational only.
+        let mut data = data.get(8..).ok_or_else(|| invalid("truncated stamp"))?;
+
+        let addr = take_lp(&mut data).ok_or_else(|| invalid("truncated stamp"))?;
+        let addr = std::str::from_utf8(addr)
+            .ok()
+            .and_then(parse_addr)
+            .ok_or_else(|| invalid("invalid resolver address"))?;
+
+        let provider_pk = take_lp(&mut data)
+            .and_then(|pk| pk.try_into().ok())
+            .ok_or_else(|| invalid("invalid provider public key"))?;
+
+        let provider_name = take_lp(&mut data)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| invalid("invalid provider name"))?
+            .to_string();
+
+        Ok(Self {
+            addr,
+            provider_pk,
+            provider_name,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::Stamp;
+    use crate::router::upstreams::upstream::qhandle::QHandleError;
+    use std::str::FromStr;
+
+    // Encode a DNSCrypt stamp, also used to point the client at the stub resolver in tests.
+    pub(crate) fn encode(addr: &str, pk: &[u8], name: &str) -> String {
+        let mut data = vec![0x01, 1, 0, 0, 0, 0, 0, 0, 0];
+        for field in [addr.as_bytes(), pk, name.as_bytes()] {
+            data.push(field.len() as u8);
+            data.extend_from_slice(field);
+        }
+        format!(
+            "sdns://{}",
+            base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn parse_stamp() {
+        let stamp = Stamp::from_str(&encode(
+            "127.0.0.1:8443",
+            &[7; 32],
+            "2.dnscrypt-cert.example.com",
+        ))
+        .unwrap();
+        assert_eq!(stamp.addr, "127.0.0.1:8443".parse().unwrap());
+        assert_eq!(stamp.provider_pk, [7; 32]);
+        assert_eq!(stamp.provider_name, "2.dnscrypt-cert.example.com");
+
+        // Port defaults to 443
+        let stamp =
+            Stamp::from_str(&encode("[::1]", &[7; 32], "2.dnscrypt-cert.example.com")).unwrap();
+        assert_eq!(stamp.addr, "[::1]:443".parse().unwrap());
+    }
+
+    #[test]
+    fn invalid_stamp() {
+        for stamp in [
+            "https://example.com".to_string(),
+            "sdns://!!!".to_string(),
+            // Public key too short
+            encode("127.0.0.1", &[7; 31], "2.dnscrypt-cert.example.com"),
+            encode("localhost", &[7; 32], "2.dnscrypt-cert.example.com"),
+            encode("127.0.0.1", &[7; 32], ""),
+        ] {
+            assert!(matches!(
+                Stamp::from_str(&stamp),
+                Err(QHandleError::InvalidStamp(_))
+            ));
+        }
+    }
+}
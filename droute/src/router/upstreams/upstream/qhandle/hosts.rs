@@ -0,0 +1,594 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{QHandle, QHandleError, Result};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use domain::base::{
+    iana::{Class, Rcode},
+    rdata::UnknownRecordData,
+    Dname, Message, MessageBuilder, Rtype,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+// The hosts files are checked for modification once in this period.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+// Maximum number of CNAME records to follow in a chain
+const MAX_CHAIN: usize = 8;
+
+// Records are indexed by the lowercase names, as names are compared case-insensitively.
+fn key(name: &Dname<Bytes>) -> String {
+    name.to_string().to_ascii_lowercase()
+}
+
+// Name of the PTR record for the address, e.g. `1.0.0.127.in-addr.arpa`
+fn reverse_name(addr: IpAddr) -> Dname<Bytes> {
+    let mut name = String::with_capacity(72);
+    match addr {
+        IpAddr::V4(addr) => {
+            for octet in addr.octets().iter().rev() {
+                write!(name, "{}.", octet).unwrap();
+            }
+            name.push_str("in-addr.arpa");
+        }
+        IpAddr::V6(addr) => {
+            for octet in addr.octets().iter().rev() {
+                write!(name, "{:x}.{:x}.", octet & 0xf, octet >> 4).unwrap();
+            }
+            name.push_str("ip6.arpa");
+        }
+    }
+    Dname::from_str(&name).unwrap()
+}
+
+fn parse_name(s: &str) -> std::result::Result<Dname<Bytes>, String> {
+    Dname::from_str(s).map_err(|_| format!("invalid domain name `{}`", s))
+}
+
+// Data of a record to be put into the table
+enum Data {
+    Addr(IpAddr),
+    Cname(Dname<Bytes>),
+    Ptr(Dname<Bytes>),
+    Txt(String),
+}
+
+/// Type of a static record
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum StaticType {
+    /// IPv4 address
+    A,
+    /// IPv6 address
+    Aaaa,
+    /// Alias to another name
+    Cname,
+    /// Text
+    Txt,
+    /// Name of an address
+    Ptr,
+}
+
+/// A record answered by the static upstream
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StaticRecord {
+    /// Owner of the record, e.g. `router.lan`. For PTR records, the IP address can be given in place of the reverse name.
+    pub name: String,
+    /// Type of the record
+    #[serde(rename = "type")]
+    pub rtype: StaticType,
+    /// The address for A and AAAA records, the domain name for CNAME and PTR records, or the text for TXT records
+    pub value: String,
+    /// TTL of the record. The default one of the upstream is used if omitted.
+    pub ttl: Option<u32>,
+}
+
+impl StaticRecord {
+    fn parse(&self, default_ttl: u32) -> std::result::Result<(Dname<Bytes>, u32, Data), String> {
+        let ttl = self.ttl.unwrap_or(default_ttl);
+        let (name, data) = match self.rtype {
+            StaticType::A => (
+                parse_name(&self.name)?,
+                Data::Addr(IpAddr::V4(
+                    self.value
+                        .parse::<Ipv4Addr>()
+                        .map_err(|_| format!("invalid IPv4 address `{}`", self.value))?,
+                )),
+            ),
+            StaticType::Aaaa => (
+                parse_name(&self.name)?,
+                Data::Addr(IpAddr::V6(
+                    self.value
+                        .parse::<Ipv6Addr>()
+                        .map_err(|_| format!("invalid IPv6 address `{}`", self.value))?,
+                )),
+            ),
+            StaticType::Cname => (
+                parse_name(&self.name)?,
+                Data::Cname(parse_name(&self.value)?),
+            ),
+            StaticType::Txt => (parse_name(&self.name)?, Data::Txt(self.value.clone())),
+            StaticType::Ptr => (
+                match self.name.parse() {
+                    Ok(addr) => reverse_name(addr),
+                    Err(_) => parse_name(&self.name)?,
+                },
+                Data::Ptr(parse_name(&self.value)?),
+            ),
+        };
+        Ok((name, ttl, data))
+    }
+}
+
+struct Rr {
+    rtype: Rtype,
+    ttl: u32,
+    data: Bytes,
+    // Target of CNAME records
+    target: Option<Dname<Bytes>>,
+}
+
+/// A table of records answered locally, with the PTR records of the addresses generated.
+pub struct Table {
+    records: HashMap<String, Vec<Rr>>,
+}
+
+impl Table {
+    fn new(entries: Vec<(Dname<Bytes>, u32, Data)>) -> std::result::Result<Self, String> {
+        let mut records: HashMap<String, Vec<Rr>> = HashMap::new();
+        // Addresses to generate the PTR records for, in the order they appear
+        let mut addrs = Vec::new();
+
+        for (name, ttl, data) in entries {
+            let (rtype, data, target) = match data {
+                Data::Addr(addr) => {
+                    addrs.push((addr, name.clone(), ttl));
+                    match addr {
+                        IpAddr::V4(addr) => {
+                            (Rtype::A, Bytes::copy_from_slice(&addr.octets()), None)
+                        }
+                        IpAddr::V6(addr) => {
+                            (Rtype::Aaaa, Bytes::copy_from_slice(&addr.octets()), None)
+                        }
+                    }
+                }
+                Data::Cname(target) => (
+                    Rtype::Cname,
+                    Bytes::copy_from_slice(target.as_slice()),
+                    Some(target),
+                ),
+                Data::Ptr(target) => (Rtype::Ptr, Bytes::copy_from_slice(target.as_slice()), None),
+                Data::Txt(text) => {
+                    // Long texts are split into multiple character strings.
+                    let mut buf = BytesMut::with_capacity(text.len() + text.len() / 255 + 1);
+                    for chunk in text.as_bytes().chunks(255) {
+                        buf.put_u8(chunk.len() as u8);
+                        buf.put_slice(chunk);
+                    }
+                    if text.is_empty() {
+                        buf.put_u8(0);
+                    }
+                    (Rtype::Txt, buf.freeze(), None)
+                }
+            };
+
+            let rrs = records.entry(key(&name)).or_default();
+            // CNAME records cannot coexist with any other data (RFC 1034, 3.6.2)
+            if !rrs.is_empty()
+                && (rtype == Rtype::Cname || rrs.iter().any(|rr| rr.rtype == Rtype::Cname))
+            {
+                return Err(format!("{} has CNAME and other data", name));
+            }
+            // Duplicate records are dropped, as in hosts files listing a name more than once.
+            if !rrs.iter().any(|rr| rr.rtype == rtype && rr.data == data) {
+                rrs.push(Rr {
+                    rtype,
+                    ttl,
+                    data,
+                    target,
+                });
+            }
+        }
+
+        // The first name of an address is used, unless PTR records are given for it.
+        for (addr, name, ttl) in addrs {
+            let rrs = records.entry(key(&reverse_name(addr))).or_default();
+            if rrs.is_empty() {
+                rrs.push(Rr {
+                    rtype: Rtype::Ptr,
+                    ttl,
+                    data: Bytes::copy_from_slice(name.as_slice()),
+                    target: None,
+                });
+            }
+        }
+
+        Ok(Self { records })
+    }
+
+    /// Create the table from the static records, with the TTL used if the record doesn't specify one.
+    pub fn from_static(records: &[StaticRecord], default_ttl: u32) -> Result<Self> {
+        records
+            .iter()
+            .map(|r| {
+                r.parse(default_ttl)
+                    .map_err(|e| format!("{} {:?}: {}", r.name, r.rtype, e))
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .and_then(Self::new)
+            .map_err(QHandleError::InvalidRecord)
+    }
+
+    // Lines in the hosts file are like `127.0.0.1 localhost localhost.localdomain # comment`.
+    // Invalid lines are skipped, as glibc does.
+    fn from_hosts(text: &str, ttl: u32) -> Self {
+        let mut entries = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap();
+            let mut fields = line.split_whitespace();
+            let addr = match fields.next() {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let addr = match addr.parse() {
+                Ok(addr) => addr,
+                Err(_) => {
+                    log::warn!("skipping invalid address in hosts file: {}", addr);
+                    continue;
+                }
+            };
+            for name in fields {
+                match parse_name(name) {
+                    Ok(name) => entries.push((name, ttl, Data::Addr(addr))),
+                    Err(e) => log::warn!("skipping {} in hosts file", e),
+                }
+            }
+        }
+        // Hosts files have no CNAME records, and therefore no conflict.
+        Self::new(entries).unwrap()
+    }
+
+    fn lookup(&self, mut name: Dname<Bytes>, qtype: Rtype) -> (Rcode, Vec<(Dname<Bytes>, &Rr)>) {
+        let mut answers = Vec::new();
+        let mut rcode = Rcode::NXDomain;
+        for _ in 0..MAX_CHAIN {
+            let rrs = match self.records.get(&key(&name)) {
+                Some(rrs) => rrs,
+                // The target of the CNAME may be answered by others.
+                None => break,
+            };
+            rcode = Rcode::NoError;
+
+            if let Some(cname) = rrs.iter().find(|rr| rr.rtype == Rtype::Cname) {
+                if qtype != Rtype::Cname && qtype != Rtype::Any {
+                    answers.push((name, cname));
+                    name = cname.target.clone().unwrap();
+                    continue;
+                }
+            }
+
+            answers.extend(
+                rrs.iter()
+                    .filter(|rr| qtype == Rtype::Any || rr.rtype == qtype)
+                    .map(|rr| (name.clone(), rr)),
+            );
+            break;
+        }
+        (rcode, answers)
+    }
+}
+
+#[async_trait]
+impl QHandle for Table {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?;
+        let question = match msg.first_question() {
+            Some(q) => q,
+            None => return Ok(builder.start_answer(msg, Rcode::FormErr)?.into_message()),
+        };
+        let name = match Dname::from_str(&question.qname().to_string()) {
+            Ok(name) if matches!(question.qclass(), Class::In | Class::Any) => name,
+            _ => return Ok(builder.start_answer(msg, Rcode::Refused)?.into_message()),
+        };
+
+        // Names not in the table are NXDOMAIN, and names without records of the type are NODATA.
+        let (rcode, answers) = self.lookup(name, question.qtype());
+        let mut answer = builder.start_answer(msg, rcode)?;
+        for (owner, rr) in answers {
+            answer.push((
+                &owner,
+                rr.ttl,
+                UnknownRecordData::from_octets(rr.rtype, rr.data.clone()),
+            ))?;
+        }
+        Ok(answer.into_message())
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+}
+
+async fn read_hosts(files: &[PathBuf], ttl: u32) -> Result<Table> {
+    let mut text = String::new();
+    for file in files {
+        text.push_str(&tokio::fs::read_to_string(file).await?);
+        text.push('\n');
+    }
+    Ok(Table::from_hosts(&text, ttl))
+}
+
+// Modification time of the files, or None if the file is missing.
+async fn modified(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::with_capacity(files.len());
+    for file in files {
+        times.push(
+            tokio::fs::metadata(file)
+                .await
+                .and_then(|m| m.modified())
+                .ok(),
+        );
+    }
+    times
+}
+
+/// Records from hosts files, which are reloaded once the files are modified.
+pub struct Hosts {
+    files: Vec<PathBuf>,
+    ttl: u32,
+    table: RwLock<Arc<Table>>,
+}
+
+impl Hosts {
+    /// Load the hosts files, with the TTL given to all the records.
+    pub async fn load(files: Vec<PathBuf>, ttl: u32) -> Result<Arc<Self>> {
+        let times = modified(&files).await;
+        let hosts = Arc::new(Self {
+            table: RwLock::new(Arc::new(read_hosts(&files, ttl).await?)),
+            files,
+            ttl,
+        });
+        tokio::spawn(Self::watch(Arc::downgrade(&hosts), times));
+        Ok(hosts)
+    }
+
+    // Reload the files on modification, until the upstream is dropped.
+    async fn watch(hosts: Weak<Self>, mut times: Vec<Option<SystemTime>>) {
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+            let hosts = match hosts.upgrade() {
+                Some(hosts) => hosts,
+                None => break,
+            };
+            let now = modified(&hosts.files).await;
+            if now == times {
+                continue;
+            }
+            times = now;
+            match read_hosts(&hosts.files, hosts.ttl).await {
+                Ok(table) => {
+                    *hosts.table.write().unwrap() = Arc::new(table);
+                    log::info!("hosts files reloaded");
+                }
+                Err(e) => log::warn!(
+                    "failed to reload hosts files, keeping the old records: {}",
+                    e
+                ),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for Hosts {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let table = self.table.read().unwrap().clone();
+        table.query(msg).await
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reverse_name, Hosts, QHandle, StaticRecord, StaticType, Table};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, ParsedDname, Rtype},
+        rdata::{Ptr, A},
+    };
+    use std::{
+        net::Ipv4Addr,
+        str::FromStr,
+        time::{Duration, SystemTime},
+    };
+
+    fn query(name: &str, qtype: Rtype) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), qtype))
+            .unwrap();
+        Message::from_octets(builder.into_message().into_octets().freeze()).unwrap()
+    }
+
+    fn answer_types(msg: &Message<Bytes>) -> Vec<(String, Rtype)> {
+        msg.answer()
+            .unwrap()
+            .map(|r| {
+                let r = r.unwrap();
+                (r.owner().to_string(), r.rtype())
+            })
+            .collect()
+    }
+
+    fn addrs(msg: &Message<Bytes>) -> Vec<Ipv4Addr> {
+        msg.answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|r| r.unwrap().data().addr())
+            .collect()
+    }
+
+    fn ptrs(msg: &Message<Bytes>) -> Vec<String> {
+        msg.answer()
+            .unwrap()
+            .limit_to::<Ptr<ParsedDname<&Bytes>>>()
+            .map(|r| r.unwrap().data().ptrdname().to_string())
+            .collect()
+    }
+
+    fn record(name: &str, rtype: StaticType, value: &str) -> StaticRecord {
+        StaticRecord {
+            name: name.to_string(),
+            rtype,
+            value: value.to_string(),
+            ttl: None,
+        }
+    }
+
+    #[test]
+    fn reverse() {
+        assert_eq!(
+            reverse_name("192.168.1.2".parse().unwrap()).to_string(),
+            "2.1.168.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name("2001:db8::1".parse().unwrap()).to_string(),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    #[tokio::test]
+    async fn hosts_file() {
+        let table = Table::from_hosts(
+            "# comment\n\
+             127.0.0.1 localhost localhost.localdomain\n\
+             ::1 localhost # IPv6\n\
+             192.168.1.2\tnas.lan NAS\n\
+             192.168.1.3 nas.lan\n\
+             fe80::1%lo0 invalid\n",
+            60,
+        );
+
+        let resp = table.query(&query("NAS.lan", Rtype::A)).await.unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(
+            addrs(&resp),
+            vec![Ipv4Addr::new(192, 168, 1, 2), Ipv4Addr::new(192, 168, 1, 3)]
+        );
+        assert_eq!(
+            answer_types(&table.query(&query("localhost", Rtype::Aaaa)).await.unwrap()),
+            vec![("localhost".to_string(), Rtype::Aaaa)]
+        );
+
+        // PTR records are generated for the first name of each address.
+        let resp = table
+            .query(&query("2.1.168.192.in-addr.arpa", Rtype::Ptr))
+            .await
+            .unwrap();
+        assert_eq!(ptrs(&resp), vec!["nas.lan".to_string()]);
+        let resp = table
+            .query(&query("1.0.0.127.in-addr.arpa", Rtype::Ptr))
+            .await
+            .unwrap();
+        assert_eq!(ptrs(&resp), vec!["localhost".to_string()]);
+
+        // NODATA
+        let resp = table.query(&query("nas.lan", Rtype::Aaaa)).await.unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(resp.header_counts().ancount(), 0);
+
+        let resp = table.query(&query("invalid", Rtype::A)).await.unwrap();
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+    }
+
+    #[tokio::test]
+    async fn static_records() {
+        let table = Table::from_static(
+            &[
+                record("router.lan", StaticType::A, "192.168.1.1"),
+                record("www.lan", StaticType::Cname, "router.lan"),
+                record("router.lan", StaticType::Txt, "hello"),
+                // The PTR record given takes precedence over the generated one.
+                record("192.168.1.1", StaticType::Ptr, "gateway.lan"),
+            ],
+            300,
+        )
+        .unwrap();
+
+        let resp = table.query(&query("www.lan", Rtype::A)).await.unwrap();
+        assert_eq!(
+            answer_types(&resp),
+            vec![
+                ("www.lan".to_string(), Rtype::Cname),
+                ("router.lan".to_string(), Rtype::A),
+            ]
+        );
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(192, 168, 1, 1)]);
+        assert!(resp.answer().unwrap().all(|r| r.unwrap().ttl() == 300));
+
+        let resp = table
+            .query(&query("1.1.168.192.in-addr.arpa", Rtype::Ptr))
+            .await
+            .unwrap();
+        assert_eq!(ptrs(&resp), vec!["gateway.lan".to_string()]);
+
+        for records in [
+            vec![record("router.lan", StaticType::A, "192.168.1")],
+            vec![record("router.lan", StaticType::Aaaa, "192.168.1.1")],
+            vec![
+                record("www.lan", StaticType::Cname, "router.lan"),
+                record("www.lan", StaticType::A, "192.168.1.1"),
+            ],
+        ] {
+            assert!(Table::from_static(&records, 300).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn reload_on_modification() {
+        let path = std::env::temp_dir().join(format!("dcompass-hosts-{}", std::process::id()));
+        std::fs::write(&path, "192.168.1.2 nas.lan\n").unwrap();
+        let hosts = Hosts::load(vec![path.clone()], 60).await.unwrap();
+        let resp = hosts.query(&query("nas.lan", Rtype::A)).await.unwrap();
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(192, 168, 1, 2)]);
+
+        let file = std::fs::File::create(&path).unwrap();
+        std::io::Write::write_all(&file, b"192.168.1.3 nas.lan\n").unwrap();
+        // Make sure the change is seen even if the file system has a coarse timestamp.
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        drop(file);
+
+        tokio::time::sleep(super::RELOAD_INTERVAL + Duration::from_secs(1)).await;
+        let resp = hosts.query(&query("nas.lan", Rtype::A)).await.unwrap();
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(192, 168, 1, 3)]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
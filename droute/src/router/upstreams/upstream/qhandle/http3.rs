@@ -0,0 +1,334 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{
+    https::{DohRequest, APP_USER_AGENT},
+    quic, QHandle, QHandleError, Result,
+};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use domain::base::Message;
+use futures::future::poll_fn;
+use h3::client::SendRequest;
+use h3_quinn::OpenStreams;
+use http::{header::USER_AGENT, HeaderValue, Request};
+use log::debug;
+use quinn::{ClientConfig, Connection, Endpoint};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::Mutex, time::timeout};
+
+// ALPN token of HTTP/3
+const ALPN_H3: &[u8] = b"h3";
+
+// Same as the connect timeout of the HTTP/2 client, after which we may fall back to it.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Whether to send DNS over HTTPS queries over HTTP/3
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Http3Mode {
+    /// Use HTTP/2 only
+    Disabled,
+    /// Try HTTP/3 first, and fall back to HTTP/2 if the server cannot be reached over QUIC
+    Prefer,
+    /// Use HTTP/3 only
+    Force,
+}
+
+impl Default for Http3Mode {
+    fn default() -> Self {
+        Self::Disabled
+    }
+}
+
+/// A long-lived HTTP/3 connection to a DoH server, re-established once it fails.
+pub struct Http3 {
+    endpoint: Endpoint,
+    addr: SocketAddr,
+    domain: String,
+    request: Arc<DohRequest>,
+    conn: Mutex<Option<Http3Conn>>,
+}
+
+impl Http3 {
+    /// `tls` is the TLS configuration of the HTTP/2 client, on which we only change the ALPN.
+    pub fn new(
+        request: Arc<DohRequest>,
+        domain: &str,
+        addr: SocketAddr,
+        mut tls: rustls::ClientConfig,
+    ) -> Result<Self> {
+        tls.alpn_protocols = vec![ALPN_H3.to_vec()];
+        Ok(Self {
+            endpoint: quic::endpoint(addr, ClientConfig::new(Arc::new(tls)))?,
+            addr,
+            domain: domain.to_string(),
+            request,
+            conn: Mutex::new(None),
+        })
+    }
+
+    // Get the connection alive, or connect again.
+    pub async fn connect(&self) -> std::io::Result<Http3Conn> {
+        let mut guard = self.conn.lock().await;
+        if let Some(conn) = guard.as_ref() {
+            if conn.conn.close_reason().is_none() {
+                return Ok(conn.clone());
+            }
+            debug!("HTTP/3 connection lost, reconnecting");
+        }
+
+        let connecting = self
+            .endpoint
+            .connect(self.addr, &self.domain)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let conn = timeout(CONNECT_TIMEOUT, connecting).await??;
+        let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(conn.clone()))
+            .await
+            .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e))?;
+        // The driver has to be polled for the connection to make progress. It finishes once the connection is closed.
+        tokio::spawn(async move {
+            if let Err(e) = poll_fn(|cx| driver.poll_close(cx)).await {
+                debug!("HTTP/3 connection closed: {}", e);
+            }
+        });
+
+        let conn = Http3Conn {
+            conn,
+            send_request,
+            request: self.request.clone(),
+        };
+        *guard = Some(conn.clone());
+        Ok(conn)
+    }
+}
+
+/// A handle to the shared HTTP/3 connection, on which each query takes a request stream of its own.
+#[derive(Clone)]
+pub struct Http3Conn {
+    conn: Connection,
+    send_request: SendRequest<OpenStreams, Bytes>,
+    request: Arc<DohRequest>,
+}
+
+// Errors from h3 are all about the connection and streams.
+fn h3_error(e: h3::Error) -> QHandleError {
+    Error::new(ErrorKind::Other, e).into()
+}
+
+#[async_trait]
+impl QHandle for Http3Conn {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        // Per RFC, the message ID should be set to 0 to better facilitate HTTPS caching.
+        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+        msg.header_mut().set_id(0);
+
+        let (mut parts, body) = self.request.build(msg.into_octets().freeze())?.into_parts();
+        parts
+            .headers
+            .entry(USER_AGENT)
+            .or_insert(HeaderValue::from_static(APP_USER_AGENT));
+
+        let mut send_request = self.send_request.clone();
+        let mut stream = send_request
+            .send_request(Request::from_parts(parts, ()))
+            .await
+            .map_err(h3_error)?;
+        if !body.is_empty() {
+            stream.send_data(body).await.map_err(h3_error)?;
+        }
+        stream.finish().await.map_err(h3_error)?;
+
+        let res = stream.recv_response().await.map_err(h3_error)?;
+        if !res.status().is_success() {
+            return Err(QHandleError::FailedHttp(res.status()));
+        }
+
+        let mut body = BytesMut::new();
+        while let Some(chunk) = stream.recv_data().await.map_err(h3_error)? {
+            body.extend_from_slice(chunk.chunk());
+        }
+        Ok(Message::from_octets(body.freeze())?)
+    }
+
+    async fn reusable(&self) -> deadpool::managed::RecycleResult<std::io::Error> {
+        match self.conn.close_reason() {
+            Some(e) => Err(Error::from(e).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::https::{DohMethod, DohRequest},
+        Http3, QHandle, ALPN_H3,
+    };
+    use bytes::{Buf, Bytes, BytesMut};
+    use domain::base::{Dname, Message, MessageBuilder, Rcode, Rtype};
+    use http::{Method, Response, StatusCode};
+    use quinn::{Endpoint, ServerConfig};
+    use reqwest::Url;
+    use rustls::{Certificate, PrivateKey, RootCertStore};
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    // An h3 stub answering DoH requests carrying the token with an empty NOERROR response, and the others with HTTP 403. Returns the number of connections accepted.
+    fn spawn_server() -> (SocketAddr, rustls::ClientConfig, Arc<AtomicUsize>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_der = Certificate(cert.serialize_der().unwrap());
+        let mut roots = RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+        let client = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der], PrivateKey(cert.serialize_private_key_der()))
+            .unwrap();
+        crypto.alpn_protocols = vec![ALPN_H3.to_vec()];
+        let endpoint = Endpoint::server(
+            ServerConfig::with_crypto(Arc::new(crypto)),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let conn = connecting.await.unwrap();
+                tokio::spawn(async move {
+                    let mut conn =
+                        h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn))
+                            .await
+                            .unwrap();
+                    while let Ok(Some((req, mut stream))) = conn.accept().await {
+                        // Failures are reported to the client rather than panicking in the task.
+                        if req.uri().path() != "/dns-query"
+                            || req.headers().get("x-token").map_or(true, |v| v != "secret")
+                        {
+                            stream
+                                .send_response(
+                                    Response::builder()
+                                        .status(StatusCode::FORBIDDEN)
+                                        .body(())
+                                        .unwrap(),
+                                )
+                                .await
+                                .unwrap();
+                            stream.finish().await.unwrap();
+                            continue;
+                        }
+                        let mut body = BytesMut::new();
+                        while let Some(chunk) = stream.recv_data().await.unwrap() {
+                            body.extend_from_slice(chunk.chunk());
+                        }
+                        if req.method() == Method::GET {
+                            let param = req.uri().query().unwrap().strip_prefix("dns=").unwrap();
+                            body = base64::decode_config(param, base64::URL_SAFE_NO_PAD)
+                                .unwrap()
+                                .as_slice()
+                                .into();
+                        }
+                        let query = Message::from_octets(body.freeze()).unwrap();
+                        let resp = MessageBuilder::from_target(BytesMut::new())
+                            .unwrap()
+                            .start_answer(&query, Rcode::NoError)
+                            .unwrap()
+                            .finish();
+                        stream
+                            .send_response(
+                                Response::builder()
+                                    .status(StatusCode::OK)
+                                    .header("content-type", "application/dns-message")
+                                    .body(())
+                                    .unwrap(),
+                            )
+                            .await
+                            .unwrap();
+                        stream.send_data(resp.freeze()).await.unwrap();
+                        stream.finish().await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, client, connections)
+    }
+
+    fn query() -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        Message::from_octets(builder.into_message().into_octets().freeze()).unwrap()
+    }
+
+    async fn query_over_h3(method: DohMethod) {
+        let (addr, tls, connections) = spawn_server();
+        let request = DohRequest::new(
+            Url::parse(&format!("https://localhost:{}/dns-query", addr.port())).unwrap(),
+            method,
+            HashMap::from([("X-Token".to_string(), "secret".to_string())]),
+        )
+        .unwrap();
+        let http3 = Http3::new(Arc::new(request), "localhost", addr, tls).unwrap();
+
+        // Requests are multiplexed over the same connection.
+        for _ in 0..3 {
+            let resp = http3
+                .connect()
+                .await
+                .unwrap()
+                .query(&query())
+                .await
+                .unwrap();
+            assert_eq!(resp.header().rcode(), Rcode::NoError);
+            assert!(resp.header().qr());
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn post_over_h3() {
+        query_over_h3(DohMethod::Post).await
+    }
+
+    #[tokio::test]
+    async fn get_over_h3() {
+        query_over_h3(DohMethod::Get).await
+    }
+}
@@ -13,153 +13,434 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+// The TLS configuration handed over to reqwest.
 #[cfg(feature = "doh-rustls")]
-mod rustls_cfgs {
-    use once_cell::sync::Lazy;
-    use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
-
-    pub static NO_SNI_CLIENT_CFG: Lazy<ClientConfig> = Lazy::new(|| create_client_config(&false));
-    pub static CLIENT_CFG: Lazy<ClientConfig> = Lazy::new(|| create_client_config(&true));
-
-    fn create_client_config(sni: &bool) -> ClientConfig {
-        let mut root_store = RootCertStore::empty();
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-            OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-
-        let mut client_config = ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        client_config.enable_sni = *sni; // Disable SNI on need.
-
-        client_config
-    }
-}
-
+type TlsConfig = rustls::ClientConfig;
 #[cfg(feature = "doh-native-tls")]
-mod native_tls_cfgs {
-    use native_tls::TlsConnector;
-    use once_cell::sync::Lazy;
-
-    pub static NO_SNI_CLIENT_CFG: Lazy<TlsConnector> =
-        Lazy::new(|| TlsConnector::builder().use_sni(false).build().unwrap());
-    pub static CLIENT_CFG: Lazy<TlsConnector> = Lazy::new(|| TlsConnector::new().unwrap());
-}
+type TlsConfig = native_tls::TlsConnector;
 
+// Negotiate HTTP/2 so that queries are multiplexed over the same connection.
 #[cfg(feature = "doh-rustls")]
-use rustls_cfgs::{CLIENT_CFG, NO_SNI_CLIENT_CFG};
+fn tls_config(tls: &TlsSettings, sni: bool) -> Result<TlsConfig> {
+    let mut client_config = tls.rustls_config(sni)?;
+    client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(client_config)
+}
 
+// Negotiate HTTP/2 so that queries are multiplexed over the same connection.
+// Pins cannot be checked before the requests are sent with reqwest on native-tls.
 #[cfg(feature = "doh-native-tls")]
-use native_tls_cfgs::{CLIENT_CFG, NO_SNI_CLIENT_CFG};
+fn tls_config(tls: &TlsSettings, sni: bool) -> Result<TlsConfig> {
+    if !tls.spki_pins.is_empty() {
+        return Err(QHandleError::InvalidTlsSettings(
+            "SPKI pinning is not supported for DoH with native-tls".to_string(),
+        ));
+    }
+    Ok(tls
+        .native_tls_builder(sni)?
+        .request_alpns(&["h2", "http/1.1"])
+        .build()?)
+}
 
-use super::{ConnInitiator, QHandle, QHandleError, Result};
+#[cfg(feature = "doh3-rustls")]
+use super::http3::{Http3, Http3Conn, Http3Mode};
+use super::{tls_settings::TlsSettings, ConnInitiator, QHandle, QHandleError, Result};
+use crate::dnstap::SocketProtocol;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use domain::base::Message;
-use reqwest::{Client, Proxy, Url};
+use http::Request;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE},
+    Client, Method, Proxy, Url,
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    net::{IpAddr, SocketAddr},
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     str::FromStr,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
+// Media type of DNS messages in DoH (RFC 8484, 6)
+const DNS_MESSAGE: &str = "application/dns-message";
+
+// Idle connections are closed after this period, as servers close them at some point anyway.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// The route to the server is checked for network changes at most once in this period.
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// HTTP method used to send DoH queries
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DohMethod {
+    /// Send the message as the request body
+    Post,
+    /// Send the message in the `dns` query parameter in base64url, which is friendlier to HTTP caches
+    Get,
+}
+
+impl Default for DohMethod {
+    fn default() -> Self {
+        Self::Post
+    }
+}
+
+// How queries are turned into DoH requests, shared by the HTTP/2 and HTTP/3 clients.
+pub struct DohRequest {
+    uri: Url,
+    method: DohMethod,
+    headers: HeaderMap,
+}
+
+impl DohRequest {
+    pub(super) fn new(
+        uri: Url,
+        method: DohMethod,
+        headers: HashMap<String, String>,
+    ) -> Result<Self> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| QHandleError::InvalidHeader(name.clone()))?;
+            let mut value = HeaderValue::from_str(&value)
+                .map_err(|_| QHandleError::InvalidHeader(name.to_string()))?;
+            // Values may carry credentials. Sensitive values are redacted whenever headers are logged.
+            value.set_sensitive(true);
+            map.insert(name, value);
+        }
+        Ok(Self {
+            uri,
+            method,
+            headers: map,
+        })
+    }
+
+    // Build the request carrying the message, whose ID should have been set to 0. The body is empty for GET requests.
+    pub fn build(&self, msg: Bytes) -> Result<Request<Bytes>> {
+        let (method, uri, body) = match self.method {
+            DohMethod::Post => (Method::POST, self.uri.clone(), msg),
+            DohMethod::Get => {
+                let mut uri = self.uri.clone();
+                uri.query_pairs_mut()
+                    .append_pair("dns", &base64::encode_config(&msg, base64::URL_SAFE_NO_PAD));
+                (Method::GET, uri, Bytes::new())
+            }
+        };
+
+        let mut req = Request::builder()
+            .method(method.clone())
+            .uri(uri.as_str())
+            .header(ACCEPT, DNS_MESSAGE);
+        if method == Method::POST {
+            req = req.header(CONTENT_TYPE, DNS_MESSAGE);
+        }
+        let mut req = req
+            .body(body)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        // Custom headers take precedence over ours.
+        req.headers_mut().extend(self.headers.clone());
+
+        log::debug!(
+            "DoH {} request to {} with headers {:?}",
+            method,
+            self.uri,
+            req.headers()
+        );
+        Ok(req)
+    }
+}
+
+/// How the client talks to the server, on top of where the server is
+pub struct HttpsSettings {
+    /// Whether to send SNI
+    pub sni: bool,
+    /// Trust anchors, client certificate, and SPKI pins
+    pub tls: TlsSettings,
+    /// HTTP method to send queries with
+    pub method: DohMethod,
+    /// Extra HTTP headers sent with every query
+    pub headers: HashMap<String, String>,
+    /// Whether to send queries over HTTP/3
+    #[cfg(feature = "doh3-rustls")]
+    pub http3: Http3Mode,
+}
+
 /// Client instance for HTTPS connections
 #[derive(Clone)]
 pub struct Https {
-    client: PostClient,
+    client: Http2Client,
+    addr: SocketAddr,
+    #[cfg(feature = "doh3-rustls")]
+    http3: Option<(Arc<Http3>, Http3Mode)>,
 }
 
-static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+pub static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
-impl Https {
-    /// Create a new HTTPS client creator instance. with the given remote server address.
-    // We *CANNOT* reuse the client *WITH* connection pool because if the network changes, *connection* inside client pool of each client remains the same, and cloning them inevitably leads to no reconnection but using stale connections.
-    // However, we are able to disable the connection pool and use the client.
-    // We cannot store ClientBuilder because it is not Clone.
-    pub async fn new(uri: String, addr: IpAddr, proxy: Option<String>, sni: bool) -> Result<Self> {
-        let uri = Url::from_str(&uri).map_err(|_| QHandleError::InvalidUri(uri))?;
-        // Check domain validness
-        let _ = uri
-            .domain()
-            .ok_or_else(|| QHandleError::InvalidDomain(uri.clone()))?;
+// Settings to (re)build the client from, as ClientBuilder is not Clone.
+struct ClientSettings {
+    domain: String,
+    addr: IpAddr,
+    proxy: Option<String>,
+    tls: TlsConfig,
+}
 
-        // This has already been checked and it is safe to unwrap
-        let domain = uri.domain().unwrap();
+impl ClientSettings {
+    fn build(&self) -> Result<Client> {
         let client = Client::builder()
             // The port in socket addr doesn't take effect here per documentation
-            .resolve(domain, SocketAddr::new(addr, 0))
-            .use_preconfigured_tls(if sni {
-                CLIENT_CFG.clone()
-            } else {
-                NO_SNI_CLIENT_CFG.clone()
-            })
+            .resolve(&self.domain, SocketAddr::new(self.addr, 0))
+            .use_preconfigured_tls(self.tls.clone())
             .https_only(true)
             .user_agent(APP_USER_AGENT)
             .connect_timeout(Duration::from_secs(3))
-            // Disable the inner connection pool
-            .pool_max_idle_per_host(0);
+            .pool_idle_timeout(IDLE_TIMEOUT);
 
         // Add proxy
-        let client = if let Some(proxy) = proxy {
+        let client = if let Some(proxy) = &self.proxy {
             client.proxy(Proxy::all(proxy)?)
         } else {
             client
         };
 
+        Ok(client.build().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "TLS backend failed to initialize",
+            )
+        })?)
+    }
+}
+
+// Local address on the route to the remote. Connecting a UDP socket doesn't send anything.
+fn local_ip(remote: SocketAddr) -> Option<IpAddr> {
+    let local: SocketAddr = if remote.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).ok()?;
+    socket.connect(remote).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+struct ClientState {
+    client: Client,
+    // Incremented every time the client is rebuilt
+    generation: u64,
+    // Local address on the route to the server as of the last check
+    local: Option<IpAddr>,
+    checked: Instant,
+}
+
+// The client shared by all the handles in the pool, whose connections are kept alive and multiplexed over HTTP/2.
+// Connections go stale once the network changes, and the client would keep using them. Therefore, the client along with its connections is rebuilt once a request fails in transport or the route to the server changes.
+// Requests cancelled (e.g. losers of a hybrid race) leave the connections alone, as other requests are multiplexed over them.
+pub struct SharedClient {
+    settings: ClientSettings,
+    remote: SocketAddr,
+    state: Mutex<ClientState>,
+}
+
+impl SharedClient {
+    fn new(settings: ClientSettings, remote: SocketAddr) -> Result<Self> {
         Ok(Self {
-            client: PostClient(
-                client.build().map_err(|_| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "TLS backend failed to initialize",
-                    )
-                })?,
-                uri.clone(),
-            ),
+            state: Mutex::new(ClientState {
+                client: settings.build()?,
+                generation: 0,
+                local: local_ip(remote),
+                checked: Instant::now(),
+            }),
+            settings,
+            remote,
+        })
+    }
+
+    fn get(&self) -> (Client, u64) {
+        let state = self.state.lock().unwrap();
+        (state.client.clone(), state.generation)
+    }
+
+    // Rebuild the client, unless it has been rebuilt since the `generation` given.
+    fn invalidate(&self, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        match self.settings.build() {
+            Ok(client) => {
+                log::debug!("dropping the HTTP connections to {}", self.remote);
+                state.client = client;
+                state.generation += 1;
+            }
+            Err(e) => log::warn!("failed to rebuild the HTTP client: {}", e),
+        }
+    }
+
+    fn check_network(&self) {
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            if state.checked.elapsed() < NETWORK_CHECK_INTERVAL {
+                return;
+            }
+            state.checked = Instant::now();
+            let local = local_ip(self.remote);
+            if local == state.local {
+                return;
+            }
+            log::info!(
+                "route to {} changed from {:?} to {:?}",
+                self.remote,
+                state.local,
+                local
+            );
+            state.local = local;
+            state.generation
+        };
+        self.invalidate(generation);
+    }
+}
+
+impl Https {
+    /// Create a new HTTPS client creator instance. with the given remote server address.
+    pub async fn new(
+        uri: String,
+        addr: IpAddr,
+        proxy: Option<String>,
+        settings: HttpsSettings,
+    ) -> Result<Self> {
+        let uri = Url::from_str(&uri).map_err(|_| QHandleError::InvalidUri(uri))?;
+        // Check domain validness
+        let _ = uri
+            .domain()
+            .ok_or_else(|| QHandleError::InvalidDomain(uri.clone()))?;
+
+        // This has already been checked and it is safe to unwrap
+        let domain = uri.domain().unwrap();
+
+        let remote = SocketAddr::new(addr, uri.port_or_known_default().unwrap_or(443));
+        let request = Arc::new(DohRequest::new(
+            uri.clone(),
+            settings.method,
+            settings.headers,
+        )?);
+        let tls = tls_config(&settings.tls, settings.sni)?;
+
+        // QUIC cannot be tunneled through the proxies we support.
+        #[cfg(feature = "doh3-rustls")]
+        let http3 = match (settings.http3, &proxy) {
+            (Http3Mode::Disabled, _) => None,
+            (Http3Mode::Force, Some(_)) => return Err(QHandleError::Http3OverProxy),
+            (Http3Mode::Prefer, Some(_)) => {
+                log::warn!("HTTP/3 is not used for DoH upstreams with a proxy");
+                None
+            }
+            (mode, None) => Some((
+                Arc::new(Http3::new(request.clone(), domain, remote, tls.clone())?),
+                mode,
+            )),
+        };
+
+        let settings = ClientSettings {
+            domain: domain.to_string(),
+            addr,
+            proxy,
+            tls,
+        };
+
+        Ok(Self {
+            client: Http2Client(Arc::new(SharedClient::new(settings, remote)?), request),
+            addr: remote,
+            #[cfg(feature = "doh3-rustls")]
+            http3,
         })
     }
 }
 
 #[async_trait]
 impl ConnInitiator for Https {
-    type Connection = PostClient;
+    type Connection = HttpsConn;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
-        Ok(self.client.clone())
+        #[cfg(feature = "doh3-rustls")]
+        if let Some((http3, mode)) = &self.http3 {
+            match http3.connect().await {
+                Ok(conn) => return Ok(HttpsConn::Http3(conn)),
+                Err(e) if *mode == Http3Mode::Prefer => {
+                    log::warn!(
+                        "failed to connect over HTTP/3, falling back to HTTP/2: {}",
+                        e
+                    )
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.client.0.check_network();
+        Ok(HttpsConn::Http2(self.client.clone()))
     }
 
     fn conn_type(&self) -> &'static str {
         "HTTPS"
     }
+
+    fn remote(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn protocol(&self) -> SocketProtocol {
+        SocketProtocol::Doh
+    }
+}
+
+pub enum HttpsConn {
+    Http2(Http2Client),
+    #[cfg(feature = "doh3-rustls")]
+    Http3(Http3Conn),
+}
+
+#[async_trait]
+impl QHandle for HttpsConn {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        match self {
+            Self::Http2(c) => c.query(msg).await,
+            #[cfg(feature = "doh3-rustls")]
+            Self::Http3(c) => c.query(msg).await,
+        }
+    }
+
+    async fn reusable(&self) -> deadpool::managed::RecycleResult<std::io::Error> {
+        match self {
+            Self::Http2(c) => c.reusable().await,
+            #[cfg(feature = "doh3-rustls")]
+            Self::Http3(c) => c.reusable().await,
+        }
+    }
 }
 
 #[derive(Clone)]
-pub struct PostClient(Client, Url);
+pub struct Http2Client(Arc<SharedClient>, Arc<DohRequest>);
 
 #[async_trait]
-impl QHandle for PostClient {
+impl QHandle for Http2Client {
     async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
         // Per RFC, the message ID should be set to 0 to better facilitate HTTPS caching.
         let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
         msg.header_mut().set_id(0);
 
-        let body: reqwest::Body = msg.into_octets().freeze().into();
-        let res = self
-            .0
-            .post(self.1.clone())
-            .header("content-type", "application/dns-message")
-            .body(body)
-            .send()
-            .await?;
+        let (client, generation) = self.0.get();
+        // Connections are dropped on transport errors only. The connection is fine if the server has answered, even unsuccessfully.
+        let invalidate = |e: reqwest::Error| {
+            self.0.invalidate(generation);
+            QHandleError::from(e)
+        };
+
+        let req = reqwest::Request::try_from(self.1.build(msg.into_octets().freeze())?)?;
+        let res = client.execute(req).await.map_err(invalidate)?;
 
         if res.status().is_success() {
-            let res = res.bytes().await?;
+            let res = res.bytes().await.map_err(invalidate)?;
             let answer = Message::from_octets(res)?;
             Ok(answer)
         } else {
@@ -168,6 +449,154 @@ impl QHandle for PostClient {
     }
 
     async fn reusable(&self) -> deadpool::managed::RecycleResult<std::io::Error> {
+        self.0.check_network();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        tls_config, ClientSettings, DohMethod, DohRequest, Http2Client, QHandle, SharedClient,
+        TlsSettings,
+    };
+    use bytes::Bytes;
+    use domain::base::Message;
+    use reqwest::{header::CONTENT_TYPE, Method, Url};
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use tokio::net::TcpListener;
+
+    // The query for `www.example.com` A in RFC 8484, 4.1.1
+    const QUERY: &[u8] = &[
+        0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x77, 0x77,
+        0x77, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00,
+        0x01, 0x00, 0x01,
+    ];
+
+    fn request(method: DohMethod) -> DohRequest {
+        DohRequest::new(
+            Url::parse("https://dnsserver.example.net/dns-query").unwrap(),
+            method,
+            HashMap::from([("Authorization".to_string(), "Bearer secret".to_string())]),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn get_request() {
+        let req = request(DohMethod::Get)
+            .build(Bytes::from_static(QUERY))
+            .unwrap();
+        assert_eq!(req.method(), Method::GET);
+        assert_eq!(
+            req.uri(),
+            "https://dnsserver.example.net/dns-query?dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB"
+        );
+        assert!(req.body().is_empty());
+        assert!(req.headers().get(CONTENT_TYPE).is_none());
+    }
+
+    #[test]
+    fn post_request() {
+        let req = request(DohMethod::Post)
+            .build(Bytes::from_static(QUERY))
+            .unwrap();
+        assert_eq!(req.method(), Method::POST);
+        assert_eq!(req.uri(), "https://dnsserver.example.net/dns-query");
+        assert_eq!(req.body().as_ref(), QUERY);
+        assert_eq!(req.headers()[CONTENT_TYPE], "application/dns-message");
+    }
+
+    #[test]
+    fn redact_headers() {
+        let req = request(DohMethod::Post)
+            .build(Bytes::from_static(QUERY))
+            .unwrap();
+        assert_eq!(req.headers()["authorization"], "Bearer secret");
+        assert!(!format!("{:?}", req.headers()).contains("secret"));
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(DohRequest::new(
+            Url::parse("https://dnsserver.example.net/dns-query").unwrap(),
+            DohMethod::Post,
+            HashMap::from([("bad header".to_string(), "value".to_string())]),
+        )
+        .is_err());
+    }
+
+    fn shared_client(domain: &str, addr: &str) -> SharedClient {
+        SharedClient::new(
+            ClientSettings {
+                domain: domain.to_string(),
+                addr: addr.parse().unwrap(),
+                proxy: None,
+                tls: tls_config(&TlsSettings::default(), true).unwrap(),
+            },
+            (addr.parse().unwrap(), 443).into(),
+        )
+        .unwrap()
+    }
+
+    // A client to `localhost` on the port given
+    fn local_client(port: u16) -> Http2Client {
+        Http2Client(
+            Arc::new(shared_client("localhost", "127.0.0.1")),
+            Arc::new(
+                DohRequest::new(
+                    Url::parse(&format!("https://localhost:{}/dns-query", port)).unwrap(),
+                    DohMethod::Post,
+                    HashMap::new(),
+                )
+                .unwrap(),
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn invalidate_once_per_generation() {
+        let client = shared_client("cloudflare-dns.com", "1.1.1.1");
+        let (_, generation) = client.get();
+        // Concurrent failures on the same client only rebuild it once.
+        client.invalidate(generation);
+        client.invalidate(generation);
+        assert_eq!(client.get().1, generation + 1);
+    }
+
+    #[tokio::test]
+    async fn invalidate_on_transport_error() {
+        // Nothing listens on the port once the listener is dropped.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = local_client(port);
+        let query = Message::from_octets(Bytes::from_static(QUERY)).unwrap();
+        assert!(client.query(&query).await.is_err());
+        assert_eq!(client.0.get().1, 1);
+    }
+
+    #[tokio::test]
+    async fn keep_on_cancel() {
+        // The server accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = local_client(listener.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+        let query = Message::from_octets(Bytes::from_static(QUERY)).unwrap();
+        // e.g. the request is dropped on timeout, or loses a hybrid race
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), client.query(&query))
+                .await
+                .is_err()
+        );
+        assert_eq!(client.0.get().1, 0);
+    }
+}
@@ -13,34 +13,53 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+#[cfg(feature = "dnscrypt")]
+pub mod dnscrypt;
+pub mod hosts;
+#[cfg(feature = "doh3-rustls")]
+pub mod http3;
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 pub mod https;
 #[cfg_attr(target_pointer_width = "64", path = "qos_governor.rs")]
 #[cfg_attr(not(target_pointer_width = "64"), path = "qos_none.rs")]
 mod qos;
+#[cfg(feature = "doq-rustls")]
+pub mod quic;
+pub mod recursive;
+pub mod tcp;
 #[cfg(any(feature = "dot-rustls", feature = "dot-native-tls"))]
 pub mod tls;
+#[cfg(any(
+    feature = "doh-rustls",
+    feature = "doh-native-tls",
+    feature = "dot-rustls",
+    feature = "dot-native-tls"
+))]
+pub mod tls_settings;
 pub mod udp;
+pub mod zone;
 
+use super::health::HealthState;
+use crate::dnstap::{self, Event, MessageType, SocketProtocol};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use deadpool::{
     managed::{self, BuildError, Manager, Pool, RecycleError},
-    Runtime,
+    Runtime, Status,
 };
 use domain::base::{Dname, Message, MessageBuilder, Rtype};
 use once_cell::sync::Lazy;
 use qos::QosPolicy;
 #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
 use reqwest::{StatusCode, Url};
-use std::{str::FromStr, time::Duration};
+use std::{net::SocketAddr, str::FromStr, time::Duration};
 use thiserror::Error;
 use tokio::time::{error::Elapsed, timeout};
 
 const MAX_ERROR_TOLERANCE: u8 = 2;
 const WAIT_TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
 
-static DUMMY_QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
+pub(super) static DUMMY_QUERY: Lazy<Message<Bytes>> = Lazy::new(|| {
     let name = Dname::<Bytes>::from_str("example.com").unwrap();
     let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
     builder.header_mut().set_id(0);
@@ -58,6 +77,12 @@ pub trait ConnInitiator: Send + Sync + 'static {
     async fn create(&self) -> std::io::Result<Self::Connection>;
 
     fn conn_type(&self) -> &'static str;
+
+    // Address of the remote server
+    fn remote(&self) -> SocketAddr;
+
+    // Transport protocol used, as in dnstap
+    fn protocol(&self) -> SocketProtocol;
 }
 
 // A local ConnInitiator wrapper
@@ -101,6 +126,21 @@ pub trait QHandle: Send + Sync {
     async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
         Ok(())
     }
+
+    // Status of the underlying connection pool, if there is any.
+    fn status(&self) -> Option<Status> {
+        None
+    }
+
+    // Whether the responses should be cached. Local records are answered without the cache, so that changes to them take effect at once.
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    // Health state given by the circuit breaker in front, if there is any.
+    fn health(&self) -> Option<HealthState> {
+        None
+    }
 }
 
 pub type Result<T> = std::result::Result<T, QHandleError>;
@@ -139,15 +179,93 @@ pub enum QHandleError {
     #[error("unsuccessful HTTP code: {0}")]
     FailedHttp(StatusCode),
 
-    #[cfg(any(feature = "dot-native-tls"))]
+    #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+    #[error("the HTTP header '{0}' is invalid")]
+    InvalidHeader(String),
+
+    #[cfg(feature = "doh3-rustls")]
+    #[error("HTTP/3 cannot be used with a proxy")]
+    Http3OverProxy,
+
+    #[cfg(any(feature = "dot-native-tls", feature = "doh-native-tls"))]
     #[error(transparent)]
     NativeTlsError(#[from] native_tls::Error),
 
+    #[cfg(any(
+        feature = "doh-rustls",
+        feature = "doh-native-tls",
+        feature = "dot-rustls",
+        feature = "dot-native-tls"
+    ))]
+    #[error("invalid TLS settings: {0}")]
+    InvalidTlsSettings(String),
+
+    #[cfg(feature = "dnscrypt")]
+    #[error("invalid DNS stamp: {0}")]
+    InvalidStamp(String),
+
+    #[error("invalid zone file: {0}")]
+    InvalidZone(String),
+
+    #[error("invalid static record: {0}")]
+    InvalidRecord(String),
+
+    #[error("failed to resolve recursively: {0}")]
+    Unresolvable(String),
+
+    #[error("UDP payload size of {0} bytes is below the minimum of 512 bytes")]
+    InvalidPayloadSize(usize),
+
     #[error(transparent)]
     ShortBuf(#[from] domain::base::ShortBuf),
 
     #[error("ratelimiter throttled the upstream query")]
     Throttled,
+
+    #[error("the upstream is down")]
+    Down,
+}
+
+impl QHandleError {
+    /// Name of the variant, used to tell the errors apart in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::TimeError(_) => "TimeError",
+            Self::IoError(_) => "IoError",
+            Self::PoolRunError(_) => "PoolRunError",
+            Self::PoolBuildError(_) => "PoolBuildError",
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::ReqwestError(_) => "ReqwestError",
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::InvalidUri(_) => "InvalidUri",
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::InvalidDomain(_) => "InvalidDomain",
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::FailedHttp(_) => "FailedHttp",
+            #[cfg(any(feature = "doh-rustls", feature = "doh-native-tls"))]
+            Self::InvalidHeader(_) => "InvalidHeader",
+            #[cfg(feature = "doh3-rustls")]
+            Self::Http3OverProxy => "Http3OverProxy",
+            #[cfg(any(feature = "dot-native-tls", feature = "doh-native-tls"))]
+            Self::NativeTlsError(_) => "NativeTlsError",
+            #[cfg(any(
+                feature = "doh-rustls",
+                feature = "doh-native-tls",
+                feature = "dot-rustls",
+                feature = "dot-native-tls"
+            ))]
+            Self::InvalidTlsSettings(_) => "InvalidTlsSettings",
+            #[cfg(feature = "dnscrypt")]
+            Self::InvalidStamp(_) => "InvalidStamp",
+            Self::InvalidZone(_) => "InvalidZone",
+            Self::InvalidRecord(_) => "InvalidRecord",
+            Self::Unresolvable(_) => "Unresolvable",
+            Self::InvalidPayloadSize(_) => "InvalidPayloadSize",
+            Self::ShortBuf(_) => "ShortBuf",
+            Self::Throttled => "Throttled",
+            Self::Down => "Down",
+        }
+    }
 }
 
 // For HTTPS connections, ConnPool enables parallelism
@@ -155,6 +273,8 @@ pub struct ConnPool<T: ConnInitiator> {
     pool: Pool<ConnInitWrapper<T>>,
     timeout: Duration,
     ratelimiter: QosPolicy,
+    remote: SocketAddr,
+    protocol: SocketProtocol,
 }
 
 impl<T: ConnInitiator> ConnPool<T> {
@@ -165,6 +285,8 @@ impl<T: ConnInitiator> ConnPool<T> {
         ratelimiter: QosPolicy,
     ) -> std::result::Result<Self, BuildError<<ConnInitWrapper<T> as Manager>::Error>> {
         Ok(Self {
+            remote: initiator.remote(),
+            protocol: initiator.protocol(),
             pool: Pool::builder(ConnInitWrapper(initiator))
                 .max_size(max_pool_size)
                 .wait_timeout(WAIT_TIMEOUT)
@@ -176,10 +298,25 @@ impl<T: ConnInitiator> ConnPool<T> {
     }
 }
 
+impl<T: ConnInitiator> ConnPool<T> {
+    fn tap(&self, kind: MessageType, msg: &Message<Bytes>) {
+        dnstap::emit(Event {
+            kind,
+            protocol: self.protocol,
+            query_address: None,
+            query_port: None,
+            response_address: Some(self.remote.ip()),
+            response_port: Some(self.remote.port()),
+            message: msg.as_slice(),
+        })
+    }
+}
+
 #[async_trait]
 impl<T: ConnInitiator> QHandle for ConnPool<T> {
     async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
         if self.ratelimiter.check() {
+            self.tap(MessageType::ForwarderQuery, msg);
             let mut conn = self.pool.get().await?;
 
             log::debug!(
@@ -192,6 +329,7 @@ impl<T: ConnInitiator> QHandle for ConnPool<T> {
                 // Within the timeout, query was successful
                 Ok(Ok(m)) => {
                     conn.1 = 0;
+                    self.tap(MessageType::ForwarderResponse, &m);
                     Ok(m)
                 }
                 // Within the timeout, query was unsuccessful
@@ -214,4 +352,8 @@ impl<T: ConnInitiator> QHandle for ConnPool<T> {
     async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
         Ok(())
     }
+
+    fn status(&self) -> Option<Status> {
+        Some(self.pool.status())
+    }
 }
@@ -0,0 +1,312 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{ConnInitiator, QHandle, Result};
+use crate::dnstap::SocketProtocol;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use deadpool::managed;
+use domain::base::Message;
+use log::debug;
+use quinn::{ClientConfig, Connection, Endpoint, ReadError, ReadToEndError, WriteError};
+use rustls::{OwnedTrustAnchor, RootCertStore};
+use std::{
+    io::{Error, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+
+// ALPN token of DNS over QUIC (RFC 9250, 4.1.1)
+const ALPN_DOQ: &[u8] = b"doq";
+
+// Responses are prefixed with their length in two bytes.
+const MAX_RESPONSE_LEN: usize = u16::MAX as usize + 2;
+
+fn create_client_config(root_store: RootCertStore) -> ClientConfig {
+    let mut client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    client_config.alpn_protocols = vec![ALPN_DOQ.to_vec()];
+    // Send queries in 0-RTT on reconnects, with the session tickets kept by rustls.
+    client_config.enable_early_data = true;
+
+    ClientConfig::new(Arc::new(client_config))
+}
+
+fn webpki_roots() -> RootCertStore {
+    let mut root_store = RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    root_store
+}
+
+// Create a client endpoint on an ephemeral port, in the same address family as the remote.
+pub(super) fn endpoint(remote: SocketAddr, config: ClientConfig) -> std::io::Result<Endpoint> {
+    let local: SocketAddr = if remote.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let mut endpoint = Endpoint::client(local)?;
+    endpoint.set_default_client_config(config);
+    Ok(endpoint)
+}
+
+/// Client instance for DNS over QUIC connections
+pub struct Quic {
+    endpoint: Endpoint,
+    addr: SocketAddr,
+    domain: String,
+    // The long-lived connection shared by all the handles in the pool
+    conn: Mutex<Option<Connection>>,
+}
+
+impl Quic {
+    /// Create a new QUIC connection creator instance. with the given remote server address.
+    pub fn new(domain: String, addr: SocketAddr) -> Result<Self> {
+        Self::with_roots(domain, addr, webpki_roots())
+    }
+
+    fn with_roots(domain: String, addr: SocketAddr, root_store: RootCertStore) -> Result<Self> {
+        Ok(Self {
+            endpoint: endpoint(addr, create_client_config(root_store))?,
+            addr,
+            domain,
+            conn: Mutex::new(None),
+        })
+    }
+}
+
+#[async_trait]
+impl ConnInitiator for Quic {
+    // Every handle in the pool is a clone of the same connection, on which each query takes a stream of its own.
+    type Connection = Connection;
+
+    async fn create(&self) -> std::io::Result<Self::Connection> {
+        let mut guard = self.conn.lock().await;
+        if let Some(conn) = guard.as_ref() {
+            if conn.close_reason().is_none() {
+                return Ok(conn.clone());
+            }
+            debug!("QUIC connection lost, reconnecting");
+        }
+
+        let connecting = self
+            .endpoint
+            .connect(self.addr, &self.domain)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        // 0-RTT is only possible if we have connected to the server before.
+        let conn = match connecting.into_0rtt() {
+            Ok((conn, _)) => {
+                debug!("QUIC connection resumed with 0-RTT");
+                conn
+            }
+            Err(connecting) => connecting.await?,
+        };
+        *guard = Some(conn.clone());
+        Ok(conn)
+    }
+
+    fn conn_type(&self) -> &'static str {
+        "QUIC"
+    }
+
+    fn remote(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn protocol(&self) -> SocketProtocol {
+        SocketProtocol::Doq
+    }
+}
+
+// Send the prefixed query over a new stream and read the whole response back.
+// `None` is returned if the query was sent in 0-RTT and the server rejected it.
+async fn exchange(conn: &Connection, query: &[u8]) -> Result<Option<Vec<u8>>> {
+    let (mut send, recv) = conn.open_bi().await.map_err(Error::from)?;
+
+    match send.write_all(query).await {
+        Err(WriteError::ZeroRttRejected) => return Ok(None),
+        r => r.map_err(Error::from)?,
+    }
+    // The client must indicate that there is no more data with a STREAM FIN (RFC 9250, 4.2)
+    match send.finish().await {
+        Err(WriteError::ZeroRttRejected) => return Ok(None),
+        r => r.map_err(Error::from)?,
+    }
+
+    match recv.read_to_end(MAX_RESPONSE_LEN).await {
+        Ok(buf) => Ok(Some(buf)),
+        Err(ReadToEndError::Read(ReadError::ZeroRttRejected)) => Ok(None),
+        Err(ReadToEndError::Read(e)) => Err(Error::from(e).into()),
+        Err(ReadToEndError::TooLong) => {
+            Err(Error::new(ErrorKind::InvalidData, "DoQ response too long").into())
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for Connection {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        // The message ID must be set to 0 over QUIC (RFC 9250, 4.2.1)
+        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+        msg.header_mut().set_id(0);
+        let msg = msg.for_slice();
+
+        // Prefix our payload with length per RFC.
+        let len = u16::try_from(msg.as_slice().len()).expect("request too long");
+        let mut query = Vec::with_capacity(msg.as_slice().len() + 2);
+        query.extend_from_slice(&len.to_be_bytes());
+        query.extend_from_slice(msg.as_slice());
+
+        let buf = match exchange(self, &query).await? {
+            Some(buf) => buf,
+            // Data rejected in 0-RTT is not retransmitted. Now that the handshake has completed, send it again.
+            None => {
+                debug!("0-RTT rejected by the server, retrying the query");
+                exchange(self, &query)
+                    .await?
+                    .ok_or_else(|| Error::new(ErrorKind::Other, "0-RTT rejected"))?
+            }
+        };
+
+        debug!("QUIC stream received {:?}", buf);
+
+        if buf.len() < 2 || usize::from(u16::from_be_bytes([buf[0], buf[1]])) != buf.len() - 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "malformed DoQ response").into());
+        }
+        let answer = Message::from_octets(Bytes::from(buf).slice(2..))?;
+        if !answer.is_answer(&msg) {
+            return Err(Error::new(ErrorKind::InvalidData, "DoQ response mismatched").into());
+        }
+        Ok(answer)
+    }
+
+    async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
+        // Once the connection is closed, handles are discarded and the next one created reconnects.
+        match self.close_reason() {
+            Some(e) => Err(Error::from(e).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::qos::QosPolicy, ConnPool, QHandle, Quic, ALPN_DOQ, MAX_RESPONSE_LEN};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{Dname, Message, MessageBuilder, Rcode, Rtype};
+    use quinn::{Connection, Endpoint, ServerConfig};
+    use rustls::{Certificate, PrivateKey, RootCertStore};
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    // A DoQ stub answering every query with an empty NOERROR response. Returns the connections accepted.
+    fn spawn_server() -> (SocketAddr, RootCertStore, Arc<Mutex<Vec<Connection>>>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_der = Certificate(cert.serialize_der().unwrap());
+        let mut roots = RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der], PrivateKey(cert.serialize_private_key_der()))
+            .unwrap();
+        crypto.alpn_protocols = vec![ALPN_DOQ.to_vec()];
+        let endpoint = Endpoint::server(
+            ServerConfig::with_crypto(Arc::new(crypto)),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let conn = connecting.await.unwrap();
+                accepted.lock().unwrap().push(conn.clone());
+                tokio::spawn(async move {
+                    while let Ok((mut send, recv)) = conn.accept_bi().await {
+                        let buf = recv.read_to_end(MAX_RESPONSE_LEN).await.unwrap();
+                        let query = Message::from_octets(Bytes::from(buf).slice(2..)).unwrap();
+                        assert_eq!(query.header().id(), 0);
+                        let resp = MessageBuilder::from_target(BytesMut::new())
+                            .unwrap()
+                            .start_answer(&query, Rcode::NoError)
+                            .unwrap()
+                            .finish();
+                        send.write_all(&(resp.len() as u16).to_be_bytes())
+                            .await
+                            .unwrap();
+                        send.write_all(&resp).await.unwrap();
+                        send.finish().await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, roots, connections)
+    }
+
+    fn query() -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder.header_mut().set_id(1234);
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        Message::from_octets(builder.into_message().into_octets().freeze()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reuse_and_reconnect() {
+        let (addr, roots, connections) = spawn_server();
+        let pool = ConnPool::new(
+            Quic::with_roots("localhost".into(), addr, roots).unwrap(),
+            4,
+            Duration::from_secs(5),
+            QosPolicy::default(),
+        )
+        .unwrap();
+
+        // Queries are multiplexed over the same connection.
+        for _ in 0..3 {
+            let resp = pool.query(&query()).await.unwrap();
+            assert_eq!(resp.header().rcode(), Rcode::NoError);
+        }
+        assert_eq!(connections.lock().unwrap().len(), 1);
+
+        // Once the connection is closed, we connect again.
+        let conn = connections.lock().unwrap()[0].clone();
+        conn.close(0u32.into(), b"");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        pool.query(&query()).await.unwrap();
+        assert_eq!(connections.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::super::zone::{key, parent};
+use bytes::Bytes;
+use clru::CLruCache;
+use domain::base::Dname;
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Name servers of a zone learned from referrals
+pub struct Delegation {
+    pub zone: Dname<Bytes>,
+    pub servers: Vec<Dname<Bytes>>,
+}
+
+struct Entry<T> {
+    value: T,
+    expires: Instant,
+}
+
+impl<T: Clone> Entry<T> {
+    fn new(value: T, ttl: u32) -> Self {
+        Self {
+            value,
+            expires: Instant::now() + Duration::from_secs(ttl.into()),
+        }
+    }
+
+    fn get(&self) -> Option<T> {
+        (Instant::now() < self.expires).then(|| self.value.clone())
+    }
+}
+
+// Delegations and addresses of name servers, shared by all the queries of the resolver.
+pub struct Cache {
+    delegations: Mutex<CLruCache<String, Entry<Arc<Delegation>>>>,
+    addrs: Mutex<CLruCache<String, Entry<Arc<[IpAddr]>>>>,
+}
+
+impl Cache {
+    pub fn new(size: NonZeroUsize) -> Self {
+        Self {
+            delegations: Mutex::new(CLruCache::new(size)),
+            addrs: Mutex::new(CLruCache::new(size)),
+        }
+    }
+
+    pub fn put_delegation(&self, delegation: Delegation, ttl: u32) {
+        self.delegations
+            .lock()
+            .unwrap()
+            .put(key(&delegation.zone), Entry::new(Arc::new(delegation), ttl));
+    }
+
+    // The deepest delegation enclosing the name, if we know any.
+    pub fn delegation(&self, name: &Dname<Bytes>) -> Option<Arc<Delegation>> {
+        let name = key(name);
+        let mut delegations = self.delegations.lock().unwrap();
+        let mut current = Some(name.as_str());
+        while let Some(k) = current {
+            if let Some(delegation) = delegations.get(k).and_then(Entry::get) {
+                return Some(delegation);
+            }
+            current = parent(k);
+        }
+        None
+    }
+
+    pub fn put_addrs(&self, name: &Dname<Bytes>, addrs: Vec<IpAddr>, ttl: u32) {
+        if !addrs.is_empty() {
+            self.addrs
+                .lock()
+                .unwrap()
+                .put(key(name), Entry::new(addrs.into(), ttl));
+        }
+    }
+
+    pub fn addrs(&self, name: &Dname<Bytes>) -> Option<Arc<[IpAddr]>> {
+        self.addrs
+            .lock()
+            .unwrap()
+            .get(&key(name))
+            .and_then(Entry::get)
+    }
+}
@@ -0,0 +1,633 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+mod cache;
+
+use self::cache::{Cache, Delegation};
+use super::{
+    udp::Udp,
+    zone::{is_under, key, parent},
+    ConnInitiator, QHandle, QHandleError, Result,
+};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{
+        iana::{Class, Rcode},
+        Dname, Message, MessageBuilder, ParsedDname, ParsedRecord, Rtype,
+    },
+    rdata::{Aaaa, AllRecordData, Cname, Ns, A},
+};
+use futures::future::{BoxFuture, FutureExt};
+use log::debug;
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::time::timeout;
+
+// Time to wait for a single authoritative server before trying the next one
+const SERVER_TIMEOUT: Duration = Duration::from_millis(1500);
+
+// Maximum number of CNAME records to follow in a chain
+const MAX_CHAIN: usize = 8;
+
+// Maximum number of referrals and minimised queries to go through for a name
+const MAX_STEPS: usize = 32;
+
+// Maximum depth of nested resolutions for the addresses of name servers without glue
+const MAX_DEPTH: usize = 4;
+
+// Number of delegations and name server addresses cached
+const CACHE_SIZE: usize = 4096;
+
+fn owned(name: &impl Display) -> Option<Dname<Bytes>> {
+    Dname::from_str(&name.to_string()).ok()
+}
+
+fn section<'a>(
+    resp: &'a Message<Bytes>,
+    authority: bool,
+) -> impl Iterator<Item = ParsedRecord<&'a Bytes>> {
+    let section = if authority {
+        resp.authority().ok()
+    } else {
+        resp.answer().ok()
+    };
+    section.into_iter().flatten().flatten()
+}
+
+fn owned_by(record: &ParsedRecord<&Bytes>, owner: &str) -> bool {
+    record.owner().to_string().eq_ignore_ascii_case(owner)
+}
+
+// The name one label below `ancestor` towards `name`, or None if they are the same (RFC 9156).
+fn child_towards(name: &str, ancestor: &str) -> Option<Dname<Bytes>> {
+    let mut current = name;
+    let mut child = None;
+    while current != ancestor {
+        if current == "." {
+            return None;
+        }
+        child = Some(current);
+        current = parent(current).unwrap_or(".");
+    }
+    child.and_then(|c| Dname::from_str(c).ok())
+}
+
+// Records copied from the responses of authoritative servers
+enum Part {
+    // Records in the answer section owned by the name, with the type given or any type for ANY
+    Answer {
+        resp: Message<Bytes>,
+        owner: String,
+        rtype: Rtype,
+    },
+    // SOA records in the authority section of a negative response
+    Soa(Message<Bytes>),
+}
+
+struct Outcome {
+    rcode: Rcode,
+    parts: Vec<Part>,
+}
+
+impl Outcome {
+    fn answers(&self) -> impl Iterator<Item = ParsedRecord<&Bytes>> {
+        self.parts.iter().flat_map(|part| {
+            let (resp, owner, rtype) = match part {
+                Part::Answer { resp, owner, rtype } => (resp, owner.as_str(), *rtype),
+                Part::Soa(resp) => (resp, "", Rtype::Soa),
+            };
+            section(resp, false).filter(move |record| {
+                !owner.is_empty()
+                    && owned_by(record, owner)
+                    && (rtype == Rtype::Any || record.rtype() == rtype)
+            })
+        })
+    }
+
+    fn authority(&self) -> impl Iterator<Item = ParsedRecord<&Bytes>> {
+        self.parts.iter().flat_map(|part| {
+            let resp = match part {
+                Part::Soa(resp) => Some(resp),
+                Part::Answer { .. } => None,
+            };
+            resp.into_iter()
+                .flat_map(|resp| section(resp, true))
+                .filter(|record| record.rtype() == Rtype::Soa)
+        })
+    }
+
+    fn addrs(&self) -> Vec<IpAddr> {
+        let mut addrs = Vec::new();
+        for record in self.answers() {
+            if let Ok(Some(a)) = record.to_record::<A>() {
+                addrs.push(IpAddr::V4(a.data().addr()));
+            } else if let Ok(Some(aaaa)) = record.to_record::<Aaaa>() {
+                addrs.push(IpAddr::V6(aaaa.data().addr()));
+            }
+        }
+        addrs
+    }
+
+    fn ttl(&self) -> u32 {
+        self.answers().map(|r| r.ttl()).min().unwrap_or(0)
+    }
+}
+
+// Name servers to ask for a zone
+struct Servers {
+    zone: Dname<Bytes>,
+    // Addresses known, from hints, glue, or the cache
+    addrs: Vec<SocketAddr>,
+    // Names of the servers whose addresses are yet to be resolved
+    names: Vec<Dname<Bytes>>,
+}
+
+/// Iterative resolver which walks down from the root servers itself, without trusting any other resolver.
+pub struct Recursive {
+    roots: Vec<SocketAddr>,
+    port: u16,
+    timeout: Duration,
+    cache: Cache,
+}
+
+impl Recursive {
+    /// Create a resolver starting from the root servers given. Name servers found by referrals are asked on `port`.
+    pub fn new(roots: Vec<SocketAddr>, port: u16, timeout: Duration) -> Self {
+        Self {
+            roots,
+            port,
+            timeout,
+            cache: Cache::new(NonZeroUsize::new(CACHE_SIZE).unwrap()),
+        }
+    }
+
+    // The closest zone enclosing the name we know the servers of.
+    fn servers(&self, name: &Dname<Bytes>) -> Servers {
+        match self.cache.delegation(name) {
+            Some(delegation) => self.servers_of(&delegation),
+            None => Servers {
+                zone: Dname::root_bytes(),
+                addrs: self.roots.clone(),
+                names: Vec::new(),
+            },
+        }
+    }
+
+    fn servers_of(&self, delegation: &Delegation) -> Servers {
+        let mut servers = Servers {
+            zone: delegation.zone.clone(),
+            addrs: Vec::new(),
+            names: Vec::new(),
+        };
+        for name in &delegation.servers {
+            match self.cache.addrs(name) {
+                Some(addrs) => servers
+                    .addrs
+                    .extend(addrs.iter().map(|ip| SocketAddr::new(*ip, self.port))),
+                None => servers.names.push(name.clone()),
+            }
+        }
+        servers
+    }
+
+    // Send the query to a single server.
+    async fn send(
+        &self,
+        addr: SocketAddr,
+        name: &Dname<Bytes>,
+        rtype: Rtype,
+    ) -> Result<Message<Bytes>> {
+        let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(512))?.question();
+        builder.push((name, rtype))?;
+        // UDP payload size is set by the UDP client.
+        let mut builder = builder.additional();
+        builder.opt(|_| Ok(()))?;
+        let query = builder.into_message();
+
+        let conn = Udp::new(addr, 1232, 4096).await?.create().await?;
+        Ok(timeout(SERVER_TIMEOUT, conn.query(&query)).await??)
+    }
+
+    // Ask the servers in turn until one of them answers. Servers without addresses are resolved on need.
+    async fn ask(
+        &self,
+        servers: &Servers,
+        name: &Dname<Bytes>,
+        rtype: Rtype,
+        depth: usize,
+    ) -> Result<Message<Bytes>> {
+        let usable = |resp: &Message<Bytes>| {
+            matches!(resp.header().rcode(), Rcode::NoError | Rcode::NXDomain)
+        };
+
+        for addr in &servers.addrs {
+            match self.send(*addr, name, rtype).await {
+                Ok(resp) if usable(&resp) => return Ok(resp),
+                Ok(resp) => debug!("{} answered {}", addr, resp.header().rcode()),
+                Err(e) => debug!("failed to query {}: {}", addr, e),
+            }
+        }
+
+        if depth < MAX_DEPTH {
+            for server in &servers.names {
+                let addrs = match self.resolve(server.clone(), Rtype::A, depth + 1).await {
+                    Ok(outcome) => {
+                        let addrs = outcome.addrs();
+                        self.cache.put_addrs(server, addrs.clone(), outcome.ttl());
+                        addrs
+                    }
+                    Err(e) => {
+                        debug!("failed to resolve name server {}: {}", server, e);
+                        continue;
+                    }
+                };
+                for ip in addrs {
+                    let addr = SocketAddr::new(ip, self.port);
+                    match self.send(addr, name, rtype).await {
+                        Ok(resp) if usable(&resp) => return Ok(resp),
+                        Ok(resp) => debug!("{} answered {}", addr, resp.header().rcode()),
+                        Err(e) => debug!("failed to query {}: {}", addr, e),
+                    }
+                }
+            }
+        }
+
+        Err(QHandleError::Unresolvable(format!(
+            "no server of `{}` answered",
+            servers.zone
+        )))
+    }
+
+    // If the response refers us to the servers of a child zone enclosing the name, cache and return them.
+    fn referral(&self, resp: &Message<Bytes>, zone: &Dname<Bytes>, name: &str) -> Option<Servers> {
+        if resp.header().rcode() != Rcode::NoError || section(resp, false).next().is_some() {
+            return None;
+        }
+        let zone = key(zone);
+
+        let mut child: Option<(String, Dname<Bytes>)> = None;
+        let mut servers = Vec::new();
+        let mut ttl = u32::MAX;
+        let authority = resp.authority().ok()?.limit_to::<Ns<ParsedDname<&Bytes>>>();
+        for record in authority.flatten() {
+            let owner = record.owner().to_string().to_ascii_lowercase();
+            // The child must be below the zone of the server, and enclose the name (no lame or bogus referrals).
+            if owner == zone || !is_under(&owner, &zone) || !is_under(name, &owner) {
+                continue;
+            }
+            match child.as_ref().map(|(k, _)| *k == owner) {
+                Some(false) => continue,
+                Some(true) => (),
+                None => child = Some((owner, owned(record.owner())?)),
+            }
+            servers.extend(owned(record.data().nsdname()));
+            ttl = ttl.min(record.ttl());
+        }
+        let (_, child) = child?;
+
+        // Glue is only trusted if it is within the zone of the server.
+        let additional = resp.additional().ok()?;
+        let mut glue: Vec<(Dname<Bytes>, IpAddr, u32)> = Vec::new();
+        for record in additional.flatten() {
+            let server = match servers.iter().find(|s| owned_by(&record, &key(s))) {
+                Some(server) if is_under(&key(server), &zone) => server,
+                _ => continue,
+            };
+            if let Ok(Some(a)) = record.to_record::<A>() {
+                glue.push((server.clone(), IpAddr::V4(a.data().addr()), a.ttl()));
+            } else if let Ok(Some(aaaa)) = record.to_record::<Aaaa>() {
+                glue.push((server.clone(), IpAddr::V6(aaaa.data().addr()), aaaa.ttl()));
+            }
+        }
+        for server in &servers {
+            let addrs: Vec<_> = glue
+                .iter()
+                .filter(|(s, _, _)| s == server)
+                .map(|(_, ip, _)| *ip)
+                .collect();
+            let ttl = glue
+                .iter()
+                .filter(|(s, _, _)| s == server)
+                .map(|(_, _, ttl)| *ttl)
+                .min()
+                .unwrap_or(0);
+            self.cache.put_addrs(server, addrs, ttl);
+        }
+
+        let delegation = Delegation {
+            zone: child,
+            servers,
+        };
+        let servers = self.servers_of(&delegation);
+        self.cache.put_delegation(delegation, ttl);
+        Some(servers)
+    }
+
+    // Walk down the delegations to the servers of the name, and return their response to the query.
+    async fn resolve_name(
+        &self,
+        qname: &Dname<Bytes>,
+        qtype: Rtype,
+        depth: usize,
+    ) -> Result<Message<Bytes>> {
+        let qkey = key(qname);
+        let mut servers = self.servers(qname);
+        // The deepest name known to exist, from which the next name to ask is minimised.
+        let mut known = key(&servers.zone);
+
+        for _ in 0..MAX_STEPS {
+            // Only the zone cuts need to be revealed to the servers of the ancestors (RFC 9156).
+            let (name, rtype) = match child_towards(&qkey, &known) {
+                Some(child) if key(&child) != qkey => (child, Rtype::A),
+                _ => (qname.clone(), qtype),
+            };
+            let name_key = key(&name);
+
+            let resp = self.ask(&servers, &name, rtype, depth).await?;
+            if let Some(child) = self.referral(&resp, &servers.zone, &name_key) {
+                known = key(&child.zone);
+                servers = child;
+                continue;
+            }
+
+            // Nothing exists below a nonexistent name (RFC 8020).
+            if name_key == qkey || resp.header().rcode() == Rcode::NXDomain {
+                return Ok(resp);
+            }
+            known = name_key;
+        }
+
+        Err(QHandleError::Unresolvable(format!(
+            "too many referrals for `{}`",
+            qname
+        )))
+    }
+
+    // Resolve the name, following the CNAME records.
+    fn resolve(
+        &self,
+        qname: Dname<Bytes>,
+        qtype: Rtype,
+        depth: usize,
+    ) -> BoxFuture<'_, Result<Outcome>> {
+        async move {
+            let mut parts = Vec::new();
+            let mut name = qname;
+            for _ in 0..MAX_CHAIN {
+                let resp = self.resolve_name(&name, qtype, depth).await?;
+                let rcode = resp.header().rcode();
+                let owner = key(&name);
+
+                if qtype != Rtype::Cname && qtype != Rtype::Any {
+                    let cname = section(&resp, false)
+                        .filter(|record| owned_by(record, &owner))
+                        .find_map(|record| record.to_record::<Cname<ParsedDname<&Bytes>>>().ok()?)
+                        .and_then(|record| owned(record.data().cname()));
+                    if let Some(target) = cname {
+                        parts.push(Part::Answer {
+                            resp,
+                            owner,
+                            rtype: Rtype::Cname,
+                        });
+                        name = target;
+                        continue;
+                    }
+                }
+
+                let answered = section(&resp, false).any(|record| {
+                    owned_by(&record, &owner) && (qtype == Rtype::Any || record.rtype() == qtype)
+                });
+                parts.push(if answered {
+                    Part::Answer {
+                        resp,
+                        owner,
+                        rtype: qtype,
+                    }
+                } else {
+                    Part::Soa(resp)
+                });
+                return Ok(Outcome { rcode, parts });
+            }
+
+            Err(QHandleError::Unresolvable(
+                "CNAME chain too long".to_string(),
+            ))
+        }
+        .boxed()
+    }
+}
+
+#[async_trait]
+impl QHandle for Recursive {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?;
+        let question = match msg.first_question() {
+            Some(q) => q,
+            None => return Ok(builder.start_answer(msg, Rcode::FormErr)?.into_message()),
+        };
+        let name = match owned(question.qname()) {
+            Some(name) if question.qclass() == Class::In => name,
+            _ => return Ok(builder.start_answer(msg, Rcode::Refused)?.into_message()),
+        };
+
+        let outcome = timeout(self.timeout, self.resolve(name, question.qtype(), 0)).await??;
+        let mut answer = builder.start_answer(msg, outcome.rcode)?;
+        answer.header_mut().set_ra(true);
+        for record in outcome.answers() {
+            if let Ok(Some(record)) = record.into_record::<AllRecordData<_, _>>() {
+                answer.push(record)?;
+            }
+        }
+        let mut authority = answer.authority();
+        for record in outcome.authority() {
+            if let Ok(Some(record)) = record.into_record::<AllRecordData<_, _>>() {
+                authority.push(record)?;
+            }
+        }
+        Ok(authority.into_message())
+    }
+}
+
+// The mock servers listen on 127.0.0.2 and 127.0.0.3 as well, which are only there without any setup on Linux.
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{QHandle, Recursive};
+    use crate::mock::Authority;
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::A,
+    };
+    use std::{
+        io::ErrorKind,
+        net::Ipv4Addr,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::net::UdpSocket;
+
+    fn serve(authority: Authority, socket: UdpSocket) -> Arc<Mutex<Vec<String>>> {
+        let log = authority.log();
+        tokio::spawn(authority.run(socket));
+        log
+    }
+
+    // Bind on the same port on each of the loopback addresses, with the port picked by the system.
+    async fn bind(ips: &[&str]) -> Vec<UdpSocket> {
+        for _ in 0..8 {
+            let first = UdpSocket::bind((ips[0], 0)).await.unwrap();
+            let port = first.local_addr().unwrap().port();
+            let mut sockets = vec![first];
+            for ip in &ips[1..] {
+                match UdpSocket::bind((*ip, port)).await {
+                    Ok(socket) => sockets.push(socket),
+                    // Try another port
+                    Err(e) if e.kind() == ErrorKind::AddrInUse => break,
+                    Err(e) => panic!("failed to bind on {}: {}", ip, e),
+                }
+            }
+            if sockets.len() == ips.len() {
+                return sockets;
+            }
+        }
+        panic!("no port is free on all of {:?}", ips);
+    }
+
+    // A tree of zones, each served on its own loopback address sharing the same port. The servers of `example.` have no glue.
+    async fn tree() -> (Recursive, Arc<Mutex<Vec<String>>>, Arc<Mutex<Vec<String>>>) {
+        let mut sockets = bind(&["127.0.0.1", "127.0.0.2", "127.0.0.3"])
+            .await
+            .into_iter();
+        let root_socket = sockets.next().unwrap();
+        let port = root_socket.local_addr().unwrap().port();
+        let root = serve(
+            Authority::new(".")
+                .add("test", Rtype::Ns, "ns.test")
+                .add("ns.test", Rtype::A, "127.0.0.2")
+                .add("example", Rtype::Ns, "ns.example-dns.test"),
+            root_socket,
+        );
+        serve(
+            Authority::new("test")
+                .add("ns.test", Rtype::A, "127.0.0.2")
+                .add("ns.example-dns.test", Rtype::A, "127.0.0.3")
+                .add("www.test", Rtype::A, "10.0.0.1")
+                .add("alias.test", Rtype::Cname, "www.example"),
+            sockets.next().unwrap(),
+        );
+        let example = serve(
+            Authority::new("example")
+                .add("www.example", Rtype::A, "10.0.0.2")
+                .add("a.b.c.example", Rtype::A, "10.0.0.3"),
+            sockets.next().unwrap(),
+        );
+
+        let resolver = Recursive::new(
+            vec![([127, 0, 0, 1], port).into()],
+            port,
+            Duration::from_secs(5),
+        );
+        (resolver, root, example)
+    }
+
+    async fn query(resolver: &Recursive, name: &str) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), Rtype::A))
+            .unwrap();
+        let msg = Message::from_octets(builder.into_message().into_octets().freeze()).unwrap();
+        resolver.query(&msg).await.unwrap()
+    }
+
+    fn addrs(msg: &Message<Bytes>) -> Vec<Ipv4Addr> {
+        msg.answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|r| r.unwrap().data().addr())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resolve_with_glue() {
+        let (resolver, _, _) = tree().await;
+        let resp = query(&resolver, "www.test").await;
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert!(resp.header().ra());
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(10, 0, 0, 1)]);
+    }
+
+    #[tokio::test]
+    async fn chase_out_of_bailiwick() {
+        let (resolver, root, _) = tree().await;
+        // The CNAME target is in another zone, whose server is resolved from the `test.` zone.
+        let resp = query(&resolver, "alias.test").await;
+        assert_eq!(
+            resp.answer()
+                .unwrap()
+                .map(|r| r.unwrap().rtype())
+                .collect::<Vec<_>>(),
+            vec![Rtype::Cname, Rtype::A]
+        );
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(10, 0, 0, 2)]);
+
+        // The delegations are cached.
+        let asked = root.lock().unwrap().len();
+        let resp = query(&resolver, "www.example").await;
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(10, 0, 0, 2)]);
+        assert_eq!(root.lock().unwrap().len(), asked);
+    }
+
+    #[tokio::test]
+    async fn minimise_qname() {
+        let (resolver, root, example) = tree().await;
+        let resp = query(&resolver, "a.b.c.example").await;
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(10, 0, 0, 3)]);
+        // Only the top-level domains are revealed to the root servers.
+        let root = root.lock().unwrap();
+        assert!(root.contains(&"example".to_string()));
+        assert!(root.iter().all(|name| !name.contains('.')));
+        assert_eq!(
+            *example.lock().unwrap(),
+            vec![
+                "c.example".to_string(),
+                "b.c.example".to_string(),
+                "a.b.c.example".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn nxdomain() {
+        let (resolver, _, example) = tree().await;
+        let resp = query(&resolver, "x.y.nonexistent.example").await;
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert_eq!(resp.header_counts().nscount(), 1);
+        // Nothing is asked below the nonexistent name.
+        assert!(!example
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|name| name.starts_with("y.")));
+    }
+}
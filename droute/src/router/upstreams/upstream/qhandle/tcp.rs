@@ -0,0 +1,372 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{ConnInitiator, QHandle, Result};
+use crate::dnstap::SocketProtocol;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use deadpool::managed::{self, RecycleError};
+use domain::base::Message;
+use log::debug;
+use socket2::{Socket, TcpKeepalive};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
+    sync::{oneshot, Mutex},
+    task::JoinHandle,
+};
+
+// Queries in flight, by their IDs.
+type Pending = std::sync::Mutex<HashMap<u16, (Message<Bytes>, oneshot::Sender<Message<Bytes>>)>>;
+
+struct Inner<S> {
+    writer: Mutex<WriteHalf<S>>,
+    pending: Arc<Pending>,
+    // Set once the connection fails, or a query on it is abandoned (e.g. on timeout) while its message is partially written.
+    broken: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+    // Time the connection established
+    established: Instant,
+    // Number of query sent
+    sent: AtomicUsize,
+    // Time in milliseconds to keep the connection for reuse
+    reuse_timeout: u64,
+    // Maximum number of queries allowed on the connection
+    max_reuse: usize,
+}
+
+impl<S> Drop for Inner<S> {
+    fn drop(&mut self) {
+        // The stream is closed once both halves are dropped.
+        self.reader.abort();
+    }
+}
+
+// A persistent connection over a stream, on which messages are framed as per RFC 1035, 4.2.2.
+// Queries are pipelined, and the responses, which may come out of order, are matched back by their IDs (RFC 7766, 6.2.1.1).
+// It is cheap to clone, and all the clones share the same connection.
+pub struct StreamConn<S>(Arc<Inner<S>>);
+
+impl<S> Clone for StreamConn<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+// Dispatch the responses to the queries in flight until the connection fails.
+async fn read_responses<S: AsyncRead>(
+    mut reader: ReadHalf<S>,
+    pending: Arc<Pending>,
+    broken: Arc<AtomicBool>,
+) {
+    let e = loop {
+        // Get the length of the response
+        let mut len = [0; 2];
+        if let Err(e) = reader.read_exact(&mut len).await {
+            break e;
+        }
+        let len: usize = u16::from_be_bytes(len).into();
+
+        debug!("stream got response length: {} bytes", len);
+
+        // Read the response
+        let mut buf = BytesMut::with_capacity(len);
+        buf.resize(len, 0);
+        if let Err(e) = reader.read_exact(&mut buf).await {
+            break e;
+        }
+
+        debug!("stream received {:?}", buf);
+
+        // We ignore garbage since there is a timer on every query.
+        let answer = match Message::from_octets(buf.freeze()) {
+            Ok(answer) => answer,
+            Err(_) => continue,
+        };
+        let mut pending = pending.lock().unwrap();
+        let id = answer.header().id();
+        if matches!(pending.get(&id), Some((query, _)) if answer.is_answer(query)) {
+            // The query may have been abandoned.
+            let _ = pending.remove(&id).unwrap().1.send(answer);
+        }
+    };
+    debug!("stream closed: {}", e);
+    broken.store(true, Ordering::Relaxed);
+    // Dropping the senders fails all the queries in flight.
+    pending.lock().unwrap().clear();
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + 'static> StreamConn<S> {
+    pub fn new(stream: S, reuse_timeout: u64, max_reuse: usize) -> Self {
+        let (reader, writer) = split(stream);
+        let pending = Arc::new(Pending::default());
+        let broken = Arc::new(AtomicBool::new(false));
+        Self(Arc::new(Inner {
+            writer: Mutex::new(writer),
+            reader: tokio::spawn(read_responses(reader, pending.clone(), broken.clone())),
+            pending,
+            broken,
+            established: Instant::now(),
+            sent: AtomicUsize::new(0),
+            reuse_timeout,
+            max_reuse,
+        }))
+    }
+}
+
+impl<S> StreamConn<S> {
+    // No matter when our last valid query was on, TCP connections all expire a certain amount of time after they were established.
+    // This is because the server may have got a timeout timer set on our outgoing connections.
+    // Moreover, most of the server has limit on the maximum number of query possible. We check it as well here
+    fn check_reusable(&self) -> std::result::Result<(), &'static str> {
+        let inner = &self.0;
+        if inner.broken.load(Ordering::Relaxed) {
+            return Err("stream connection broken");
+        }
+        if inner.sent.load(Ordering::Relaxed) >= inner.max_reuse {
+            debug!("stream has reached maximum number of queries that can be sent on the underlying persistent TCP connection.");
+            return Err("max reuse TCP queries reached");
+        }
+        if inner.established.elapsed().as_millis() >= inner.reuse_timeout.into() {
+            debug!("stream has reached period dcompass will keep the underlying TCP persistent connections open.");
+            return Err("TCP reuse timeout reached");
+        }
+        Ok(())
+    }
+}
+
+// Remove the query from the ones in flight once it is completed or abandoned.
+// Queries abandoned (e.g. on timeout, or losing a hybrid race) leave the connection to the others, unless the stream is left with a partial frame.
+struct InFlight<'a, S> {
+    conn: &'a Inner<S>,
+    id: u16,
+    writing: bool,
+}
+
+impl<S> Drop for InFlight<'_, S> {
+    fn drop(&mut self) {
+        self.conn.pending.lock().unwrap().remove(&self.id);
+        if self.writing {
+            self.conn.broken.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+// The connection shared by all the handles in the pool, which is replaced once it is no longer reusable.
+pub struct SharedConn<S> {
+    current: Mutex<Option<StreamConn<S>>>,
+    reuse_timeout: u64,
+    max_reuse: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + 'static> SharedConn<S> {
+    pub fn new(reuse_timeout: u64, max_reuse: usize) -> Self {
+        Self {
+            current: Mutex::new(None),
+            reuse_timeout,
+            max_reuse,
+        }
+    }
+
+    // Get the current connection, or establish a new one with `connect` if it is no longer reusable.
+    pub async fn get_or_connect<F: Future<Output = std::io::Result<S>>>(
+        &self,
+        connect: impl FnOnce() -> F,
+    ) -> std::io::Result<StreamConn<S>> {
+        let mut current = self.current.lock().await;
+        if let Some(conn) = current.as_ref() {
+            if conn.check_reusable().is_ok() {
+                return Ok(conn.clone());
+            }
+        }
+        let conn = StreamConn::new(connect().await?, self.reuse_timeout, self.max_reuse);
+        *current = Some(conn.clone());
+        Ok(conn)
+    }
+}
+
+// Connect to the remote with keepalive set.
+pub(super) async fn connect(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+
+    // Good default as reqwest also sets this.
+    let keepalive = TcpKeepalive::new().with_time(std::time::Duration::from_secs(60));
+    let socket: Socket = stream.into_std()?.into();
+    socket.set_tcp_keepalive(&keepalive)?;
+    TcpStream::from_std(socket.into())
+}
+
+/// Client instance for plain TCP connections
+pub struct Tcp {
+    addr: SocketAddr,
+    conn: SharedConn<TcpStream>,
+}
+
+impl Tcp {
+    /// Create a new TCP connection creator instance. with the given remote server address.
+    pub fn new(addr: SocketAddr, tcp_reuse_timeout: u64, max_reuse_tcp_queries: usize) -> Self {
+        Self {
+            addr,
+            conn: SharedConn::new(tcp_reuse_timeout, max_reuse_tcp_queries),
+        }
+    }
+}
+
+#[async_trait]
+impl ConnInitiator for Tcp {
+    type Connection = StreamConn<TcpStream>;
+
+    async fn create(&self) -> std::io::Result<Self::Connection> {
+        self.conn.get_or_connect(|| connect(self.addr)).await
+    }
+
+    fn conn_type(&self) -> &'static str {
+        "TCP"
+    }
+
+    fn remote(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn protocol(&self) -> SocketProtocol {
+        SocketProtocol::Tcp
+    }
+}
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Send + 'static> QHandle for StreamConn<S> {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let inner = &self.0;
+        inner.sent.fetch_add(1, Ordering::Relaxed);
+
+        // Randomnize the message, with an ID unique among the queries in flight.
+        let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
+        let (msg, rx) = {
+            let mut pending = inner.pending.lock().unwrap();
+            msg.header_mut().set_random_id();
+            while pending.contains_key(&msg.header().id()) {
+                msg.header_mut().set_random_id();
+            }
+            let msg = Message::from_octets(msg.into_octets().freeze())?;
+            let (tx, rx) = oneshot::channel();
+            pending.insert(msg.header().id(), (msg.clone(), tx));
+            (msg, rx)
+        };
+        let mut in_flight = InFlight {
+            conn: inner,
+            id: msg.header().id(),
+            writing: false,
+        };
+
+        // Prefix our payload with length per RFC.
+        let len = u16::try_from(msg.as_slice().len())
+            .expect("request too long")
+            .to_be_bytes();
+        let mut buf = Vec::with_capacity(msg.as_slice().len() + 2);
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(msg.as_slice());
+
+        // Write all of our query
+        {
+            let mut writer = inner.writer.lock().await;
+            in_flight.writing = true;
+            writer.write_all(&buf).await?;
+            in_flight.writing = false;
+            writer.flush().await?;
+        }
+
+        debug!("stream wrote all of the prefixed query");
+
+        let answer = rx.await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "stream closed before the response arrived",
+            )
+        })?;
+        Ok(answer)
+    }
+
+    async fn reusable(&self) -> managed::RecycleResult<std::io::Error> {
+        self.check_reusable().map_err(RecycleError::StaticMessage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QHandle, StreamConn};
+    use bytes::{Bytes, BytesMut};
+    use domain::base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype};
+    use std::{str::FromStr, time::Duration};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    fn query() -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str("example.com").unwrap(), Rtype::A))
+            .unwrap();
+        Message::from_octets(builder.into_message().into_octets().freeze()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn keep_on_abandon() {
+        let (client, mut server) = duplex(4096);
+        // The server ignores the first query and answers the rest.
+        tokio::spawn(async move {
+            let mut ignored = false;
+            loop {
+                let len = server.read_u16().await.unwrap();
+                let mut buf = vec![0; len.into()];
+                server.read_exact(&mut buf).await.unwrap();
+                if !ignored {
+                    ignored = true;
+                    continue;
+                }
+                let query = Message::from_octets(Bytes::from(buf)).unwrap();
+                let resp = MessageBuilder::from_target(BytesMut::new())
+                    .unwrap()
+                    .start_answer(&query, Rcode::NoError)
+                    .unwrap()
+                    .finish();
+                server.write_u16(resp.len() as u16).await.unwrap();
+                server.write_all(&resp).await.unwrap();
+            }
+        });
+
+        let conn = StreamConn::new(client, 60_000, 100);
+        // e.g. the query times out, or loses a hybrid race
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), conn.query(&query()))
+                .await
+                .is_err()
+        );
+        assert!(conn.check_reusable().is_ok());
+        assert_eq!(
+            conn.query(&query()).await.unwrap().header().rcode(),
+            Rcode::NoError
+        );
+    }
+}
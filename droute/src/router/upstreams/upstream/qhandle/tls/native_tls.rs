@@ -13,23 +13,23 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::{ConnInitiator, Result};
+use super::{super::tls_settings::Pins, tcp, ConnInitiator, Result, TlsSettings};
+use crate::dnstap::SocketProtocol;
 use async_trait::async_trait;
-use native_tls::{Protocol, TlsConnector as NativeTlsConnector};
-use socket2::{Socket, TcpKeepalive};
-use std::{net::SocketAddr, time::Instant};
-use tokio::{net::TcpStream, sync::Mutex};
+use native_tls::Protocol;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
 use tokio_native_tls::TlsConnector;
 pub use tokio_native_tls::TlsStream;
 
 /// Client instance for TLS connections
-#[derive(Clone)]
 pub struct Tls {
     client: TlsConnector,
+    // Checked on every connection, as native-tls cannot do it during the handshake
+    pins: Pins,
     addr: SocketAddr,
     domain: String,
-    tcp_reuse_timeout: u64,
-    max_reuse_tcp_queries: usize,
+    conn: tcp::SharedConn<TlsStream<TcpStream>>,
 }
 
 impl Tls {
@@ -38,51 +38,67 @@ impl Tls {
         domain: String,
         addr: SocketAddr,
         sni: bool,
+        tls: &TlsSettings,
         tcp_reuse_timeout: u64,
         max_reuse_tcp_queries: usize,
     ) -> Result<Self> {
         Ok(Self {
-            client: NativeTlsConnector::builder()
-                .use_sni(sni)
+            client: tls
+                .native_tls_builder(sni)?
                 .min_protocol_version(Some(Protocol::Tlsv12))
                 .build()?
                 .into(),
+            pins: tls.pins()?,
             addr,
             domain,
-            tcp_reuse_timeout,
-            max_reuse_tcp_queries,
+            conn: tcp::SharedConn::new(tcp_reuse_timeout, max_reuse_tcp_queries),
         })
     }
+
+    async fn connect(&self) -> std::io::Result<TlsStream<TcpStream>> {
+        let stream = tcp::connect(self.addr).await?;
+        let stream = self
+            .client
+            .connect(&self.domain, stream)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?;
+
+        // Only the end-entity certificate is available to check against the pins.
+        if !self.pins.is_empty() {
+            let cert = stream
+                .get_ref()
+                .peer_certificate()
+                .and_then(|cert| cert.map(|cert| cert.to_der()).transpose())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            if !self.pins.matches(cert.as_deref().into_iter()) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "no certificate matches the SPKI pins",
+                ));
+            }
+        }
+
+        Ok(stream)
+    }
 }
 
 #[async_trait]
 impl ConnInitiator for Tls {
-    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
+    type Connection = tcp::StreamConn<TlsStream<TcpStream>>;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
-        let mut stream = TcpStream::connect(self.addr).await?;
-
-        // Good default as reqwest also sets this
-        let keepalive = TcpKeepalive::new().with_time(std::time::Duration::from_secs(60));
-        let socket: Socket = stream.into_std()?.into();
-        socket.set_tcp_keepalive(&keepalive)?;
-        stream = TcpStream::from_std(socket.into())?;
-
-        Ok((
-            Mutex::new((
-                self.client
-                    .connect(&self.domain, stream)
-                    .await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
-                Instant::now(),
-                0,
-            )),
-            self.tcp_reuse_timeout,
-            self.max_reuse_tcp_queries,
-        ))
+        self.conn.get_or_connect(|| self.connect()).await
     }
 
     fn conn_type(&self) -> &'static str {
         "TLS"
     }
+
+    fn remote(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn protocol(&self) -> SocketProtocol {
+        SocketProtocol::Dot
+    }
 }
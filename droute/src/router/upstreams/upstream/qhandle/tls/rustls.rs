@@ -13,43 +13,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::{ConnInitiator, Result};
+use super::{tcp, ConnInitiator, Result, TlsSettings};
+use crate::dnstap::SocketProtocol;
 use async_trait::async_trait;
-use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
-use socket2::{Socket, TcpKeepalive};
-use std::{net::SocketAddr, sync::Arc, time::Instant};
-use tokio::{net::TcpStream, sync::Mutex};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpStream;
 pub use tokio_rustls::client::TlsStream;
 use tokio_rustls::TlsConnector;
 
-fn create_client_config(sni: &bool) -> ClientConfig {
-    let mut root_store = RootCertStore::empty();
-    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
-
-    let mut client_config = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
-
-    client_config.enable_sni = *sni; // Disable SNI on need.
-
-    client_config
-}
-
 /// Client instance for TLS connections
-#[derive(Clone)]
 pub struct Tls {
     client: TlsConnector,
     addr: SocketAddr,
     domain: String,
-    tcp_reuse_timeout: u64,
-    max_reuse_tcp_queries: usize,
+    conn: tcp::SharedConn<TlsStream<TcpStream>>,
 }
 
 impl Tls {
@@ -58,50 +35,48 @@ impl Tls {
         domain: String,
         addr: SocketAddr,
         sni: bool,
+        tls: &TlsSettings,
         tcp_reuse_timeout: u64,
         max_reuse_tcp_queries: usize,
     ) -> Result<Self> {
         Ok(Self {
-            client: TlsConnector::from(Arc::new(create_client_config(&sni))),
+            client: TlsConnector::from(Arc::new(tls.rustls_config(sni)?)),
             addr,
             domain,
-            tcp_reuse_timeout,
-            max_reuse_tcp_queries,
+            conn: tcp::SharedConn::new(tcp_reuse_timeout, max_reuse_tcp_queries),
         })
     }
+
+    async fn connect(&self) -> std::io::Result<TlsStream<TcpStream>> {
+        let stream = tcp::connect(self.addr).await?;
+
+        let domain = rustls::ServerName::try_from(self.domain.as_str()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid dnsname")
+        })?;
+        self.client
+            .connect(domain, stream)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))
+    }
 }
 
 #[async_trait]
 impl ConnInitiator for Tls {
-    type Connection = (Mutex<(TlsStream<TcpStream>, Instant, usize)>, u64, usize);
+    type Connection = tcp::StreamConn<TlsStream<TcpStream>>;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
-        let mut stream = TcpStream::connect(self.addr).await?;
-
-        // Good default as reqwest also sets this.
-        let keepalive = TcpKeepalive::new().with_time(std::time::Duration::from_secs(60));
-        let socket: Socket = stream.into_std()?.into();
-        socket.set_tcp_keepalive(&keepalive)?;
-        stream = TcpStream::from_std(socket.into())?;
-
-        let domain = rustls::ServerName::try_from(self.domain.as_str()).map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid dnsname")
-        })?;
-        Ok((
-            Mutex::new((
-                self.client
-                    .connect(domain, stream)
-                    .await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::WouldBlock, e))?,
-                Instant::now(),
-                0,
-            )),
-            self.tcp_reuse_timeout,
-            self.max_reuse_tcp_queries,
-        ))
+        self.conn.get_or_connect(|| self.connect()).await
     }
 
     fn conn_type(&self) -> &'static str {
         "TLS"
     }
+
+    fn remote(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn protocol(&self) -> SocketProtocol {
+        SocketProtocol::Dot
+    }
 }
@@ -0,0 +1,320 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::{QHandleError, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+#[cfg(any(feature = "doh-rustls", feature = "dot-rustls"))]
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
+};
+#[cfg(any(feature = "doh-rustls", feature = "dot-rustls"))]
+use std::{sync::Arc, time::SystemTime};
+
+/// TLS settings of the connections to the upstream, for private resolvers.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TlsSettings {
+    /// Path to the bundle of CA certificates in PEM format, which are trusted instead of the built-in ones.
+    pub ca: Option<PathBuf>,
+    /// Path to the client certificate chain in PEM format, presented to the server for mutual TLS.
+    pub cert: Option<PathBuf>,
+    /// Path to the private key of the client certificate in PEM format (PKCS#8 for the native-tls backend).
+    pub key: Option<PathBuf>,
+    /// Base64-encoded SHA-256 hashes of the SubjectPublicKeyInfo (as in RFC 7469), one of which must match a certificate from the server.
+    pub spki_pins: Vec<String>,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| {
+        QHandleError::InvalidTlsSettings(format!("failed to read {}: {}", path.display(), e))
+    })
+}
+
+// DER certificates in the PEM bundle
+fn certs(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let certs = rustls_pemfile::certs(&mut read(path)?.as_slice()).map_err(|e| {
+        QHandleError::InvalidTlsSettings(format!("failed to parse {}: {}", path.display(), e))
+    })?;
+    if certs.is_empty() {
+        return Err(QHandleError::InvalidTlsSettings(format!(
+            "no certificate found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+// Split a DER TLV off the front, returning the whole TLV, its value, and the rest of the buffer.
+fn der_tlv(buf: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *buf.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (first.into(), 2)
+    } else {
+        // Long form, where the lower bits are the number of bytes of the length.
+        let n = usize::from(first & 0x7f);
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = buf
+            .get(2..2 + n)?
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | usize::from(*b));
+        (len, 2 + n)
+    };
+    let tlv = buf.get(..header.checked_add(len)?)?;
+    Some((tlv, &tlv[header..], &buf[tlv.len()..]))
+}
+
+// The DER-encoded SubjectPublicKeyInfo of the certificate (RFC 5280, 4.1)
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    // Certificate and TBSCertificate are both SEQUENCEs.
+    let (_, cert, _) = der_tlv(cert)?;
+    let (_, mut tbs, _) = der_tlv(cert)?;
+    // The version is an optional field tagged with [0].
+    if *tbs.first()? == 0xa0 {
+        tbs = der_tlv(tbs)?.2;
+    }
+    // Skip serialNumber, signature, issuer, validity, and subject.
+    for _ in 0..5 {
+        tbs = der_tlv(tbs)?.2;
+    }
+    der_tlv(tbs).map(|(spki, _, _)| spki)
+}
+
+// SHA-256 hashes of the SubjectPublicKeyInfo pinned. No pin means anything goes.
+#[derive(Clone, Default)]
+pub struct Pins(Vec<[u8; 32]>);
+
+impl Pins {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Whether any of the DER certificates matches a pin.
+    pub fn matches<'a>(&self, mut certs: impl Iterator<Item = &'a [u8]>) -> bool {
+        self.is_empty()
+            || certs.any(|cert| match spki(cert) {
+                Some(spki) => {
+                    let hash: [u8; 32] = Sha256::digest(spki).into();
+                    self.0.contains(&hash)
+                }
+                None => false,
+            })
+    }
+}
+
+impl TlsSettings {
+    pub fn pins(&self) -> Result<Pins> {
+        self.spki_pins
+            .iter()
+            .map(|pin| {
+                base64::decode(pin)
+                    .ok()
+                    .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                    .ok_or_else(|| {
+                        QHandleError::InvalidTlsSettings(format!("invalid SPKI pin: {}", pin))
+                    })
+            })
+            .collect::<Result<_>>()
+            .map(Pins)
+    }
+
+    // Paths to the client certificate and key, which must be given together.
+    fn identity(&self) -> Result<Option<(&Path, &Path)>> {
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            _ => Err(QHandleError::InvalidTlsSettings(
+                "`cert` and `key` must be specified together".to_string(),
+            )),
+        }
+    }
+}
+
+// Verify the certificate as usual, then check it against the pins.
+#[cfg(any(feature = "doh-rustls", feature = "dot-rustls"))]
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pins: Pins,
+}
+
+#[cfg(any(feature = "doh-rustls", feature = "dot-rustls"))]
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        if self.pins.matches(
+            std::iter::once(end_entity)
+                .chain(intermediates)
+                .map(|cert| cert.0.as_slice()),
+        ) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(
+                "no certificate matches the SPKI pins".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(any(feature = "doh-rustls", feature = "dot-rustls"))]
+impl TlsSettings {
+    /// Build the rustls client configuration.
+    pub fn rustls_config(&self, sni: bool) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca {
+            Some(ca) => {
+                roots.add_parsable_certificates(&certs(ca)?);
+            }
+            None => {
+                roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }))
+            }
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                inner: WebPkiVerifier::new(roots, None),
+                pins: self.pins()?,
+            }));
+
+        let mut client_config = match self.identity()? {
+            Some((cert, key)) => builder
+                .with_single_cert(
+                    certs(cert)?.into_iter().map(Certificate).collect(),
+                    private_key(key)?,
+                )
+                .map_err(|e| QHandleError::InvalidTlsSettings(e.to_string()))?,
+            None => builder.with_no_client_auth(),
+        };
+
+        client_config.enable_sni = sni; // Disable SNI on need.
+
+        Ok(client_config)
+    }
+}
+
+// The first private key in the PEM file
+#[cfg(any(feature = "doh-rustls", feature = "dot-rustls"))]
+fn private_key(path: &Path) -> Result<PrivateKey> {
+    use rustls_pemfile::Item;
+    rustls_pemfile::read_all(&mut read(path)?.as_slice())
+        .map_err(|e| {
+            QHandleError::InvalidTlsSettings(format!("failed to parse {}: {}", path.display(), e))
+        })?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            QHandleError::InvalidTlsSettings(format!("no private key found in {}", path.display()))
+        })
+}
+
+#[cfg(any(feature = "doh-native-tls", feature = "dot-native-tls"))]
+impl TlsSettings {
+    /// Build the native-tls connector. SPKI pins have to be checked by the caller on the connections established.
+    pub fn native_tls_builder(&self, sni: bool) -> Result<native_tls::TlsConnectorBuilder> {
+        let mut builder = native_tls::TlsConnector::builder();
+        builder.use_sni(sni);
+
+        if let Some(ca) = &self.ca {
+            builder.disable_built_in_roots(true);
+            for cert in certs(ca)? {
+                builder.add_root_certificate(native_tls::Certificate::from_der(&cert)?);
+            }
+        }
+
+        if let Some((cert, key)) = self.identity()? {
+            builder.identity(native_tls::Identity::from_pkcs8(&read(cert)?, &read(key)?)?);
+        }
+
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spki, Pins, TlsSettings};
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn spki_of_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        assert_eq!(
+            spki(&cert.serialize_der().unwrap()).unwrap(),
+            cert.get_key_pair().public_key_der()
+        );
+    }
+
+    #[test]
+    fn match_pins() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let (cert, other) = (
+            cert.serialize_der().unwrap(),
+            other.serialize_der().unwrap(),
+        );
+        let pins = TlsSettings {
+            spki_pins: vec![base64::encode(Sha256::digest(spki(&cert).unwrap()))],
+            ..Default::default()
+        }
+        .pins()
+        .unwrap();
+
+        assert!(pins.matches([other.as_slice(), cert.as_slice()].into_iter()));
+        assert!(!pins.matches([other.as_slice()].into_iter()));
+        assert!(Pins::default().matches([other.as_slice()].into_iter()));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let settings = TlsSettings {
+            spki_pins: vec!["not a pin".to_string()],
+            ..Default::default()
+        };
+        assert!(settings.pins().is_err());
+
+        let settings = TlsSettings {
+            cert: Some("cert.pem".into()),
+            ..Default::default()
+        };
+        assert!(settings.identity().is_err());
+    }
+}
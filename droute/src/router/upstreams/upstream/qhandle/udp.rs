@@ -13,12 +13,15 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::MAX_LEN;
-
-use super::{ConnInitiator, QHandle, Result};
+use super::{
+    tcp::{self, StreamConn},
+    ConnInitiator, QHandle, Result,
+};
+use crate::{dnstap::SocketProtocol, utils::set_payload_size};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use domain::base::Message;
+use log::debug;
 use std::net::SocketAddr;
 use tokio::net::UdpSocket;
 
@@ -26,28 +29,56 @@ use tokio::net::UdpSocket;
 #[derive(Clone)]
 pub struct Udp {
     addr: SocketAddr,
+    edns_size: u16,
+    buffer_size: usize,
 }
 
 impl Udp {
     /// Create a new UDP client creator instance. with the given remote server address.
-    pub async fn new(addr: SocketAddr) -> Result<Self> {
-        Ok(Self { addr })
+    /// `edns_size` is the UDP payload size advertised in queries with EDNS, and `buffer_size` is the size of the receive buffer.
+    pub async fn new(addr: SocketAddr, edns_size: u16, buffer_size: usize) -> Result<Self> {
+        Ok(Self {
+            addr,
+            edns_size,
+            buffer_size,
+        })
     }
 }
 
+/// A connected UDP socket, which falls back to TCP on truncated responses.
+pub struct UdpConn {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    edns_size: u16,
+    buffer_size: usize,
+}
+
 #[async_trait]
 impl ConnInitiator for Udp {
-    type Connection = UdpSocket;
+    type Connection = UdpConn;
 
     async fn create(&self) -> std::io::Result<Self::Connection> {
         let socket = UdpSocket::bind(bind_addr(self.addr.is_ipv4())).await?;
         socket.connect(self.addr).await?;
-        Ok(socket)
+        Ok(UdpConn {
+            socket,
+            addr: self.addr,
+            edns_size: self.edns_size,
+            buffer_size: self.buffer_size,
+        })
     }
 
     fn conn_type(&self) -> &'static str {
         "UDP"
     }
+
+    fn remote(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn protocol(&self) -> SocketProtocol {
+        SocketProtocol::Udp
+    }
 }
 
 fn bind_addr(is_ipv4: bool) -> SocketAddr {
@@ -58,37 +89,62 @@ fn bind_addr(is_ipv4: bool) -> SocketAddr {
     }
 }
 
+impl UdpConn {
+    // Retry the query over a one-off TCP connection to the same server.
+    async fn query_tcp(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        // The connection is closed once dropped.
+        StreamConn::new(tcp::connect(self.addr).await?, 0, 1)
+            .query(msg)
+            .await
+    }
+}
+
 #[async_trait]
-impl QHandle for UdpSocket {
+impl QHandle for UdpConn {
     async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        // Queries without EDNS are left as is, as the OPT record in the response is not expected by the client.
+        // Those we fail to rebuild are sent as they are.
+        let msg = set_payload_size(msg, self.edns_size).unwrap_or_else(|_| msg.clone());
+
         // Randomnize the message
         let mut msg = Message::from_octets(BytesMut::from(msg.as_slice()))?;
         msg.header_mut().set_random_id();
-        let msg = msg.for_slice();
+        let msg = Message::from_octets(msg.into_octets().freeze())?;
 
-        self.send(msg.as_slice()).await?;
+        self.socket.send(msg.as_slice()).await?;
 
         loop {
-            let mut buf = BytesMut::with_capacity(MAX_LEN);
-            buf.resize(MAX_LEN, 0);
-            let len = self.recv(&mut buf).await?;
+            let mut buf = BytesMut::with_capacity(self.buffer_size);
+            buf.resize(self.buffer_size, 0);
+            let len = self.socket.recv(&mut buf).await?;
+            // The datagram may have been cut off if it filled up the whole buffer.
+            let full = len == buf.len();
             buf.resize(len, 0);
 
             // We ignore garbage since there is a timer on this whole thing.
             let answer = match Message::from_octets(buf.freeze()) {
                 Ok(answer) => answer,
+                Err(_) if full => return self.query_tcp(&msg).await,
                 Err(_) => continue,
             };
             if !answer.is_answer(&msg) {
                 continue;
             }
+            if full || answer.header().tc() {
+                debug!(
+                    "response from {} is truncated, retrying over TCP",
+                    self.addr
+                );
+                return self.query_tcp(&msg).await;
+            }
             return Ok(answer);
         }
     }
 
     async fn reusable(&self) -> deadpool::managed::RecycleResult<std::io::Error> {
         // We don't care about the response of our test query because we would ignore unrelated response that up in receive loop.
-        self.send(super::DUMMY_QUERY.as_slice())
+        self.socket
+            .send(super::DUMMY_QUERY.as_slice())
             .await
             .map(|_| ())
             .map_err(deadpool::managed::RecycleError::Backend)
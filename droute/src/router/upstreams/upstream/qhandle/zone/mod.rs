@@ -0,0 +1,499 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+mod parser;
+
+use self::parser::{Entry, ANAME};
+use super::{QHandle, QHandleError, Result};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use domain::base::{
+    iana::{Class, Rcode},
+    rdata::UnknownRecordData,
+    Dname, Message, MessageBuilder, Rtype,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+};
+
+// Maximum number of CNAME and ANAME records to follow in a chain
+const MAX_CHAIN: usize = 8;
+
+// Records are indexed by the lowercase names, as names are compared case-insensitively.
+pub(super) fn key(name: &Dname<Bytes>) -> String {
+    name.to_string().to_ascii_lowercase()
+}
+
+// The name without its leftmost label
+pub(super) fn parent(key: &str) -> Option<&str> {
+    let mut chars = key.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '.' => return Some(&key[i + 1..]).filter(|p| !p.is_empty()),
+            _ => (),
+        }
+    }
+    None
+}
+
+pub(super) fn is_under(mut key: &str, origin: &str) -> bool {
+    if origin == "." {
+        return true;
+    }
+    loop {
+        if key == origin {
+            return true;
+        }
+        key = match parent(key) {
+            Some(p) => p,
+            None => return false,
+        }
+    }
+}
+
+struct Rr {
+    rtype: Rtype,
+    ttl: u32,
+    data: Bytes,
+    // Target of CNAME and ANAME records
+    target: Option<Dname<Bytes>>,
+}
+
+/// A zone loaded from a master file, which we are authoritative for.
+pub struct Zone {
+    origin: Dname<Bytes>,
+    origin_key: String,
+    soa: Bytes,
+    // TTL of negative responses (RFC 2308, 5)
+    negative_ttl: u32,
+    records: HashMap<String, Vec<Rr>>,
+    // Names with records, and the empty non-terminals above them
+    names: HashSet<String>,
+}
+
+impl Zone {
+    fn new(origin: Dname<Bytes>, entries: Vec<Entry>) -> std::result::Result<Self, String> {
+        let origin_key = key(&origin);
+        let mut soa = None;
+        let mut records: HashMap<String, Vec<Rr>> = HashMap::new();
+        let mut names = HashSet::from([origin_key.clone()]);
+
+        for entry in entries {
+            let k = key(&entry.owner);
+            if !is_under(&k, &origin_key) {
+                return Err(format!("{} is out of zone {}", entry.owner, origin));
+            }
+            if entry.rtype == Rtype::Soa {
+                if k != origin_key || soa.is_some() {
+                    return Err("there must be exactly one SOA record, at the origin".to_string());
+                }
+                soa = Some((entry.ttl, entry.data.clone()));
+            }
+
+            let mut name = k.as_str();
+            while names.insert(name.to_string()) {
+                name = match parent(name) {
+                    Some(p) => p,
+                    None => break,
+                }
+            }
+
+            let rrs = records.entry(k).or_default();
+            // CNAME records cannot coexist with any other data (RFC 1034, 3.6.2)
+            if !rrs.is_empty()
+                && (entry.rtype == Rtype::Cname || rrs.iter().any(|rr| rr.rtype == Rtype::Cname))
+            {
+                return Err(format!("{} has CNAME and other data", entry.owner));
+            }
+            rrs.push(Rr {
+                rtype: entry.rtype,
+                ttl: entry.ttl,
+                data: entry.data,
+                target: entry.target,
+            });
+        }
+
+        let (soa_ttl, soa) = soa.ok_or("missing SOA record at the origin")?;
+        let minimum = u32::from_be_bytes(soa[soa.len() - 4..].try_into().unwrap());
+        Ok(Self {
+            origin,
+            origin_key,
+            soa,
+            negative_ttl: soa_ttl.min(minimum),
+            records,
+            names,
+        })
+    }
+
+    /// Load the zone from the master file, with the origin given for relative names.
+    pub async fn load(origin: &str, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let origin = Dname::from_str(origin)
+            .map_err(|_| QHandleError::InvalidZone(format!("invalid origin `{}`", origin)))?;
+        let text = tokio::fs::read_to_string(path).await?;
+        parser::parse(&text, &origin)
+            .map_err(|(line, e)| format!("{}:{}: {}", path.display(), line, e))
+            .and_then(|entries| {
+                Self::new(origin, entries).map_err(|e| format!("{}: {}", path.display(), e))
+            })
+            .map_err(QHandleError::InvalidZone)
+    }
+
+    // Records at the name, or synthesized from the wildcard if the name doesn't exist. None if neither exists.
+    fn find(&self, key: &str) -> Option<&[Rr]> {
+        if let Some(rrs) = self.records.get(key) {
+            return Some(rrs);
+        }
+        // Empty non-terminals exist without any data.
+        if self.names.contains(key) {
+            return Some(&[]);
+        }
+        // Only the wildcard at the closest encloser applies (RFC 4592, 3.3.1).
+        let mut encloser = parent(key).unwrap_or(".");
+        loop {
+            if self.names.contains(encloser) {
+                let wildcard = if encloser == "." {
+                    "*".to_string()
+                } else {
+                    format!("*.{}", encloser)
+                };
+                return self.records.get(&wildcard).map(Vec::as_slice);
+            }
+            if encloser == "." {
+                return None;
+            }
+            encloser = parent(encloser).unwrap_or(".");
+        }
+    }
+}
+
+// Result of looking up a name in our zones
+struct Lookup<'a> {
+    rcode: Rcode,
+    answers: Vec<(Dname<Bytes>, u32, Rtype, &'a Bytes)>,
+    // Zone whose SOA goes into the authority section for negative responses
+    negative: Option<&'a Zone>,
+}
+
+/// A set of zones answered authoritatively, like an authoritative server.
+pub struct Zones(Vec<Zone>);
+
+impl Zones {
+    /// Create a set of zones. Queries are answered from the closest zone enclosing the name.
+    pub fn new(zones: Vec<Zone>) -> Self {
+        Self(zones)
+    }
+
+    fn zone(&self, name: &Dname<Bytes>) -> Option<&Zone> {
+        let key = key(name);
+        self.0
+            .iter()
+            .filter(|z| is_under(&key, &z.origin_key))
+            .max_by_key(|z| z.origin_key.len())
+    }
+
+    fn lookup<'a>(
+        &'a self,
+        mut name: Dname<Bytes>,
+        qtype: Rtype,
+        mut zone: &'a Zone,
+        depth: usize,
+    ) -> Lookup<'a> {
+        let mut answers = Vec::new();
+        for _ in 0..MAX_CHAIN {
+            let rrs = match zone.find(&key(&name)) {
+                Some(rrs) => rrs,
+                None => {
+                    return Lookup {
+                        rcode: Rcode::NXDomain,
+                        answers,
+                        negative: Some(zone),
+                    }
+                }
+            };
+
+            // Follow the CNAME as long as the target is in our zones.
+            if let Some(cname) = rrs.iter().find(|rr| rr.rtype == Rtype::Cname) {
+                if qtype != Rtype::Cname && qtype != Rtype::Any {
+                    answers.push((name, cname.ttl, cname.rtype, &cname.data));
+                    name = cname.target.clone().unwrap();
+                    match self.zone(&name) {
+                        Some(z) => {
+                            zone = z;
+                            continue;
+                        }
+                        None => {
+                            return Lookup {
+                                rcode: Rcode::NoError,
+                                answers,
+                                negative: None,
+                            }
+                        }
+                    }
+                }
+            }
+
+            let mut matched: Vec<_> = rrs
+                .iter()
+                .filter(|rr| qtype == Rtype::Any || rr.rtype == qtype)
+                .map(|rr| (name.clone(), rr.ttl, rr.rtype, &rr.data))
+                .collect();
+
+            // ANAME records are answered with the addresses of the target under our name.
+            if matched.is_empty() && (qtype == Rtype::A || qtype == Rtype::Aaaa) {
+                if let Some(aname) = rrs.iter().find(|rr| rr.rtype == ANAME) {
+                    let target = aname.target.clone().unwrap();
+                    if let Some(z) = self.zone(&target).filter(|_| depth < MAX_CHAIN) {
+                        matched = self
+                            .lookup(target, qtype, z, depth + 1)
+                            .answers
+                            .into_iter()
+                            .filter(|(_, _, rtype, _)| *rtype == qtype)
+                            .map(|(_, ttl, rtype, data)| {
+                                (name.clone(), ttl.min(aname.ttl), rtype, data)
+                            })
+                            .collect();
+                    }
+                }
+            }
+
+            // NODATA
+            let negative = matched.is_empty().then_some(zone);
+            answers.extend(matched);
+            return Lookup {
+                rcode: Rcode::NoError,
+                answers,
+                negative,
+            };
+        }
+
+        // The chain is too long.
+        Lookup {
+            rcode: Rcode::ServFail,
+            answers,
+            negative: None,
+        }
+    }
+}
+
+#[async_trait]
+impl QHandle for Zones {
+    async fn query(&self, msg: &Message<Bytes>) -> Result<Message<Bytes>> {
+        let builder = MessageBuilder::from_target(BytesMut::with_capacity(crate::MAX_LEN))?;
+        let question = match msg.first_question() {
+            Some(q) => q,
+            None => return Ok(builder.start_answer(msg, Rcode::FormErr)?.into_message()),
+        };
+
+        // Queries out of our zones are refused, as an authoritative server does.
+        let name = Dname::from_str(&question.qname().to_string()).ok();
+        let (name, zone) = match name.as_ref().and_then(|n| Some((n, self.zone(n)?))) {
+            Some((name, zone)) if matches!(question.qclass(), Class::In | Class::Any) => {
+                (name.clone(), zone)
+            }
+            _ => return Ok(builder.start_answer(msg, Rcode::Refused)?.into_message()),
+        };
+
+        let lookup = self.lookup(name, question.qtype(), zone, 0);
+        let mut answer = builder.start_answer(msg, lookup.rcode)?;
+        answer.header_mut().set_aa(true);
+        for (owner, ttl, rtype, data) in lookup.answers {
+            answer.push((
+                &owner,
+                ttl,
+                UnknownRecordData::from_octets(rtype, data.clone()),
+            ))?;
+        }
+        let mut authority = answer.authority();
+        if let Some(zone) = lookup.negative {
+            authority.push((
+                &zone.origin,
+                zone.negative_ttl,
+                UnknownRecordData::from_octets(Rtype::Soa, zone.soa.clone()),
+            ))?;
+        }
+        Ok(authority.into_message())
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QHandle, Zone, Zones};
+    use bytes::{Bytes, BytesMut};
+    use domain::{
+        base::{iana::Rcode, Dname, Message, MessageBuilder, Rtype},
+        rdata::A,
+    };
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    async fn zones() -> Zones {
+        Zones::new(vec![Zone::load(
+            "example.com",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/../data/a.cn.zone"),
+        )
+        .await
+        .unwrap()])
+    }
+
+    async fn query(name: &str, qtype: Rtype) -> Message<Bytes> {
+        let mut builder = MessageBuilder::from_target(BytesMut::new())
+            .unwrap()
+            .question();
+        builder
+            .push((Dname::<Bytes>::from_str(name).unwrap(), qtype))
+            .unwrap();
+        let msg = Message::from_octets(builder.into_message().into_octets().freeze()).unwrap();
+        zones().await.query(&msg).await.unwrap()
+    }
+
+    fn answer_types(msg: &Message<Bytes>) -> Vec<(String, Rtype)> {
+        msg.answer()
+            .unwrap()
+            .map(|r| {
+                let r = r.unwrap();
+                (r.owner().to_string(), r.rtype())
+            })
+            .collect()
+    }
+
+    fn addrs(msg: &Message<Bytes>) -> Vec<Ipv4Addr> {
+        msg.answer()
+            .unwrap()
+            .limit_to::<A>()
+            .map(|r| r.unwrap().data().addr())
+            .collect()
+    }
+
+    fn has_soa(msg: &Message<Bytes>) -> bool {
+        let mut authority = msg.authority().unwrap();
+        matches!(authority.next(), Some(Ok(r)) if r.rtype() == Rtype::Soa)
+            && authority.next().is_none()
+    }
+
+    #[tokio::test]
+    async fn authoritative_answer() {
+        let resp = query("www.example.com", Rtype::A).await;
+        assert!(resp.header().aa());
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(127, 0, 0, 1)]);
+
+        // Names are matched case-insensitively.
+        let resp = query("THIS.has.Dots.example.com", Rtype::A).await;
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(127, 0, 0, 3)]);
+    }
+
+    #[tokio::test]
+    async fn follow_cname_chain() {
+        let resp = query("alias-chain.example.com", Rtype::A).await;
+        assert_eq!(
+            answer_types(&resp),
+            vec![
+                ("alias-chain.example.com".to_string(), Rtype::Cname),
+                ("alias.example.com".to_string(), Rtype::Cname),
+                ("www.example.com".to_string(), Rtype::A),
+            ]
+        );
+
+        // The CNAME itself is answered if asked for.
+        let resp = query("alias-chain.example.com", Rtype::Cname).await;
+        assert_eq!(
+            answer_types(&resp),
+            vec![("alias-chain.example.com".to_string(), Rtype::Cname)]
+        );
+    }
+
+    #[tokio::test]
+    async fn wildcard() {
+        let resp = query("foo.wildcard.example.com", Rtype::A).await;
+        assert_eq!(
+            answer_types(&resp),
+            vec![
+                ("foo.wildcard.example.com".to_string(), Rtype::Cname),
+                ("www.example.com".to_string(), Rtype::A),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn aname() {
+        let resp = query("example.com", Rtype::A).await;
+        assert_eq!(
+            answer_types(&resp),
+            vec![("example.com".to_string(), Rtype::A)]
+        );
+        assert_eq!(addrs(&resp), vec![Ipv4Addr::new(127, 0, 0, 1)]);
+
+        let resp = query("aname-chain.example.com", Rtype::Aaaa).await;
+        assert_eq!(
+            answer_types(&resp),
+            vec![("aname-chain.example.com".to_string(), Rtype::Aaaa)]
+        );
+    }
+
+    #[tokio::test]
+    async fn negative_answers() {
+        let resp = query("nonexistent.example.com", Rtype::A).await;
+        assert!(resp.header().aa());
+        assert_eq!(resp.header().rcode(), Rcode::NXDomain);
+        assert!(has_soa(&resp));
+
+        // NODATA
+        let resp = query("www.example.com", Rtype::Mx).await;
+        assert_eq!(resp.header().rcode(), Rcode::NoError);
+        assert_eq!(resp.header_counts().ancount(), 0);
+        assert!(has_soa(&resp));
+
+        // Empty non-terminals exist, so the wildcard does not apply to them.
+        for name in ["wildcard.example.com", "has.dots.example.com"] {
+            let resp = query(name, Rtype::A).await;
+            assert_eq!(resp.header().rcode(), Rcode::NoError);
+            assert_eq!(resp.header_counts().ancount(), 0);
+            assert!(has_soa(&resp));
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_out_of_zone() {
+        let resp = query("www.example.org", Rtype::A).await;
+        assert_eq!(resp.header().rcode(), Rcode::Refused);
+        assert_eq!(resp.header_counts().ancount(), 0);
+    }
+
+    #[tokio::test]
+    async fn invalid_zone() {
+        for text in [
+            "$TTL 60\nwww A 127.0.0.1\n",
+            "@ 60 SOA ns admin 1 2 3 4 5\nwww.example.org. A 127.0.0.1\n",
+            "@ 60 SOA ns admin 1 2 3 4 5\nwww CNAME @\nwww A 127.0.0.1\n",
+        ] {
+            let entries = super::parser::parse(text, &Dname::from_str("example.com").unwrap())
+                .unwrap_or_default();
+            assert!(
+                Zone::new(Dname::from_str("example.com").unwrap(), entries).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+}
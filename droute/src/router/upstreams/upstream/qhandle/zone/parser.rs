@@ -0,0 +1,522 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// A parser of RFC 1035 master files, turning the records into wire format.
+// The master file reader of domain is not used as it doesn't know ANAME, which is not a registered type and thus has no mnemonic there.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use domain::base::{Dname, Rtype};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+// Type code of ANAME records, following the one used by trust-dns
+pub const ANAME: Rtype = Rtype::Int(65305);
+
+// Two root names followed by the five numbers, which is the shortest SOA record data possible
+const MIN_SOA_LEN: usize = 22;
+
+// A record in the master file
+pub struct Entry {
+    pub owner: Dname<Bytes>,
+    pub ttl: u32,
+    pub rtype: Rtype,
+    pub data: Bytes,
+    // Target of CNAME and ANAME records
+    pub target: Option<Dname<Bytes>>,
+}
+
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+// Entries span multiple lines if enclosed in parentheses.
+struct Line {
+    number: usize,
+    // Whether the line starts with a blank, which means the owner is the same as the previous one
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+type ParseResult<T> = std::result::Result<T, (usize, String)>;
+
+fn lines(text: &str) -> ParseResult<Vec<Line>> {
+    let mut lines = Vec::new();
+    let mut number = 1;
+    let mut line = Line {
+        number,
+        blank_owner: false,
+        tokens: Vec::new(),
+    };
+    let mut token = String::new();
+    let mut depth = 0;
+    // Whether we haven't seen anything on the current line yet
+    let mut fresh = true;
+
+    let flush = |token: &mut String, line: &mut Line| {
+        if !token.is_empty() {
+            line.tokens.push(Token {
+                text: std::mem::take(token),
+                quoted: false,
+            });
+        }
+    };
+
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let was_fresh = std::mem::replace(&mut fresh, false);
+        match c {
+            '\\' => {
+                token.push(c);
+                token.extend(chars.next());
+            }
+            '"' => {
+                flush(&mut token, &mut line);
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push('\\');
+                            text.extend(chars.next());
+                        }
+                        Some('\n') | None => {
+                            return Err((number, "unterminated quoted string".to_string()))
+                        }
+                        Some(c) => text.push(c),
+                    }
+                }
+                line.tokens.push(Token { text, quoted: true });
+            }
+            ';' => {
+                flush(&mut token, &mut line);
+                // Leave the newline to be handled below
+                let rest = chars.as_str();
+                chars = rest[rest.find('\n').unwrap_or(rest.len())..].chars();
+            }
+            '(' => {
+                flush(&mut token, &mut line);
+                depth += 1;
+            }
+            ')' => {
+                flush(&mut token, &mut line);
+                if depth == 0 {
+                    return Err((number, "unbalanced parentheses".to_string()));
+                }
+                depth -= 1;
+            }
+            ' ' | '\t' | '\r' => {
+                flush(&mut token, &mut line);
+                if was_fresh && depth == 0 && line.tokens.is_empty() {
+                    line.blank_owner = true;
+                }
+            }
+            '\n' => {
+                flush(&mut token, &mut line);
+                number += 1;
+                if depth == 0 {
+                    let next = Line {
+                        number,
+                        blank_owner: false,
+                        tokens: Vec::new(),
+                    };
+                    let line = std::mem::replace(&mut line, next);
+                    if !line.tokens.is_empty() {
+                        lines.push(line);
+                    }
+                    fresh = true;
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    flush(&mut token, &mut line);
+    if depth != 0 {
+        return Err((line.number, "unbalanced parentheses".to_string()));
+    }
+    if !line.tokens.is_empty() {
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+// Resolve the name relative to the origin
+fn name(token: &Token, origin: &Dname<Bytes>) -> Result<Dname<Bytes>, String> {
+    let s = token.text.as_str();
+    if s == "@" {
+        return Ok(origin.clone());
+    }
+    let absolute = if s.ends_with('.') && !s.ends_with("\\.") {
+        s.to_string()
+    } else if origin.is_root() {
+        format!("{}.", s)
+    } else {
+        format!("{}.{}", s, origin)
+    };
+    Dname::from_str(&absolute).map_err(|_| format!("invalid domain name `{}`", s))
+}
+
+// TTLs are in seconds, or with units like `1h30m`.
+fn ttl(s: &str) -> Option<u32> {
+    if !s.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if let Ok(ttl) = s.parse() {
+        return Some(ttl);
+    }
+    let mut total: u32 = 0;
+    let mut value: u32 = 0;
+    for c in s.chars() {
+        if let Some(d) = c.to_digit(10) {
+            value = value.checked_mul(10)?.checked_add(d)?;
+        } else {
+            let unit = match c.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86400,
+                'w' => 604800,
+                _ => return None,
+            };
+            total = total.checked_add(value.checked_mul(unit)?)?;
+            value = 0;
+        }
+    }
+    // Trailing digits without a unit are not allowed once units are used.
+    (value == 0 && !s.ends_with(|c: char| c.is_ascii_digit())).then_some(total)
+}
+
+// Decode the escapes (`\X` and `\DDD`) in character strings.
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(d) if d.is_ascii_digit() => {
+                let digits = [Some(d), bytes.next(), bytes.next()];
+                let value = digits.iter().try_fold(0u16, |acc, d| match d {
+                    Some(d) if d.is_ascii_digit() => Some(acc * 10 + u16::from(d - b'0')),
+                    _ => None,
+                });
+                match value.and_then(|v| u8::try_from(v).ok()) {
+                    Some(v) => out.push(v),
+                    None => return Err(format!("invalid escape in `{}`", s)),
+                }
+            }
+            Some(c) => out.push(c),
+            None => return Err(format!("dangling escape in `{}`", s)),
+        }
+    }
+    Ok(out)
+}
+
+fn number<T: FromStr>(token: &Token) -> Result<T, String> {
+    token
+        .text
+        .parse()
+        .map_err(|_| format!("invalid number `{}`", token.text))
+}
+
+// Encode the record data, returning the target name of CNAME and ANAME records as well.
+fn rdata(
+    rtype: Rtype,
+    tokens: &[Token],
+    origin: &Dname<Bytes>,
+) -> Result<(Bytes, Option<Dname<Bytes>>), String> {
+    let mut buf = BytesMut::new();
+    let expect = |n: usize| {
+        if tokens.len() == n {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} record expects {} field(s), got {}",
+                rtype,
+                n,
+                tokens.len()
+            ))
+        }
+    };
+
+    // Generic format for any type (RFC 3597)
+    if tokens.first().map(|t| t.text.as_str()) == Some("\\#") {
+        let len: usize = number(tokens.get(1).ok_or("missing data length")?)?;
+        let data: String = tokens[2..].iter().map(|t| t.text.as_str()).collect();
+        let data = hex::decode(data).map_err(|_| "invalid hex data".to_string())?;
+        if data.len() != len {
+            return Err("data length mismatched".to_string());
+        }
+        // The SOA minimum is read from the end of the data.
+        if rtype == Rtype::Soa && len < MIN_SOA_LEN {
+            return Err("SOA record data too short".to_string());
+        }
+        return Ok((data.into(), None));
+    }
+
+    let mut target = None;
+    match rtype {
+        Rtype::A => {
+            expect(1)?;
+            let addr: Ipv4Addr = number(&tokens[0])?;
+            buf.put_slice(&addr.octets());
+        }
+        Rtype::Aaaa => {
+            expect(1)?;
+            let addr: Ipv6Addr = number(&tokens[0])?;
+            buf.put_slice(&addr.octets());
+        }
+        Rtype::Ns | Rtype::Cname | Rtype::Ptr | Rtype::Dname | ANAME => {
+            expect(1)?;
+            let name = name(&tokens[0], origin)?;
+            buf.put_slice(name.as_slice());
+            if rtype == Rtype::Cname || rtype == ANAME {
+                target = Some(name);
+            }
+        }
+        Rtype::Mx => {
+            expect(2)?;
+            buf.put_u16(number(&tokens[0])?);
+            buf.put_slice(name(&tokens[1], origin)?.as_slice());
+        }
+        Rtype::Srv => {
+            expect(4)?;
+            for token in &tokens[..3] {
+                buf.put_u16(number(token)?);
+            }
+            buf.put_slice(name(&tokens[3], origin)?.as_slice());
+        }
+        Rtype::Soa => {
+            expect(7)?;
+            buf.put_slice(name(&tokens[0], origin)?.as_slice());
+            buf.put_slice(name(&tokens[1], origin)?.as_slice());
+            buf.put_u32(number(&tokens[2])?);
+            for token in &tokens[3..] {
+                buf.put_u32(
+                    ttl(&token.text).ok_or_else(|| format!("invalid time `{}`", token.text))?,
+                );
+            }
+        }
+        Rtype::Txt | Rtype::Spf => {
+            if tokens.is_empty() {
+                return Err(format!("{} record expects at least one string", rtype));
+            }
+            for token in tokens {
+                let text = unescape(&token.text)?;
+                let len = u8::try_from(text.len())
+                    .map_err(|_| "character string longer than 255 bytes".to_string())?;
+                buf.put_u8(len);
+                buf.put_slice(&text);
+            }
+        }
+        Rtype::Caa => {
+            expect(3)?;
+            buf.put_u8(number(&tokens[0])?);
+            let tag = tokens[1].text.as_bytes();
+            buf.put_u8(u8::try_from(tag.len()).map_err(|_| "CAA tag too long".to_string())?);
+            buf.put_slice(tag);
+            buf.put_slice(&unescape(&tokens[2].text)?);
+        }
+        _ => {
+            return Err(format!(
+                "unsupported record type {}, use the generic `\\#` format instead",
+                rtype
+            ))
+        }
+    }
+    Ok((buf.freeze(), target))
+}
+
+// Parse the master file with the origin given, which may be changed by `$ORIGIN` directives.
+pub fn parse(text: &str, origin: &Dname<Bytes>) -> ParseResult<Vec<Entry>> {
+    let mut origin = origin.clone();
+    let mut entries = Vec::new();
+    // TTL set by `$TTL`
+    let mut default_ttl: Option<u32> = None;
+    let mut last_ttl: Option<u32> = None;
+    let mut last_owner: Option<Dname<Bytes>> = None;
+
+    for line in lines(text)? {
+        let err = |e: String| (line.number, e);
+        let tokens = &line.tokens;
+
+        if !line.blank_owner && !tokens[0].quoted && tokens[0].text.starts_with('$') {
+            match (tokens[0].text.to_ascii_uppercase().as_str(), tokens.get(1)) {
+                ("$ORIGIN", Some(token)) => origin = name(token, &origin).map_err(err)?,
+                ("$TTL", Some(token)) => {
+                    default_ttl = Some(
+                        ttl(&token.text)
+                            .ok_or_else(|| err(format!("invalid TTL `{}`", token.text)))?,
+                    )
+                }
+                ("$INCLUDE", _) => return Err(err("$INCLUDE is not supported".to_string())),
+                (directive, _) => return Err(err(format!("invalid directive `{}`", directive))),
+            }
+            continue;
+        }
+
+        let mut i = 0;
+        let owner = if line.blank_owner {
+            last_owner
+                .clone()
+                .ok_or_else(|| err("no previous owner to inherit".to_string()))?
+        } else {
+            i += 1;
+            name(&tokens[0], &origin).map_err(err)?
+        };
+
+        // TTL and class are optional, and may come in either order.
+        let mut record_ttl = None;
+        for _ in 0..2 {
+            let token = match tokens.get(i) {
+                Some(token) => token,
+                None => break,
+            };
+            if let (None, Some(t)) = (record_ttl, ttl(&token.text)) {
+                record_ttl = Some(t);
+            } else if token.text.eq_ignore_ascii_case("IN") {
+            } else if ["CH", "HS", "CS"]
+                .iter()
+                .any(|c| token.text.eq_ignore_ascii_case(c))
+            {
+                return Err(err(format!("unsupported class `{}`", token.text)));
+            } else {
+                break;
+            }
+            i += 1;
+        }
+
+        let rtype = tokens
+            .get(i)
+            .ok_or_else(|| err("missing record type".to_string()))?;
+        let rtype = match rtype.text.to_ascii_uppercase().as_str() {
+            "ANAME" => ANAME,
+            s => Rtype::from_str(s)
+                .map_err(|_| err(format!("unknown record type `{}`", rtype.text)))?,
+        };
+
+        let (data, target) = rdata(rtype, &tokens[i + 1..], &origin).map_err(err)?;
+
+        // Omitted TTLs are the one set by `$TTL`, or the last one specified (RFC 1035, 5.1).
+        // The SOA minimum is used if there is neither, as in BIND.
+        let ttl = match record_ttl.or(default_ttl).or(last_ttl) {
+            Some(ttl) => ttl,
+            None if rtype == Rtype::Soa => {
+                u32::from_be_bytes(data[data.len() - 4..].try_into().unwrap())
+            }
+            None => return Err(err("no TTL specified".to_string())),
+        };
+        last_ttl = Some(ttl);
+        last_owner = Some(owner.clone());
+        entries.push(Entry {
+            owner,
+            ttl,
+            rtype,
+            data,
+            target,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, ttl, ANAME};
+    use bytes::Bytes;
+    use domain::base::{Dname, Rtype};
+    use std::str::FromStr;
+
+    fn origin() -> Dname<Bytes> {
+        Dname::from_str("example.com").unwrap()
+    }
+
+    #[test]
+    fn parse_ttl() {
+        assert_eq!(ttl("3600"), Some(3600));
+        assert_eq!(ttl("1h30m"), Some(5400));
+        assert_eq!(ttl("1W"), Some(604800));
+        assert_eq!(ttl("1h30"), None);
+        assert_eq!(ttl("IN"), None);
+    }
+
+    #[test]
+    fn parse_entries() {
+        let entries = parse(
+            r#"$TTL 1h
+@ IN SOA ns admin ( 1 2 3 4
+                    5 ) ; comment
+  NS ns.example.net.
+$ORIGIN sub.example.com.
+www 60 IN A 192.0.2.1
+    IN AAAA 2001:db8::1
+txt TXT "hello \"world\"" a\032b
+raw TYPE65280 \# 2 abcd
+apex ANAME www
+"#,
+            &origin(),
+        )
+        .unwrap();
+
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.owner.to_string(), e.rtype, e.ttl))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("example.com".to_string(), Rtype::Soa, 3600),
+                ("example.com".to_string(), Rtype::Ns, 3600),
+                ("www.sub.example.com".to_string(), Rtype::A, 60),
+                ("www.sub.example.com".to_string(), Rtype::Aaaa, 3600),
+                ("txt.sub.example.com".to_string(), Rtype::Txt, 3600),
+                ("raw.sub.example.com".to_string(), Rtype::Int(65280), 3600),
+                ("apex.sub.example.com".to_string(), ANAME, 3600),
+            ]
+        );
+        assert_eq!(entries[2].data.as_ref(), &[192, 0, 2, 1]);
+        assert_eq!(entries[4].data.as_ref(), b"\x0dhello \"world\"\x03a b");
+        assert_eq!(entries[5].data.as_ref(), &[0xab, 0xcd]);
+        assert_eq!(
+            entries[6].target.as_ref().unwrap().to_string(),
+            "www.sub.example.com"
+        );
+    }
+
+    #[test]
+    fn soa_minimum_as_default_ttl() {
+        let entries = parse("@ SOA ns admin 1 2 3 4 300\n  A 192.0.2.1\n", &origin()).unwrap();
+        assert_eq!(entries[0].ttl, 300);
+        assert_eq!(entries[1].ttl, 300);
+    }
+
+    #[test]
+    fn report_line_number() {
+        for (text, line) in [
+            ("$TTL 60\n@ SOA ns admin 1 2 3 4 5\nwww A 192.0.2\n", 3),
+            ("$TTL 60\nwww A (\n192.0.2.1\n", 2),
+            ("www A 192.0.2.1\n", 1),
+            ("$TTL 60\n\nwww CH A 192.0.2.1\n", 3),
+            ("$TTL 60\nwww TXT \"unterminated\n", 2),
+            ("$TTL 60\nwww LOC 0\n", 2),
+            ("@ SOA \\# 0\n", 1),
+        ] {
+            assert_eq!(parse(text, &origin()).err().unwrap().0, line, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-query trace of what happened while a query is being resolved, e.g. which upstreams were contacted.
+
+use crate::Label;
+use serde::Serialize;
+use std::{cell::RefCell, future::Future};
+
+tokio::task_local! {
+    static TRACE: RefCell<QueryTrace>;
+}
+
+/// How the response cache was involved when an upstream was asked.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    /// Answered from the cache within the TTL
+    Hit,
+    /// Cache record found, but the TTL has passed
+    Expired,
+    /// No cache record found
+    Miss,
+    /// Cache was not used as per the cache mode
+    Disabled,
+}
+
+/// An upstream asked during the resolution.
+#[derive(Serialize, Clone, Debug)]
+pub struct UpstreamTrace {
+    /// Tag of the upstream
+    pub tag: Label,
+    /// Cache status on asking the upstream
+    pub cache: CacheStatus,
+}
+
+/// Everything recorded while resolving a single query.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct QueryTrace {
+    /// Upstreams asked, in order. Upstreams raced in a hybrid upstream are all recorded.
+    pub upstreams: Vec<UpstreamTrace>,
+}
+
+// Run the future with a fresh trace, and return the trace recorded along with its output.
+pub(crate) async fn traced<F: Future>(f: F) -> (F::Output, QueryTrace) {
+    TRACE
+        .scope(RefCell::new(QueryTrace::default()), async move {
+            let output = f.await;
+            (output, TRACE.with(|t| t.take()))
+        })
+        .await
+}
+
+// Record an upstream asked. Nothing is recorded if we are not in a traced query, e.g. in a background cache update.
+pub(crate) fn record_upstream(tag: &Label, cache: CacheStatus) {
+    let _ = TRACE.try_with(|t| {
+        t.borrow_mut().upstreams.push(UpstreamTrace {
+            tag: tag.clone(),
+            cache,
+        })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_upstream, traced, CacheStatus};
+
+    #[tokio::test]
+    async fn record_in_scope() {
+        // Outside of any traced query, this should be a no-op
+        record_upstream(&"outside".into(), CacheStatus::Miss);
+
+        let ((), trace) = traced(async {
+            record_upstream(&"foo".into(), CacheStatus::Hit);
+            record_upstream(&"bar".into(), CacheStatus::Miss);
+        })
+        .await;
+        let tags: Vec<_> = trace.upstreams.iter().map(|u| u.tag.as_str()).collect();
+        assert_eq!(tags, vec!["foo", "bar"]);
+        assert_eq!(trace.upstreams[0].cache, CacheStatus::Hit);
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2022 LEXUGE
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+#![cfg(unix)]
+
+use bytes::{Bytes, BytesMut};
+use domain::{
+    base::{Dname, Message, MessageBuilder, Rtype},
+    rdata::A,
+};
+use droute::{
+    builders::*,
+    dnstap::{self, fstrm, Output},
+    mock::Server,
+    AsyncTryInto, CacheMode, Upstreams,
+};
+use std::str::FromStr;
+use tokio::net::{UdpSocket, UnixListener};
+
+fn create_message(qr: bool) -> Message<BytesMut> {
+    let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
+    let mut builder = MessageBuilder::from_target(BytesMut::with_capacity(1232)).unwrap();
+    builder.header_mut().set_qr(qr);
+    let mut builder = builder.question();
+    builder.push((&name, Rtype::A)).unwrap();
+    let mut builder = builder.answer();
+    if qr {
+        builder
+            .push((&name, 10, A::from_octets(1, 1, 1, 1)))
+            .unwrap();
+    }
+    Message::from_octets(BytesMut::from(builder.as_slice())).unwrap()
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+fn read_varint(buf: &mut &[u8]) -> u64 {
+    let mut v = 0;
+    let mut shift = 0;
+    loop {
+        let b = buf[0];
+        *buf = &buf[1..];
+        v |= u64::from(b & 0x7f) << shift;
+        if b < 0x80 {
+            return v;
+        }
+        shift += 7;
+    }
+}
+
+// Find a field in a protobuf message, which is just enough to check dnstap frames.
+fn find(mut buf: &[u8], field: u64) -> Option<Value<'_>> {
+    while !buf.is_empty() {
+        let key = read_varint(&mut buf);
+        let value = match key & 0x7 {
+            0 => Value::Varint(read_varint(&mut buf)),
+            2 => {
+                let len = read_varint(&mut buf) as usize;
+                let (v, rest) = buf.split_at(len);
+                buf = rest;
+                Value::Bytes(v)
+            }
+            5 => {
+                buf = &buf[4..];
+                continue;
+            }
+            _ => panic!("unexpected wire type"),
+        };
+        if key >> 3 == field {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn forwarder_events() {
+    let path = std::env::temp_dir().join("droute-dnstap-test.sock");
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+
+    // A local frame stream reader which collects the first two frames
+    let reader = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        fstrm::accept(&mut stream).await.unwrap();
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            frames.push(fstrm::read_data(&mut stream).await.unwrap().unwrap());
+        }
+        frames
+    });
+    dnstap::init(Output::Unix(path), Some("droute-test".to_string()), None)
+        .await
+        .unwrap();
+
+    let socket = UdpSocket::bind(&"127.0.0.1:53534").await.unwrap();
+    let server = Server::new(socket, vec![0; 1024], None);
+    tokio::spawn(server.run(create_message(true)));
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            UdpBuilder {
+                addr: "127.0.0.1:53534".parse().unwrap(),
+                max_pool_size: 1,
+                timeout: 10,
+                ratelimit: None,
+                edns_size: 1232,
+                buffer_size: 4096,
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+    let query = Message::from_octets(create_message(false).into_octets().freeze()).unwrap();
+    upstreams
+        .send(&"mock".into(), &CacheMode::Disabled, &query)
+        .await
+        .unwrap();
+
+    let frames = reader.await.unwrap();
+    // FORWARDER_QUERY, then FORWARDER_RESPONSE
+    for (frame, (kind, message_field)) in frames.iter().zip([(7, 10), (8, 14)]) {
+        match find(frame, 1) {
+            Some(Value::Bytes(identity)) => assert_eq!(identity, b"droute-test"),
+            _ => panic!("identity not found"),
+        }
+        let msg = match find(frame, 14) {
+            Some(Value::Bytes(msg)) => msg,
+            _ => panic!("message not found"),
+        };
+        assert!(matches!(find(msg, 1), Some(Value::Varint(k)) if k == kind));
+        // UDP
+        assert!(matches!(find(msg, 3), Some(Value::Varint(1))));
+        assert!(matches!(find(msg, 5), Some(Value::Bytes([127, 0, 0, 1]))));
+        assert!(matches!(find(msg, 7), Some(Value::Varint(53534))));
+        assert!(
+            matches!(find(msg, message_field), Some(Value::Bytes(m)) if Message::from_octets(m).is_ok())
+        );
+    }
+}
@@ -20,9 +20,18 @@ use domain::{
     base::{Dname, Message, MessageBuilder, Rtype},
     rdata::A,
 };
-use droute::{builders::*, errors::*, mock::Server, AsyncTryInto, QueryContext, Upstreams};
+use droute::{
+    builders::*, errors::*, mock::Server, AsyncTryInto, CacheMode, QueryContext, Upstreams,
+};
 use once_cell::sync::Lazy;
-use tokio::net::UdpSocket;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+};
 
 static DUMMY_MSG: Lazy<Message<BytesMut>> = Lazy::new(|| {
     let name = Dname::<Bytes>::from_str("cloudflare-dns.com").unwrap();
@@ -63,6 +72,8 @@ async fn test_resolve() {
                 max_pool_size: 256,
                 timeout: 10,
                 ratelimit: None,
+                edns_size: 1232,
+                buffer_size: 4096,
             },
         ),
     )
@@ -80,12 +91,178 @@ async fn test_resolve() {
     );
 }
 
+// Answer every query with `DUMMY_MSG` over TCP, returning the counter of connections accepted.
+fn spawn_tcp_server(listener: TcpListener) -> Arc<AtomicUsize> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut len = [0; 2];
+                // Answer every query until the client closes the connection
+                while stream.read_exact(&mut len).await.is_ok() {
+                    let mut buf = vec![0; u16::from_be_bytes(len).into()];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    let mut resp = DUMMY_MSG.clone();
+                    resp.header_mut()
+                        .set_id(Message::from_octets(buf).unwrap().header().id());
+                    stream
+                        .write_all(&(resp.as_slice().len() as u16).to_be_bytes())
+                        .await
+                        .unwrap();
+                    stream.write_all(resp.as_slice()).await.unwrap();
+                }
+            });
+        }
+    });
+    connections
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resolve_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = spawn_tcp_server(listener);
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            TcpBuilder {
+                addr,
+                timeout: 10,
+                max_pool_size: 1,
+                reuse_timeout: 60000,
+                max_reuse: 2,
+                ratelimit: None,
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    for _ in 0..3 {
+        assert_eq!(
+            upstreams
+                .send(&"mock".into(), &CacheMode::Disabled, &QUERY)
+                .await
+                .unwrap()
+                .into_octets(),
+            DUMMY_MSG.clone().into_octets()
+        );
+    }
+    // A new connection is made once the first one has been used for `max_reuse` times.
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tcp_pipelining() {
+    const QUERIES: usize = 4;
+
+    // Only answer once all the queries are received, in the reverse order.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut ids = Vec::new();
+                while ids.len() < QUERIES {
+                    let mut len = [0; 2];
+                    stream.read_exact(&mut len).await.unwrap();
+                    let mut buf = vec![0; u16::from_be_bytes(len).into()];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    ids.push(Message::from_octets(buf).unwrap().header().id());
+                }
+                for id in ids.into_iter().rev() {
+                    let mut resp = DUMMY_MSG.clone();
+                    resp.header_mut().set_id(id);
+                    stream
+                        .write_all(&(resp.as_slice().len() as u16).to_be_bytes())
+                        .await
+                        .unwrap();
+                    stream.write_all(resp.as_slice()).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            TcpBuilder {
+                addr,
+                timeout: 10,
+                max_pool_size: QUERIES,
+                reuse_timeout: 60000,
+                max_reuse: 200,
+                ratelimit: None,
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    let tag = "mock".into();
+    for resp in futures::future::join_all(
+        (0..QUERIES).map(|_| upstreams.send(&tag, &CacheMode::Disabled, &QUERY)),
+    )
+    .await
+    {
+        assert_eq!(resp.unwrap().into_octets(), DUMMY_MSG.clone().into_octets());
+    }
+    // All the queries are sent over the same connection.
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_udp_fallback_to_tcp() {
+    // Serve a truncated response over UDP and the full one over TCP on the same port.
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let mut truncated = DUMMY_MSG.clone();
+    truncated.header_mut().set_tc(true);
+    tokio::spawn(Server::new(socket, vec![0; 1024], None).run(truncated));
+    let connections = spawn_tcp_server(TcpListener::bind(addr).await.unwrap());
+
+    let upstreams: Upstreams = UpstreamsBuilder::new(1)
+        .unwrap()
+        .add_upstream(
+            "mock",
+            UdpBuilder {
+                addr,
+                max_pool_size: 1,
+                timeout: 10,
+                ratelimit: None,
+                edns_size: 1232,
+                buffer_size: 4096,
+            },
+        )
+        .async_try_into()
+        .await
+        .unwrap();
+
+    let resp = upstreams
+        .send(&"mock".into(), &CacheMode::Disabled, &QUERY)
+        .await
+        .unwrap();
+    assert!(!resp.header().tc());
+    assert_eq!(resp.into_octets(), DUMMY_MSG.clone().into_octets());
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
 async fn resolve_script(
     upstreams: Upstreams,
     query: Message<Bytes>,
     _ctx: Option<QueryContext>,
 ) -> Result<Message<Bytes>, ScriptError> {
     Ok(upstreams
-        .send(&"mock".into(), &droute::CacheMode::Standard, &query)
+        .send(&"mock".into(), &CacheMode::Standard, &query)
         .await?)
 }